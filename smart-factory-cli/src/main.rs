@@ -0,0 +1,369 @@
+mod run;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use smart_factory_environment::doe::{Design, ExperimentDefinition, ExperimentResults, Parameter};
+use smart_factory_environment::experiment::{Kpis, ReplicationSettings};
+use smart_factory_environment::rng::DEFAULT_SEED;
+use smart_factory_environment::scenario::{EnvironmentKind, Scenario};
+use smart_factory_environment::EventEngineError;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+#[derive(Parser)]
+#[command(
+    name = "smart-factory",
+    version,
+    about = "Runs smart factory scenarios headless"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Runs a scenario once
+    Run {
+        scenario: PathBuf,
+        /// Overrides the seed of the scenario
+        #[arg(long)]
+        seed: Option<u64>,
+        /// Directory for report.csv
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Records every dispatched event, as JSON Lines for `.jsonl` files and binary otherwise
+        #[arg(short, long)]
+        trace: Option<PathBuf>,
+        /// Do not print progress
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    /// Re-runs a scenario and checks every dispatched event against a recorded trace
+    Replay {
+        scenario: PathBuf,
+        trace: PathBuf,
+        /// Overrides the seed of the scenario, has to match the seed of the recording
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Runs independent replications of a scenario and reports confidence intervals
+    Replicate {
+        scenario: PathBuf,
+        #[command(flatten)]
+        replications: ReplicationArgs,
+        /// Directory for summary.csv and replications.csv
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Runs replications for every point of an experiment design
+    Sweep {
+        scenario: PathBuf,
+        /// Scenario field and its values: `path=1..5[:step]`, `path=0.5~2.0` or `path=a,b,c`
+        #[arg(short, long = "param", value_parser = run::parse_parameter, required = true)]
+        parameters: Vec<Parameter>,
+        #[arg(long, value_enum, default_value_t = DesignKind::Factorial)]
+        design: DesignKind,
+        /// Design points of the Latin hypercube
+        #[arg(long, default_value_t = 10)]
+        samples: usize,
+        #[command(flatten)]
+        replications: ReplicationArgs,
+        /// Directory for summary.csv and replications.csv
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Checks scenario files without running them
+    Validate {
+        #[arg(required = true)]
+        scenarios: Vec<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DesignKind {
+    Factorial,
+    Lhs,
+}
+
+#[derive(clap::Args)]
+struct ReplicationArgs {
+    #[arg(short = 'n', long, default_value_t = 10)]
+    replications: usize,
+    #[arg(long, default_value_t = 3)]
+    min_replications: usize,
+    /// Stop once every KPI reaches this relative half width
+    #[arg(long)]
+    precision: Option<f64>,
+    #[arg(long, default_value_t = 0.95)]
+    confidence: f64,
+    /// Seeds of the replications are derived from it, shared by all design points
+    #[arg(long, default_value_t = DEFAULT_SEED)]
+    base_seed: u64,
+    /// Replications run at the same time, defaults to the number of CPUs
+    #[arg(short, long)]
+    jobs: Option<usize>,
+    /// Print every finished replication
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+impl ReplicationArgs {
+    fn settings(&self) -> ReplicationSettings {
+        let defaults = ReplicationSettings::default();
+        ReplicationSettings {
+            max_replications: self.replications,
+            min_replications: self.min_replications.min(self.replications),
+            base_seed: self.base_seed,
+            confidence: self.confidence,
+            target_relative_precision: self.precision,
+            parallelism: self.jobs.unwrap_or(defaults.parallelism),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match execute(cli.command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn execute(command: Command) -> Result<(), String> {
+    match command {
+        Command::Run {
+            scenario,
+            seed,
+            output,
+            trace,
+            quiet,
+        } => {
+            let scenario = load_runnable(&scenario)?;
+            let seed = seed.unwrap_or(scenario.seed);
+            let kpis = run::simulate(&scenario, seed, !quiet, trace.as_deref())
+                .map_err(|error| format!("Simulation failed: {}", error))?;
+            write_report(std::io::stdout().lock(), &kpis).map_err(|error| error.to_string())?;
+            if let Some(output) = output {
+                write_file(&output, "report.csv", |file| write_report(file, &kpis))?;
+            }
+            Ok(())
+        }
+        Command::Replay {
+            scenario,
+            trace,
+            seed,
+        } => {
+            let scenario = load_runnable(&scenario)?;
+            let seed = seed.unwrap_or(scenario.seed);
+            match run::replay(&scenario, seed, &trace) {
+                Ok(matched) => {
+                    println!("{} events match {}", matched, trace.display());
+                    Ok(())
+                }
+                Err(EventEngineError::TraceDiverged(divergence)) => Err(divergence.to_string()),
+                Err(error) => Err(format!("Replay failed: {}", error)),
+            }
+        }
+        Command::Replicate {
+            scenario,
+            replications,
+            output,
+        } => {
+            let definition =
+                ExperimentDefinition::new(Design::FullFactorial, replications.settings());
+            experiment(
+                &scenario,
+                definition,
+                output.as_deref(),
+                replications.verbose,
+            )
+        }
+        Command::Sweep {
+            scenario,
+            parameters,
+            design,
+            samples,
+            replications,
+            output,
+        } => {
+            let design = match design {
+                DesignKind::Factorial => Design::FullFactorial,
+                DesignKind::Lhs => Design::LatinHypercube {
+                    samples,
+                    seed: replications.base_seed,
+                },
+            };
+            let mut definition = ExperimentDefinition::new(design, replications.settings());
+            definition.parameters = parameters;
+            experiment(
+                &scenario,
+                definition,
+                output.as_deref(),
+                replications.verbose,
+            )
+        }
+        Command::Validate { scenarios } => {
+            let mut valid = true;
+            for path in scenarios {
+                match Scenario::load(&path) {
+                    Ok(_) => println!("{}: ok", path.display()),
+                    Err(error) => {
+                        valid = false;
+                        println!("{}:", path.display());
+                        for line in error.to_string().lines() {
+                            println!("  {}", line);
+                        }
+                    }
+                }
+            }
+            if valid {
+                Ok(())
+            } else {
+                Err("Some scenarios are invalid".to_string())
+            }
+        }
+    }
+}
+
+fn experiment(
+    path: &Path,
+    definition: ExperimentDefinition,
+    output: Option<&Path>,
+    verbose: bool,
+) -> Result<(), String> {
+    let scenario = load_runnable(path)?;
+    let mut scenarios = vec![];
+    let points = definition.design_points().map_err(|errors| {
+        let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        format!("Invalid experiment:\n{}", errors.join("\n"))
+    })?;
+    for point in points {
+        let scenario = scenario
+            .with_parameters(&point)
+            .map_err(|error| format!("Design point {}: {}", point.index, error))?;
+        if scenario.empty_environment_settings().is_none() {
+            return Err(format!("Design point {}: {}", point.index, NOT_RUNNABLE));
+        }
+        scenarios.push(scenario);
+    }
+
+    let results = smart_factory_environment::doe::run_experiment(&definition, |point, seed| {
+        let kpis = run::simulate(&scenarios[point.index], seed, false, None);
+        if verbose {
+            eprintln!("Point {} seed {} finished", point.index, seed);
+        }
+        kpis
+    })
+    .map_err(|error| format!("Simulation failed: {}", error))?;
+
+    print_csv(&results)?;
+    if let Some(output) = output {
+        write_file(output, "summary.csv", |file| {
+            results.write_summary_csv(file)
+        })?;
+        write_file(output, "replications.csv", |file| {
+            results.write_replications_csv(file)
+        })?;
+    }
+    Ok(())
+}
+
+const NOT_RUNNABLE: &str = "only the empty environment can be run";
+
+fn load_runnable(path: &Path) -> Result<Scenario, String> {
+    let scenario =
+        Scenario::load(path).map_err(|error| format!("{}:\n{}", path.display(), error))?;
+    match scenario.environment {
+        EnvironmentKind::Empty { .. } => Ok(scenario),
+        _ => Err(format!("{}: {}", path.display(), NOT_RUNNABLE)),
+    }
+}
+
+fn write_report<W: Write>(writer: W, kpis: &Kpis) -> std::io::Result<()> {
+    let mut csv = csv::Writer::from_writer(writer);
+    csv.write_record(["kpi", "value"])?;
+    for (name, value) in kpis {
+        csv.write_record([name.clone(), value.to_string()])?;
+    }
+    csv.flush()
+}
+
+fn print_csv(results: &ExperimentResults) -> Result<(), String> {
+    let stdout = std::io::stdout();
+    results
+        .write_summary_csv(&mut stdout.lock())
+        .map_err(|error| error.to_string())
+}
+
+fn write_file(
+    directory: &Path,
+    name: &str,
+    write: impl FnOnce(&mut BufWriter<File>) -> std::io::Result<()>,
+) -> Result<(), String> {
+    let path = directory.join(name);
+    std::fs::create_dir_all(directory)
+        .and_then(|_| File::create(&path))
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            writer.flush()
+        })
+        .map_err(|error| format!("Could not write {}: {}", path.display(), error))?;
+    eprintln!("Wrote {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY_SCENARIO: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../scenarios/empty.toml");
+
+    #[test]
+    fn the_empty_scenario_runs_and_writes_its_report() {
+        let output =
+            std::env::temp_dir().join(format!("smart-factory-cli-run-{}", std::process::id()));
+        let cli = Cli::parse_from([
+            "smart-factory",
+            "run",
+            EMPTY_SCENARIO,
+            "--quiet",
+            "--output",
+            output.to_str().unwrap(),
+        ]);
+        let result = execute(cli.command);
+        let report = std::fs::read_to_string(output.join("report.csv"));
+        std::fs::remove_dir_all(&output).unwrap();
+        assert_eq!(result, Ok(()));
+        assert!(report.unwrap().lines().any(|line| line == "events,1000000"));
+    }
+
+    #[test]
+    fn report_names_are_quoted() {
+        let kpis = Kpis::from([("lead time, p95".to_string(), 1.5)]);
+        let mut report = vec![];
+        write_report(&mut report, &kpis).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "kpi,value\n\"lead time, p95\",1.5\n"
+        );
+    }
+
+    #[test]
+    fn sweeps_with_invalid_ranges_are_refused() {
+        let cli = Cli::parse_from([
+            "smart-factory",
+            "sweep",
+            EMPTY_SCENARIO,
+            "--param",
+            "stop.max_iter=50..10",
+        ]);
+        let error = execute(cli.command).unwrap_err();
+        assert!(error.starts_with("Invalid experiment:"), "{}", error);
+    }
+}
@@ -0,0 +1,206 @@
+use smart_factory_environment::doe::{Parameter, ParameterRange, ParameterValue};
+use smart_factory_environment::empty_environment::InfiniteEmptyEnvironment;
+use smart_factory_environment::environment::{AgentEnvironment, TracedEnvironment};
+use smart_factory_environment::experiment::{run_to_completion, Kpis};
+use smart_factory_environment::observer::Observer;
+use smart_factory_environment::pacing::{Pacing, Pause};
+use smart_factory_environment::scenario::Scenario;
+use smart_factory_environment::trace::{TraceError, TraceRecorder, TraceReplay};
+use smart_factory_environment::EventEngineError;
+use std::path::Path;
+
+/// Runs a scenario without sleeping. Progress is printed to stderr every `iter_count` events.
+/// Events are written to `trace` if given, JSON Lines for `.jsonl` files and binary otherwise.
+pub fn simulate(
+    scenario: &Scenario,
+    seed: u64,
+    progress: bool,
+    trace: Option<&Path>,
+) -> Result<Kpis, EventEngineError> {
+    let mut scenario = scenario.clone();
+    scenario.seed = seed;
+    // Progress is reported whenever the engine yields
+    scenario.engine.pacing = if progress {
+        Pacing::Yield {
+            every: scenario.engine.iter_count,
+        }
+    } else {
+        Pacing::Unthrottled
+    };
+    let settings = scenario
+        .empty_environment_settings()
+        .expect("Environment type is checked before running");
+
+    let mut environment = InfiniteEmptyEnvironment::without_sleep(|_: &str| {});
+    if progress {
+        environment.add_observer(Box::new(ProgressPrinter));
+    }
+    if let Some(path) = trace {
+        environment.set_trace_recorder(
+            TraceRecorder::create(path).map_err(EventEngineError::CouldNotRecord)?,
+        );
+    }
+    run_to_completion(&mut environment, settings)?;
+    if let Some(mut recorder) = environment.take_trace_recorder() {
+        recorder.flush().map_err(EventEngineError::CouldNotRecord)?;
+    }
+
+    let statistics = environment.get_statistics();
+    let mut kpis = statistics.report(statistics.time()).flatten();
+    kpis.insert("events".to_string(), environment.report() as f64);
+    Ok(kpis)
+}
+
+struct ProgressPrinter;
+
+impl Observer for ProgressPrinter {
+    fn on_pause(&mut self, processed: u64, _pause: Pause) {
+        eprintln!("{} events processed", processed);
+    }
+}
+
+/// Re-runs a scenario against a recorded trace and returns the number of matching events.
+pub fn replay(scenario: &Scenario, seed: u64, trace: &Path) -> Result<u64, EventEngineError> {
+    let mut scenario = scenario.clone();
+    scenario.seed = seed;
+    scenario.engine.pacing = Pacing::Unthrottled;
+    let settings = scenario
+        .empty_environment_settings()
+        .expect("Environment type is checked before running");
+
+    let mut environment = InfiniteEmptyEnvironment::without_sleep(|_: &str| {});
+    let replay = TraceReplay::open(trace)
+        .map_err(|error| EventEngineError::CouldNotReplay(TraceError::Io(error)))?;
+    environment.set_trace_replay(replay);
+    run_to_completion(&mut environment, settings)?;
+    let mut replay = environment
+        .take_trace_replay()
+        .expect("Replay is kept by the environment");
+    replay.finish()?;
+    Ok(replay.matched())
+}
+
+/// Parses `name=spec` where spec is `min..max[:step]` (integers), `min~max` (continuous)
+/// or a comma separated list of levels.
+pub fn parse_parameter(argument: &str) -> Result<Parameter, String> {
+    let (name, spec) = argument
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=VALUES, got '{}'", argument))?;
+    let invalid = || format!("invalid values '{}' of parameter {}", spec, name);
+
+    let range = if let Some((min, max)) = spec.split_once('~') {
+        ParameterRange::Continuous {
+            min: min.trim().parse().map_err(|_| invalid())?,
+            max: max.trim().parse().map_err(|_| invalid())?,
+        }
+    } else if let Some((min, rest)) = spec.split_once("..") {
+        let (max, step) = rest.split_once(':').unwrap_or((rest, "1"));
+        ParameterRange::Integers {
+            min: min.trim().parse().map_err(|_| invalid())?,
+            max: max.trim().parse().map_err(|_| invalid())?,
+            step: step.trim().parse().map_err(|_| invalid())?,
+        }
+    } else {
+        ParameterRange::Levels(spec.split(',').map(parse_value).collect())
+    };
+    Ok(Parameter::new(name.trim(), range))
+}
+
+fn parse_value(value: &str) -> ParameterValue {
+    let value = value.trim();
+    if let Ok(integer) = value.parse() {
+        ParameterValue::Integer(integer)
+    } else if let Ok(real) = value.parse() {
+        ParameterValue::Real(real)
+    } else {
+        ParameterValue::Text(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn empty_scenario() -> Scenario {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../scenarios/empty.toml");
+        Scenario::load(&path).unwrap()
+    }
+
+    #[test]
+    fn parameters_parse_integer_ranges_intervals_and_levels() {
+        assert_eq!(
+            parse_parameter("stop.max_iter=10..50:20").unwrap(),
+            Parameter::new(
+                "stop.max_iter",
+                ParameterRange::Integers {
+                    min: 10,
+                    max: 50,
+                    step: 20
+                }
+            )
+        );
+        assert_eq!(
+            parse_parameter("environment.agent_count = 1..4")
+                .unwrap()
+                .range,
+            ParameterRange::Integers {
+                min: 1,
+                max: 4,
+                step: 1
+            }
+        );
+        assert_eq!(
+            parse_parameter("engine.pacing.scale=0.5~2").unwrap().range,
+            ParameterRange::Continuous { min: 0.5, max: 2.0 }
+        );
+        assert_eq!(
+            parse_parameter("name=3, 0.25, lot").unwrap().range,
+            ParameterRange::Levels(vec![
+                ParameterValue::Integer(3),
+                ParameterValue::Real(0.25),
+                ParameterValue::Text("lot".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn malformed_parameters_are_rejected() {
+        assert_eq!(
+            parse_parameter("stop.max_iter").unwrap_err(),
+            "expected NAME=VALUES, got 'stop.max_iter'"
+        );
+        assert_eq!(
+            parse_parameter("stop.max_iter=1..x").unwrap_err(),
+            "invalid values '1..x' of parameter stop.max_iter"
+        );
+        assert!(parse_parameter("seed=a~1").is_err());
+        assert!(parse_parameter("stop.max_iter=1..5:x").is_err());
+    }
+
+    #[test]
+    fn values_prefer_integers_over_reals_over_text() {
+        assert_eq!(parse_value(" -7 "), ParameterValue::Integer(-7));
+        assert_eq!(parse_value("1e3"), ParameterValue::Real(1000.0));
+        assert_eq!(
+            parse_value("1.5.2"),
+            ParameterValue::Text("1.5.2".to_string())
+        );
+    }
+
+    #[test]
+    fn the_empty_scenario_runs_and_replays_its_trace() {
+        let mut scenario = empty_scenario();
+        scenario.stop.max_iter = Some(1000);
+        let trace =
+            std::env::temp_dir().join(format!("smart-factory-cli-{}.jsonl", std::process::id()));
+
+        let kpis = simulate(&scenario, scenario.seed, false, Some(&trace)).unwrap();
+        assert_eq!(kpis["events"], 1000.0);
+        let matched = replay(&scenario, scenario.seed, &trace);
+        let diverged = replay(&scenario, scenario.seed + 1, &trace);
+        std::fs::remove_file(&trace).unwrap();
+        assert_eq!(matched.unwrap(), 1000);
+        assert!(matches!(diverged, Err(EventEngineError::TraceDiverged(_))));
+    }
+}
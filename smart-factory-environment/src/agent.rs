@@ -1,4 +1,7 @@
+use crate::error::AgentError;
 use crate::event::{Event, EventArg};
+use crate::introspection::Introspect;
+use async_trait::async_trait;
 use uuid::Uuid;
 
 pub type NewEventsVec = Vec<(Event, u64)>;
@@ -6,8 +9,20 @@ pub type NewEventsVec = Vec<(Event, u64)>;
 pub trait Agent {
     fn handle(&mut self, time: u64, args: EventArg) -> NewEventsVec;
 
+    /// Handler called by the engine. Agents whose handler can fail override it, what happens then
+    /// is decided by the `ErrorPolicy` of the run.
+    fn try_handle(&mut self, time: u64, args: EventArg) -> Result<NewEventsVec, AgentError> {
+        Ok(self.handle(time, args))
+    }
+
     fn get_id(&self) -> Uuid;
 
+    /// Properties answering agent queries and reported in progress messages, `None` if the agent
+    /// can not be queried.
+    fn introspect(&self) -> Option<&dyn Introspect> {
+        None
+    }
+
     fn solo_vec(agent: &mut Self) -> Vec<&mut dyn Agent>
     where
         Self: Sized,
@@ -16,6 +31,151 @@ pub trait Agent {
     }
 }
 
+/// Agent whose handler can await, e.g. a plugin call or a JS Promise. Handlers are not required to
+/// be `Send` so that browser futures can be awaited.
+#[async_trait(?Send)]
+pub trait AsyncAgent {
+    async fn handle(&mut self, time: u64, args: EventArg) -> NewEventsVec;
+
+    /// Handler called by the engine, see `Agent::try_handle`.
+    async fn try_handle(&mut self, time: u64, args: EventArg) -> Result<NewEventsVec, AgentError> {
+        Ok(self.handle(time, args).await)
+    }
+
+    fn get_id(&self) -> Uuid;
+
+    /// Properties answering agent queries and reported in progress messages, `None` if the agent
+    /// can not be queried.
+    fn introspect(&self) -> Option<&dyn Introspect> {
+        None
+    }
+}
+
+/// Agent driven by the engine. Synchronous agents are called directly without boxing a future.
+pub enum AgentSlot<'a> {
+    Sync(&'a mut dyn Agent),
+    Async(&'a mut dyn AsyncAgent),
+}
+
+impl<'a> AgentSlot<'a> {
+    pub fn get_id(&self) -> Uuid {
+        match self {
+            AgentSlot::Sync(agent) => agent.get_id(),
+            AgentSlot::Async(agent) => agent.get_id(),
+        }
+    }
+
+    pub fn introspect(&self) -> Option<&dyn Introspect> {
+        match self {
+            AgentSlot::Sync(agent) => agent.introspect(),
+            AgentSlot::Async(agent) => agent.introspect(),
+        }
+    }
+
+    pub async fn handle(&mut self, time: u64, args: EventArg) -> Result<NewEventsVec, AgentError> {
+        match self {
+            AgentSlot::Sync(agent) => agent.try_handle(time, args),
+            AgentSlot::Async(agent) => agent.try_handle(time, args).await,
+        }
+    }
+
+    /// `handle`, but a panic in the handler is caught and returned as `HandleError::Panicked`.
+    /// Panics unwind only on native targets, on WASM they still abort.
+    pub async fn handle_isolated(
+        &mut self,
+        time: u64,
+        args: EventArg,
+    ) -> Result<NewEventsVec, HandleError> {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            use futures::FutureExt;
+            use std::panic::AssertUnwindSafe;
+
+            match AssertUnwindSafe(self.handle(time, args))
+                .catch_unwind()
+                .await
+            {
+                Ok(result) => result.map_err(HandleError::Failed),
+                Err(payload) => Err(HandleError::Panicked(panic_message(payload.as_ref()))),
+            }
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            self.handle(time, args).await.map_err(HandleError::Failed)
+        }
+    }
+}
+
+/// Agent owned by a simulation, see `SimulationBuilder`.
+pub enum BoxedAgent {
+    Sync(Box<dyn Agent>),
+    Async(Box<dyn AsyncAgent>),
+}
+
+impl BoxedAgent {
+    pub fn get_id(&self) -> Uuid {
+        match self {
+            BoxedAgent::Sync(agent) => agent.get_id(),
+            BoxedAgent::Async(agent) => agent.get_id(),
+        }
+    }
+
+    pub fn introspect(&self) -> Option<&dyn Introspect> {
+        match self {
+            BoxedAgent::Sync(agent) => agent.introspect(),
+            BoxedAgent::Async(agent) => agent.introspect(),
+        }
+    }
+
+    pub fn slot(&mut self) -> AgentSlot<'_> {
+        match self {
+            BoxedAgent::Sync(agent) => AgentSlot::Sync(agent.as_mut()),
+            BoxedAgent::Async(agent) => AgentSlot::Async(agent.as_mut()),
+        }
+    }
+}
+
+impl From<Box<dyn Agent>> for BoxedAgent {
+    fn from(agent: Box<dyn Agent>) -> Self {
+        BoxedAgent::Sync(agent)
+    }
+}
+
+impl From<Box<dyn AsyncAgent>> for BoxedAgent {
+    fn from(agent: Box<dyn AsyncAgent>) -> Self {
+        BoxedAgent::Async(agent)
+    }
+}
+
+pub enum HandleError {
+    Failed(AgentError),
+    /// Message of the panic, the agent may have been left in an inconsistent state.
+    Panicked(String),
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+impl<'a> From<&'a mut dyn Agent> for AgentSlot<'a> {
+    fn from(agent: &'a mut dyn Agent) -> Self {
+        AgentSlot::Sync(agent)
+    }
+}
+
+impl<'a> From<&'a mut dyn AsyncAgent> for AgentSlot<'a> {
+    fn from(agent: &'a mut dyn AsyncAgent) -> Self {
+        AgentSlot::Async(agent)
+    }
+}
+
 pub trait AgentToMapExt<TAgent>
 where
     TAgent: Agent,
@@ -0,0 +1,400 @@
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Weekday};
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+// How far ahead working time is searched for before giving up.
+// Keeps calendars without any usable shift from looping forever.
+const WORKING_TIME_SEARCH_DAYS: u64 = 3660;
+
+pub const WORKING_DAYS: [Weekday; 5] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+];
+
+pub const ALL_DAYS: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
+
+/// A recurring working period. A shift whose end is not after its start runs past midnight
+/// and belongs to the day it starts on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Shift {
+    pub name: String,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub days: Vec<Weekday>,
+}
+
+impl Shift {
+    pub fn new(name: &str, start: NaiveTime, end: NaiveTime) -> Shift {
+        Shift {
+            name: name.to_string(),
+            start,
+            end,
+            days: ALL_DAYS.to_vec(),
+        }
+    }
+
+    pub fn on(mut self, days: &[Weekday]) -> Shift {
+        self.days = days.to_vec();
+        self
+    }
+
+    fn interval_on(&self, date: NaiveDate) -> Option<(NaiveDateTime, NaiveDateTime)> {
+        if !self.days.contains(&date.weekday()) {
+            return None;
+        }
+        let start = date.and_time(self.start);
+        let end = if self.end > self.start {
+            date.and_time(self.end)
+        } else {
+            date.succ_opt()?.and_time(self.end)
+        };
+        Some((start, end))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WorkingCalendar {
+    shifts: Vec<Shift>,
+    holidays: BTreeSet<NaiveDate>,
+}
+
+impl WorkingCalendar {
+    pub fn new() -> WorkingCalendar {
+        WorkingCalendar::default()
+    }
+
+    pub fn with_shift(mut self, shift: Shift) -> WorkingCalendar {
+        self.shifts.push(shift);
+        self
+    }
+
+    pub fn with_holiday(mut self, date: NaiveDate) -> WorkingCalendar {
+        self.holidays.insert(date);
+        self
+    }
+
+    pub fn get_shifts(&self) -> &[Shift] {
+        &self.shifts
+    }
+
+    pub fn is_holiday(&self, date: NaiveDate) -> bool {
+        self.holidays.contains(&date)
+    }
+
+    pub fn is_working_time(&self, at: NaiveDateTime) -> bool {
+        if self.is_always_working() {
+            return true;
+        }
+        self.intervals_from(at)
+            .next()
+            .is_some_and(|(start, _)| start <= at)
+    }
+
+    pub fn next_shift_start(&self, at: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut starts = self
+            .days_from(at.date())
+            .flat_map(|date| self.day_intervals(date))
+            .map(|(start, _)| start)
+            .filter(|start| *start >= at);
+        starts.next()
+    }
+
+    /// Moves `at` forward by `duration` of working time, skipping everything outside of shifts.
+    pub fn add_working_time(&self, at: NaiveDateTime, duration: Duration) -> Option<NaiveDateTime> {
+        let mut remaining = TimeDelta::from_std(duration).ok()?;
+        if remaining.is_zero() {
+            return Some(at);
+        }
+        if self.is_always_working() {
+            return at.checked_add_signed(remaining);
+        }
+        for (start, end) in self.intervals_from(at) {
+            let begin = start.max(at);
+            let available = end - begin;
+            if remaining <= available {
+                return Some(begin + remaining);
+            }
+            remaining -= available;
+        }
+        None
+    }
+
+    // All its days merge into a single interval, which the search would walk to its end
+    fn is_always_working(&self) -> bool {
+        self.holidays.is_empty()
+            && self.shifts.iter().any(|shift| {
+                shift.start == shift.end && ALL_DAYS.iter().all(|day| shift.days.contains(day))
+            })
+    }
+
+    fn days_from(&self, date: NaiveDate) -> impl Iterator<Item = NaiveDate> {
+        // Shifts crossing midnight may still be running, so the search begins a day earlier
+        date.pred_opt()
+            .unwrap_or(date)
+            .iter_days()
+            .take(WORKING_TIME_SEARCH_DAYS as usize)
+    }
+
+    fn day_intervals(&self, date: NaiveDate) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+        if self.is_holiday(date) {
+            return vec![];
+        }
+        let mut intervals: Vec<_> = self
+            .shifts
+            .iter()
+            .filter_map(|shift| shift.interval_on(date))
+            .collect();
+        intervals.sort();
+        intervals
+    }
+
+    // Merged working intervals that end after `at`, in chronological order
+    fn intervals_from(
+        &self,
+        at: NaiveDateTime,
+    ) -> impl Iterator<Item = (NaiveDateTime, NaiveDateTime)> + '_ {
+        let mut current: Option<(NaiveDateTime, NaiveDateTime)> = None;
+        let mut days = self.days_from(at.date());
+        let mut pending = vec![].into_iter();
+        std::iter::from_fn(move || loop {
+            let next = match pending.next() {
+                Some(interval) => Some(interval),
+                None => match days.next() {
+                    Some(date) => {
+                        pending = self.day_intervals(date).into_iter();
+                        continue;
+                    }
+                    None => None,
+                },
+            };
+            match (current, next) {
+                (Some((start, end)), Some((next_start, next_end))) if next_start <= end => {
+                    current = Some((start, end.max(next_end)))
+                }
+                (Some(merged), next) => {
+                    current = next;
+                    if merged.1 > at {
+                        return Some(merged);
+                    }
+                    current?;
+                }
+                (None, Some(interval)) => current = Some(interval),
+                (None, None) => return None,
+            }
+        })
+    }
+}
+
+/// Maps simulated time (ticks) onto date-times.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SimulationCalendar {
+    start: NaiveDateTime,
+    tick: Duration,
+    working_calendar: WorkingCalendar,
+}
+
+impl SimulationCalendar {
+    pub fn new(start: NaiveDateTime, tick: Duration) -> SimulationCalendar {
+        assert!(!tick.is_zero(), "Tick length must be positive");
+        SimulationCalendar {
+            start,
+            tick,
+            working_calendar: WorkingCalendar::new().with_shift(Shift::new(
+                "All day",
+                NaiveTime::MIN,
+                NaiveTime::MIN,
+            )),
+        }
+    }
+
+    pub fn with_working_calendar(
+        mut self,
+        working_calendar: WorkingCalendar,
+    ) -> SimulationCalendar {
+        self.working_calendar = working_calendar;
+        self
+    }
+
+    pub fn get_start(&self) -> NaiveDateTime {
+        self.start
+    }
+
+    pub fn get_tick(&self) -> Duration {
+        self.tick
+    }
+
+    pub fn get_working_calendar(&self) -> &WorkingCalendar {
+        &self.working_calendar
+    }
+
+    /// Returns the date time of `time`, or `None` if it is out of the range `chrono` supports.
+    pub fn to_date_time(&self, time: u64) -> Option<NaiveDateTime> {
+        let nanos = self.tick.as_nanos().checked_mul(time as u128)?;
+        i64::try_from(nanos)
+            .ok()
+            .and_then(|nanos| self.start.checked_add_signed(TimeDelta::nanoseconds(nanos)))
+    }
+
+    /// Returns the first tick not earlier than `date_time`, or `None` if it precedes the start.
+    pub fn to_time(&self, date_time: NaiveDateTime) -> Option<u64> {
+        let since_start = (date_time - self.start).to_std().ok()?;
+        u64::try_from(since_start.as_nanos().div_ceil(self.tick.as_nanos())).ok()
+    }
+
+    pub fn ticks(&self, duration: Duration) -> u64 {
+        u64::try_from(duration.as_nanos().div_ceil(self.tick.as_nanos())).unwrap_or(u64::MAX)
+    }
+
+    /// Times out of the calendar range are not working time.
+    pub fn is_working_time(&self, time: u64) -> bool {
+        self.to_date_time(time)
+            .is_some_and(|date_time| self.working_calendar.is_working_time(date_time))
+    }
+
+    pub fn next_shift_start(&self, time: u64) -> Option<u64> {
+        self.to_date_time(time)
+            .and_then(|date_time| self.working_calendar.next_shift_start(date_time))
+            .and_then(|date_time| self.to_time(date_time))
+    }
+
+    pub fn after_working_time(&self, time: u64, duration: Duration) -> Option<u64> {
+        self.to_date_time(time)
+            .and_then(|date_time| self.working_calendar.add_working_time(date_time, duration))
+            .and_then(|date_time| self.to_time(date_time))
+    }
+
+    pub fn after_working_hours(&self, time: u64, hours: u64) -> Option<u64> {
+        self.after_working_time(time, Duration::from_secs(hours * 3600))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        // 2022-08-01 is a Monday
+        NaiveDate::from_ymd_opt(2022, 8, day).unwrap()
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        date(day).and_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn hm(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn two_shift_calendar() -> WorkingCalendar {
+        WorkingCalendar::new()
+            .with_shift(Shift::new("Morning", hm(6, 0), hm(14, 0)).on(&WORKING_DAYS))
+            .with_shift(Shift::new("Late", hm(14, 0), hm(22, 0)).on(&WORKING_DAYS))
+            .with_holiday(date(3))
+    }
+
+    #[test]
+    fn it_maps_ticks_to_date_times() {
+        let calendar = SimulationCalendar::new(at(1, 0, 0), Duration::from_secs(60));
+        assert_eq!(calendar.to_date_time(0), Some(at(1, 0, 0)));
+        assert_eq!(calendar.to_date_time(90), Some(at(1, 1, 30)));
+        assert_eq!(calendar.to_date_time(u64::MAX), None);
+        assert_eq!(calendar.to_time(at(1, 1, 30)), Some(90));
+        assert_eq!(
+            calendar.to_time(at(1, 1, 30) + TimeDelta::seconds(1)),
+            Some(91)
+        );
+        assert_eq!(calendar.to_time(at(1, 0, 0) - TimeDelta::seconds(1)), None);
+        assert_eq!(calendar.ticks(Duration::from_secs(3600)), 60);
+    }
+
+    #[test]
+    fn default_calendar_is_always_working() {
+        let calendar = SimulationCalendar::new(at(1, 0, 0), Duration::from_secs(60));
+        assert!(calendar.is_working_time(12345));
+        assert_eq!(calendar.after_working_hours(0, 3), Some(180));
+        // Beyond the search limit of calendars with gaps
+        let twenty_years = 20 * 365 * 24;
+        assert_eq!(
+            calendar.after_working_hours(0, twenty_years),
+            Some(twenty_years * 60)
+        );
+        let weekdays_only = WorkingCalendar::new()
+            .with_shift(Shift::new("All day", NaiveTime::MIN, NaiveTime::MIN).on(&WORKING_DAYS));
+        let calendar = calendar.with_working_calendar(weekdays_only);
+        assert_eq!(calendar.after_working_hours(0, twenty_years), None);
+    }
+
+    #[test]
+    fn it_finds_next_shift_start() {
+        let calendar = two_shift_calendar();
+        assert_eq!(calendar.next_shift_start(at(1, 5, 0)), Some(at(1, 6, 0)));
+        assert_eq!(calendar.next_shift_start(at(1, 6, 0)), Some(at(1, 6, 0)));
+        assert_eq!(calendar.next_shift_start(at(1, 6, 1)), Some(at(1, 14, 0)));
+        // 3rd is a holiday
+        assert_eq!(calendar.next_shift_start(at(2, 23, 0)), Some(at(4, 6, 0)));
+        // Friday evening to Monday morning
+        assert_eq!(calendar.next_shift_start(at(5, 22, 0)), Some(at(8, 6, 0)));
+    }
+
+    #[test]
+    fn it_adds_working_time_across_shifts_and_days() {
+        let calendar = two_shift_calendar();
+        assert_eq!(
+            calendar.add_working_time(at(1, 13, 0), Duration::from_secs(2 * 3600)),
+            Some(at(1, 15, 0))
+        );
+        assert_eq!(
+            calendar.add_working_time(at(2, 21, 0), Duration::from_secs(3 * 3600)),
+            Some(at(4, 8, 0))
+        );
+        assert_eq!(
+            calendar.add_working_time(at(6, 10, 0), Duration::from_secs(3600)),
+            Some(at(8, 7, 0))
+        );
+        assert!(calendar.is_working_time(at(1, 14, 0)));
+        assert!(!calendar.is_working_time(at(1, 22, 0)));
+        assert!(!calendar.is_working_time(at(3, 10, 0)));
+    }
+
+    #[test]
+    fn it_handles_shifts_crossing_midnight() {
+        let calendar = WorkingCalendar::new()
+            .with_shift(Shift::new("Night", hm(22, 0), hm(6, 0)).on(&[Weekday::Mon]));
+        assert!(calendar.is_working_time(at(2, 3, 0)));
+        assert!(!calendar.is_working_time(at(2, 7, 0)));
+        assert_eq!(
+            calendar.add_working_time(at(1, 23, 0), Duration::from_secs(2 * 3600)),
+            Some(at(2, 1, 0))
+        );
+        assert_eq!(calendar.next_shift_start(at(2, 3, 0)), Some(at(8, 22, 0)));
+    }
+
+    #[test]
+    fn calendar_without_shifts_has_no_working_time() {
+        let calendar = WorkingCalendar::new();
+        assert_eq!(calendar.next_shift_start(at(1, 0, 0)), None);
+        assert_eq!(
+            calendar.add_working_time(at(1, 0, 0), Duration::from_secs(1)),
+            None
+        );
+    }
+
+    #[test]
+    fn agents_can_schedule_in_ticks() {
+        let calendar = SimulationCalendar::new(at(1, 0, 0), Duration::from_secs(60))
+            .with_working_calendar(two_shift_calendar());
+        assert_eq!(calendar.next_shift_start(0), Some(6 * 60));
+        assert_eq!(calendar.after_working_hours(13 * 60, 2), Some(15 * 60));
+    }
+}
@@ -0,0 +1,286 @@
+use crate::message::{IncomingQueueMessage, OutgoingQueueMessage, Progress};
+use futures::channel::mpsc;
+use futures::Stream;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+/// Messages queued by the engine before progress messages are coalesced.
+pub const DEFAULT_OUTGOING_CAPACITY: usize = 1024;
+
+pub type IncomingSender = mpsc::UnboundedSender<IncomingQueueMessage>;
+pub type IncomingReceiver = mpsc::UnboundedReceiver<IncomingQueueMessage>;
+
+/// Channel to control a running engine. It does not depend on an async runtime, the engine reads
+/// every pending message before each event and wakes up from pacing sleeps to read new ones.
+pub fn incoming_channel() -> (IncomingSender, IncomingReceiver) {
+    mpsc::unbounded()
+}
+
+/// Channel the engine reports through. Once `capacity` messages are queued, `Iter`, `Progress` and
+/// `Paused` messages are merged into the latest queued message of the same kind, or dropped if
+/// there is none. Every other message is always queued.
+pub fn outgoing_channel(capacity: usize) -> (OutgoingSender, OutgoingStream) {
+    let shared = Arc::new(Mutex::new(Shared {
+        queue: VecDeque::new(),
+        capacity,
+        dropped: 0,
+        waker: None,
+        sending: true,
+        receiving: true,
+    }));
+    (
+        OutgoingSender {
+            shared: shared.clone(),
+        },
+        OutgoingStream { shared },
+    )
+}
+
+struct Shared {
+    queue: VecDeque<OutgoingQueueMessage>,
+    capacity: usize,
+    dropped: u64,
+    waker: Option<Waker>,
+    sending: bool,
+    receiving: bool,
+}
+
+fn lock(shared: &Mutex<Shared>) -> MutexGuard<'_, Shared> {
+    // Nothing panics while the lock is held, a poisoned queue is still consistent
+    shared
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The stream was dropped, the message could not be delivered.
+pub struct SendError(pub OutgoingQueueMessage);
+
+impl Debug for SendError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SendError").finish_non_exhaustive()
+    }
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sending on a closed channel")
+    }
+}
+
+impl Error for SendError {}
+
+pub struct OutgoingSender {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl OutgoingSender {
+    pub fn send(&self, message: OutgoingQueueMessage) -> Result<(), SendError> {
+        let mut shared = lock(&self.shared);
+        if !shared.receiving {
+            return Err(SendError(message));
+        }
+        if shared.queue.len() < shared.capacity || !is_lossy(&message) {
+            shared.queue.push_back(message);
+        } else if let Some(queued) = shared
+            .queue
+            .iter_mut()
+            .rev()
+            .find(|queued| same_kind(queued, &message))
+        {
+            coalesce(queued, message);
+        } else {
+            shared.dropped += 1;
+            return Ok(());
+        }
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// For channels that are opened before the settings of their run are known.
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        lock(&self.shared).capacity = capacity;
+    }
+}
+
+impl Drop for OutgoingSender {
+    fn drop(&mut self) {
+        let mut shared = lock(&self.shared);
+        shared.sending = false;
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+fn is_lossy(message: &OutgoingQueueMessage) -> bool {
+    matches!(
+        message,
+        OutgoingQueueMessage::Iter(_)
+            | OutgoingQueueMessage::Progress(_)
+            | OutgoingQueueMessage::Paused(_)
+    )
+}
+
+fn same_kind(queued: &OutgoingQueueMessage, message: &OutgoingQueueMessage) -> bool {
+    std::mem::discriminant(queued) == std::mem::discriminant(message)
+}
+
+/// Replaces `queued` with the newer `message`. Changed agents of `queued` that `message` does not
+/// carry are kept, so no change is lost.
+fn coalesce(queued: &mut OutgoingQueueMessage, message: OutgoingQueueMessage) {
+    if let (OutgoingQueueMessage::Progress(older), OutgoingQueueMessage::Progress(newer)) =
+        (&*queued, &message)
+    {
+        let mut changed_agents: Vec<_> = older
+            .changed_agents
+            .iter()
+            .filter(|older| {
+                !newer
+                    .changed_agents
+                    .iter()
+                    .any(|newer| newer.agent == older.agent)
+            })
+            .cloned()
+            .collect();
+        changed_agents.extend(newer.changed_agents.iter().cloned());
+        *queued = OutgoingQueueMessage::Progress(Progress {
+            changed_agents,
+            ..newer.clone()
+        });
+    } else {
+        *queued = message;
+    }
+}
+
+/// Messages of one run, ends once the engine finished.
+pub struct OutgoingStream {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl OutgoingStream {
+    /// Next queued message without waiting.
+    pub fn try_recv(&mut self) -> Option<OutgoingQueueMessage> {
+        lock(&self.shared).queue.pop_front()
+    }
+
+    /// Queued messages without waiting.
+    pub fn try_iter(&mut self) -> impl Iterator<Item = OutgoingQueueMessage> + '_ {
+        std::iter::from_fn(|| self.try_recv())
+    }
+
+    /// Lossy messages dropped so far because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        lock(&self.shared).dropped
+    }
+}
+
+impl Stream for OutgoingStream {
+    type Item = OutgoingQueueMessage;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut shared = lock(&self.shared);
+        if let Some(message) = shared.queue.pop_front() {
+            Poll::Ready(Some(message))
+        } else if !shared.sending {
+            Poll::Ready(None)
+        } else {
+            shared.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for OutgoingStream {
+    fn drop(&mut self) {
+        lock(&self.shared).receiving = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::AgentStateChange;
+    use crate::observer::FinishReason;
+    use futures::StreamExt;
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    fn progress(events: u64, agents: &[(u128, &str)]) -> OutgoingQueueMessage {
+        OutgoingQueueMessage::Progress(Progress {
+            time: events,
+            events,
+            queue_length: 0,
+            events_per_second: 0.0,
+            changed_agents: agents
+                .iter()
+                .map(|(agent, state)| AgentStateChange {
+                    agent: Uuid::from_u128(*agent),
+                    properties: BTreeMap::from([("state".to_string(), Value::from(*state))]),
+                })
+                .collect(),
+        })
+    }
+
+    #[test]
+    fn full_queues_coalesce_progress_messages() {
+        let (sender, mut stream) = outgoing_channel(2);
+        assert!(sender.send(OutgoingQueueMessage::Started).is_ok());
+        assert!(sender.send(progress(1, &[(1, "a"), (2, "a")])).is_ok());
+        assert!(sender.send(progress(2, &[(2, "b")])).is_ok());
+        assert!(sender.send(OutgoingQueueMessage::Iter(2)).is_ok());
+        assert!(sender
+            .send(OutgoingQueueMessage::Finished(FinishReason::Halted))
+            .is_ok());
+        let messages: Vec<OutgoingQueueMessage> = stream.try_iter().collect();
+        assert_eq!(messages.len(), 3);
+        assert!(matches!(messages[0], OutgoingQueueMessage::Started));
+        match &messages[1] {
+            OutgoingQueueMessage::Progress(progress) => {
+                assert_eq!(progress.events, 2);
+                let states: Vec<(Uuid, &Value)> = progress
+                    .changed_agents
+                    .iter()
+                    .map(|change| (change.agent, &change.properties["state"]))
+                    .collect();
+                assert_eq!(
+                    states,
+                    vec![
+                        (Uuid::from_u128(1), &Value::from("a")),
+                        (Uuid::from_u128(2), &Value::from("b"))
+                    ]
+                );
+            }
+            _ => panic!("Expected progress"),
+        }
+        assert_eq!(stream.dropped(), 1);
+    }
+
+    #[test]
+    fn streams_end_with_the_sender() {
+        let (sender, stream) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        let receiving = std::thread::spawn(move || {
+            futures::executor::block_on(stream.collect::<Vec<OutgoingQueueMessage>>())
+        });
+        assert!(sender.send(OutgoingQueueMessage::Started).is_ok());
+        assert!(sender
+            .send(OutgoingQueueMessage::Paused(Duration::from_millis(1)))
+            .is_ok());
+        drop(sender);
+        assert_eq!(receiving.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn sending_fails_once_the_stream_is_dropped() {
+        let (sender, stream) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        drop(stream);
+        assert!(sender.send(OutgoingQueueMessage::Started).is_err());
+    }
+}
@@ -0,0 +1,591 @@
+use crate::error::EventEngineError;
+use crate::experiment::{run_replications, Kpis, ReplicationReport, ReplicationSettings};
+use crate::rng::{simulation_rng, DEFAULT_SEED};
+use crate::scenario::ValidationError;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParameterValue {
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+impl Display for ParameterValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParameterValue::Integer(value) => write!(f, "{}", value),
+            ParameterValue::Real(value) => write!(f, "{}", value),
+            ParameterValue::Text(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// Values a parameter can take. Continuous ranges are sampled by the Latin hypercube design and
+/// contribute their bounds to the factorial one.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParameterRange {
+    Levels(Vec<ParameterValue>),
+    Integers { min: i64, max: i64, step: i64 },
+    Continuous { min: f64, max: f64 },
+}
+
+impl ParameterRange {
+    fn levels(&self) -> Vec<ParameterValue> {
+        match self {
+            ParameterRange::Levels(levels) => levels.clone(),
+            ParameterRange::Integers { min, max, step } => (*min..=*max)
+                .step_by(*step as usize)
+                .map(ParameterValue::Integer)
+                .collect(),
+            ParameterRange::Continuous { min, max } => {
+                vec![ParameterValue::Real(*min), ParameterValue::Real(*max)]
+            }
+        }
+    }
+
+    fn validate(&self, path: &str, errors: &mut Vec<ValidationError>) {
+        match self {
+            ParameterRange::Levels(levels) if levels.is_empty() => errors.push(
+                ValidationError::new(path.to_string(), "must have at least one level"),
+            ),
+            ParameterRange::Integers { min, max, step } => {
+                if min > max {
+                    errors.push(ValidationError::new(
+                        format!("{}.max", path),
+                        "must not be less than min",
+                    ));
+                }
+                if *step <= 0 {
+                    errors.push(ValidationError::new(
+                        format!("{}.step", path),
+                        "must be at least 1",
+                    ));
+                }
+            }
+            ParameterRange::Continuous { min, max } => {
+                if !(min.is_finite() && max.is_finite()) {
+                    errors.push(ValidationError::new(
+                        path.to_string(),
+                        "bounds must be finite numbers",
+                    ));
+                } else if min > max {
+                    errors.push(ValidationError::new(
+                        format!("{}.max", path),
+                        "must not be less than min",
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Value for a point `u` in `[0, 1)`.
+    fn sample(&self, u: f64) -> ParameterValue {
+        match self {
+            ParameterRange::Continuous { min, max } => ParameterValue::Real(min + u * (max - min)),
+            _ => {
+                let levels = self.levels();
+                let index = ((u * levels.len() as f64) as usize).min(levels.len() - 1);
+                levels[index].clone()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parameter {
+    pub name: String,
+    pub range: ParameterRange,
+}
+
+impl Parameter {
+    pub fn new(name: &str, range: ParameterRange) -> Parameter {
+        Parameter {
+            name: name.to_string(),
+            range,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Design {
+    /// Every combination of parameter levels.
+    FullFactorial,
+    LatinHypercube {
+        samples: usize,
+        seed: u64,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DesignPoint {
+    pub index: usize,
+    pub values: BTreeMap<String, ParameterValue>,
+}
+
+impl DesignPoint {
+    pub fn get(&self, name: &str) -> Option<&ParameterValue> {
+        self.values.get(name)
+    }
+
+    pub fn get_integer(&self, name: &str) -> Option<i64> {
+        match self.get(name)? {
+            ParameterValue::Integer(value) => Some(*value),
+            ParameterValue::Real(value) => Some(value.round() as i64),
+            ParameterValue::Text(value) => value.parse().ok(),
+        }
+    }
+
+    pub fn get_real(&self, name: &str) -> Option<f64> {
+        match self.get(name)? {
+            ParameterValue::Integer(value) => Some(*value as f64),
+            ParameterValue::Real(value) => Some(*value),
+            ParameterValue::Text(value) => value.parse().ok(),
+        }
+    }
+
+    pub fn get_text(&self, name: &str) -> Option<String> {
+        self.get(name).map(|value| value.to_string())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExperimentDefinition {
+    pub parameters: Vec<Parameter>,
+    pub design: Design,
+    pub replications: ReplicationSettings,
+}
+
+impl ExperimentDefinition {
+    pub fn new(design: Design, replications: ReplicationSettings) -> ExperimentDefinition {
+        ExperimentDefinition {
+            parameters: vec![],
+            design,
+            replications,
+        }
+    }
+
+    pub fn with_parameter(mut self, name: &str, range: ParameterRange) -> ExperimentDefinition {
+        self.parameters.push(Parameter::new(name, range));
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = vec![];
+        for (index, parameter) in self.parameters.iter().enumerate() {
+            parameter
+                .range
+                .validate(&format!("parameters[{}].range", index), &mut errors);
+        }
+        if let Design::LatinHypercube { samples: 0, .. } = self.design {
+            errors.push(ValidationError::new(
+                "design.samples".to_string(),
+                "must be at least 1",
+            ));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Points of the design, or the fields of the definition that are invalid.
+    pub fn design_points(&self) -> Result<Vec<DesignPoint>, Vec<ValidationError>> {
+        self.validate()?;
+        let rows = match &self.design {
+            Design::FullFactorial => self.full_factorial(),
+            Design::LatinHypercube { samples, seed } => self.latin_hypercube(*samples, *seed),
+        };
+        Ok(rows
+            .into_iter()
+            .enumerate()
+            .map(|(index, row)| DesignPoint {
+                index,
+                values: self
+                    .parameters
+                    .iter()
+                    .map(|parameter| parameter.name.clone())
+                    .zip(row)
+                    .collect(),
+            })
+            .collect())
+    }
+
+    fn full_factorial(&self) -> Vec<Vec<ParameterValue>> {
+        let mut rows: Vec<Vec<ParameterValue>> = vec![vec![]];
+        for parameter in &self.parameters {
+            let levels = parameter.range.levels();
+            rows = rows
+                .into_iter()
+                .flat_map(|row| {
+                    levels.iter().map(move |level| {
+                        let mut row = row.clone();
+                        row.push(level.clone());
+                        row
+                    })
+                })
+                .collect();
+        }
+        rows
+    }
+
+    fn latin_hypercube(&self, samples: usize, seed: u64) -> Vec<Vec<ParameterValue>> {
+        let mut rng = simulation_rng(seed, 0);
+        let columns: Vec<Vec<ParameterValue>> = self
+            .parameters
+            .iter()
+            .map(|parameter| {
+                let mut strata: Vec<usize> = (0..samples).collect();
+                strata.shuffle(&mut rng);
+                strata
+                    .into_iter()
+                    .map(|stratum| {
+                        let u = (stratum as f64 + rng.gen::<f64>()) / samples as f64;
+                        parameter.range.sample(u)
+                    })
+                    .collect()
+            })
+            .collect();
+        (0..samples)
+            .map(|sample| {
+                columns
+                    .iter()
+                    .map(|column| column[sample].clone())
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+impl Default for ExperimentDefinition {
+    fn default() -> Self {
+        ExperimentDefinition::new(
+            Design::LatinHypercube {
+                samples: 10,
+                seed: DEFAULT_SEED,
+            },
+            ReplicationSettings::default(),
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DesignPointResult {
+    pub point: DesignPoint,
+    pub report: ReplicationReport,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExperimentResults {
+    pub parameters: Vec<String>,
+    pub points: Vec<DesignPointResult>,
+}
+
+impl ExperimentResults {
+    fn kpi_names(&self) -> BTreeSet<String> {
+        self.points
+            .iter()
+            .flat_map(|result| result.report.summary.keys().cloned())
+            .collect()
+    }
+
+    /// One row per design point with mean and half width of every KPI.
+    pub fn write_summary_csv<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let kpis = self.kpi_names();
+        let mut header: Vec<String> = vec!["point".to_string()];
+        header.extend(self.parameters.iter().cloned());
+        header.push("replications".to_string());
+        for kpi in &kpis {
+            header.push(format!("{}.mean", kpi));
+            header.push(format!("{}.half_width", kpi));
+        }
+        let mut csv = csv::Writer::from_writer(writer);
+        csv.write_record(&header)?;
+        for result in &self.points {
+            let mut row = self.point_columns(&result.point);
+            row.push(result.report.replications.len().to_string());
+            for kpi in &kpis {
+                match result.report.summary.get(kpi) {
+                    Some(interval) => {
+                        row.push(interval.mean.to_string());
+                        row.push(interval.half_width.to_string());
+                    }
+                    None => row.extend([String::new(), String::new()]),
+                }
+            }
+            csv.write_record(&row)?;
+        }
+        csv.flush()
+    }
+
+    /// One row per replication of every design point.
+    pub fn write_replications_csv<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let kpis = self.kpi_names();
+        let mut header: Vec<String> = vec!["point".to_string()];
+        header.extend(self.parameters.iter().cloned());
+        header.push("replication".to_string());
+        header.push("seed".to_string());
+        header.extend(kpis.iter().cloned());
+        let mut csv = csv::Writer::from_writer(writer);
+        csv.write_record(&header)?;
+        for result in &self.points {
+            for replication in &result.report.replications {
+                let mut row = self.point_columns(&result.point);
+                row.push(replication.index.to_string());
+                row.push(replication.seed.to_string());
+                for kpi in &kpis {
+                    row.push(
+                        replication
+                            .kpis
+                            .get(kpi)
+                            .map_or(String::new(), |value| value.to_string()),
+                    );
+                }
+                csv.write_record(&row)?;
+            }
+        }
+        csv.flush()
+    }
+
+    fn point_columns(&self, point: &DesignPoint) -> Vec<String> {
+        let mut row = vec![point.index.to_string()];
+        row.extend(
+            self.parameters
+                .iter()
+                .map(|name| point.get_text(name).unwrap_or_default()),
+        );
+        row
+    }
+}
+
+#[derive(Debug)]
+pub enum ExperimentError {
+    Invalid(Vec<ValidationError>),
+    Failed(EventEngineError),
+}
+
+impl Display for ExperimentError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExperimentError::Invalid(errors) => {
+                let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+                write!(f, "{}", errors.join("\n"))
+            }
+            ExperimentError::Failed(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for ExperimentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ExperimentError::Invalid(_) => None,
+            ExperimentError::Failed(error) => Some(error),
+        }
+    }
+}
+
+impl From<EventEngineError> for ExperimentError {
+    fn from(error: EventEngineError) -> Self {
+        ExperimentError::Failed(error)
+    }
+}
+
+/// Runs the replications of every design point. All points share the replication seeds of
+/// `definition.replications`, so scenarios are compared under common random numbers.
+pub fn run_experiment<Run>(
+    definition: &ExperimentDefinition,
+    run: Run,
+) -> Result<ExperimentResults, ExperimentError>
+where
+    Run: Fn(&DesignPoint, u64) -> Result<Kpis, EventEngineError> + Sync,
+{
+    let mut points = vec![];
+    for point in definition
+        .design_points()
+        .map_err(ExperimentError::Invalid)?
+    {
+        let report = run_replications(&definition.replications, |seed| run(&point, seed))?;
+        points.push(DesignPointResult { point, report });
+    }
+    Ok(ExperimentResults {
+        parameters: definition
+            .parameters
+            .iter()
+            .map(|parameter| parameter.name.clone())
+            .collect(),
+        points,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::empty_environment::{EmptyEnvironmentSettings, InfiniteEmptyEnvironment};
+    use crate::environment::AgentEnvironment;
+    use crate::experiment::run_to_completion;
+    use std::time::Duration;
+
+    fn replications(count: usize) -> ReplicationSettings {
+        ReplicationSettings {
+            max_replications: count,
+            min_replications: count,
+            parallelism: 1,
+            ..ReplicationSettings::default()
+        }
+    }
+
+    #[test]
+    fn full_factorial_enumerates_all_combinations() {
+        let definition = ExperimentDefinition::new(Design::FullFactorial, replications(1))
+            .with_parameter(
+                "machines",
+                ParameterRange::Integers {
+                    min: 1,
+                    max: 5,
+                    step: 2,
+                },
+            )
+            .with_parameter(
+                "rule",
+                ParameterRange::Levels(vec![
+                    ParameterValue::Text("FIFO".to_string()),
+                    ParameterValue::Text("EDD".to_string()),
+                ]),
+            );
+        let points = definition.design_points().unwrap();
+        assert_eq!(points.len(), 6);
+        assert_eq!(points[0].get_integer("machines"), Some(1));
+        assert_eq!(points[0].get_text("rule").as_deref(), Some("FIFO"));
+        assert_eq!(points[5].get_integer("machines"), Some(5));
+        assert_eq!(points[5].get_text("rule").as_deref(), Some("EDD"));
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        let definition = ExperimentDefinition::new(Design::FullFactorial, replications(1))
+            .with_parameter("rule", ParameterRange::Levels(vec![]))
+            .with_parameter(
+                "machines",
+                ParameterRange::Integers {
+                    min: 5,
+                    max: 1,
+                    step: 0,
+                },
+            )
+            .with_parameter("rate", ParameterRange::Continuous { min: 2.0, max: 1.0 });
+        let paths: Vec<String> = definition
+            .design_points()
+            .unwrap_err()
+            .into_iter()
+            .map(|error| error.path)
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                "parameters[0].range",
+                "parameters[1].range.max",
+                "parameters[1].range.step",
+                "parameters[2].range.max",
+            ]
+        );
+        let latin_hypercube =
+            ExperimentDefinition::default().with_parameter("rule", ParameterRange::Levels(vec![]));
+        assert!(matches!(
+            run_experiment(&latin_hypercube, |_, _| Ok(Kpis::new())),
+            Err(ExperimentError::Invalid(errors)) if errors.len() == 1
+        ));
+        let no_samples = ExperimentDefinition::new(
+            Design::LatinHypercube {
+                samples: 0,
+                seed: DEFAULT_SEED,
+            },
+            replications(1),
+        );
+        assert_eq!(no_samples.validate().unwrap_err()[0].path, "design.samples");
+    }
+
+    #[test]
+    fn latin_hypercube_covers_every_stratum() {
+        let definition = ExperimentDefinition::default()
+            .with_parameter(
+                "buffer",
+                ParameterRange::Continuous {
+                    min: 0.0,
+                    max: 10.0,
+                },
+            )
+            .with_parameter("rate", ParameterRange::Continuous { min: 1.0, max: 2.0 });
+        let points = definition.design_points().unwrap();
+        assert_eq!(points.len(), 10);
+        let mut buffer_strata: Vec<usize> = points
+            .iter()
+            .map(|point| point.get_real("buffer").unwrap() as usize)
+            .collect();
+        buffer_strata.sort();
+        assert_eq!(buffer_strata, (0..10).collect::<Vec<_>>());
+        let mut rate_strata: Vec<usize> = points
+            .iter()
+            .map(|point| ((point.get_real("rate").unwrap() - 1.0) * 10.0) as usize)
+            .collect();
+        rate_strata.sort();
+        assert_eq!(rate_strata, (0..10).collect::<Vec<_>>());
+        assert_eq!(points, definition.design_points().unwrap());
+    }
+
+    #[test]
+    fn design_points_share_replication_seeds() {
+        let definition = ExperimentDefinition::new(Design::FullFactorial, replications(3))
+            .with_parameter(
+                "agents",
+                ParameterRange::Integers {
+                    min: 1,
+                    max: 3,
+                    step: 1,
+                },
+            );
+        let results = run_experiment(&definition, |point, seed| {
+            let agents = point.get_integer("agents").unwrap() as usize;
+            let mut environment =
+                InfiniteEmptyEnvironment::new(|_: &str| {}, |_: Duration| async {});
+            run_to_completion(
+                &mut environment,
+                EmptyEnvironmentSettings::new(agents, 0, 100, 10 * agents as u64).with_seed(seed),
+            )?;
+            let mut kpis = Kpis::new();
+            kpis.insert("events".to_string(), environment.report() as f64);
+            Ok(kpis)
+        })
+        .unwrap();
+        assert_eq!(results.points.len(), 3);
+        let seeds: Vec<Vec<u64>> = results
+            .points
+            .iter()
+            .map(|result| result.report.replications.iter().map(|r| r.seed).collect())
+            .collect();
+        assert!(seeds.iter().all(|point_seeds| *point_seeds == seeds[0]));
+        assert_eq!(results.points[2].report.summary["events"].mean, 30.0);
+
+        let mut summary = vec![];
+        results.write_summary_csv(&mut summary).unwrap();
+        let summary = String::from_utf8(summary).unwrap();
+        let lines: Vec<&str> = summary.lines().collect();
+        assert_eq!(
+            lines[0],
+            "point,agents,replications,events.mean,events.half_width"
+        );
+        assert_eq!(lines[3], "2,3,3,30,0");
+
+        let mut table = vec![];
+        results.write_replications_csv(&mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        assert_eq!(table.lines().count(), 1 + 3 * 3);
+        assert!(table.starts_with("point,agents,replication,seed,events\n"));
+    }
+}
@@ -1,13 +1,24 @@
 use std::fmt::{Display, Formatter};
 use crate::agent::{Agent, AgentToMapExt};
-use crate::environment::{AgentEnvironment, EnvironmentSettings};
-use crate::event::{Event, EventArg};
-use crate::event_queue::EventEngineError;
-use crate::message::{IncomingQueueMessage, OutgoingQueueMessage};
+use crate::channel::{
+    incoming_channel, outgoing_channel, IncomingReceiver, IncomingSender, OutgoingSender,
+    OutgoingStream, DEFAULT_OUTGOING_CAPACITY,
+};
+use crate::environment::{AgentEnvironment, EnvironmentSettings, TracedEnvironment, DEFAULT_TICK};
+use crate::error::{ErrorPolicy, EventEngineError};
+use crate::event::{Event, EventArg, InjectedEventArg};
+use crate::introspection::{AgentQuery, AgentSnapshot, Introspect};
+use crate::message::{IncomingQueueMessage, InjectAt, ProgressRate};
+use crate::observer::Observer;
+use crate::pacing::Pacing;
+use crate::rng::{simulation_rng, simulation_uuid, AGENT_ID_STREAM, DEFAULT_SEED};
+use crate::simulation::SimulationBuilder;
+use crate::statistics::{Statistics, WarmUp};
+use crate::trace::{trace_sink, TraceRecorder, TraceReplay};
+use serde_json::Value;
+use std::collections::BTreeMap;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
 use std::time::Duration;
 use uuid::Uuid;
 
@@ -15,7 +26,15 @@ pub struct EmptyEnvironmentSettings {
     agent_count: usize,
     sleep_ms: u64,
     iter_count: u64,
-    max_iter: u64
+    max_iter: u64,
+    seed: u64,
+    warm_up: WarmUp,
+    pacing: Pacing,
+    progress: ProgressRate,
+    error_policy: ErrorPolicy,
+    stop_time: Option<u64>,
+    tick: Duration,
+    outgoing_capacity: usize,
 }
 
 impl EmptyEnvironmentSettings {
@@ -24,7 +43,85 @@ impl EmptyEnvironmentSettings {
             agent_count,
             sleep_ms,
             iter_count,
-            max_iter
+            max_iter,
+            seed: DEFAULT_SEED,
+            warm_up: WarmUp::None,
+            pacing: Pacing::EventCount,
+            progress: ProgressRate::Never,
+            error_policy: ErrorPolicy::Abort,
+            stop_time: None,
+            tick: DEFAULT_TICK,
+            outgoing_capacity: DEFAULT_OUTGOING_CAPACITY,
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> EmptyEnvironmentSettings {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_warm_up(mut self, warm_up: WarmUp) -> EmptyEnvironmentSettings {
+        self.warm_up = warm_up;
+        self
+    }
+
+    pub fn with_pacing(mut self, pacing: Pacing) -> EmptyEnvironmentSettings {
+        self.pacing = pacing;
+        self
+    }
+
+    pub fn with_progress(mut self, progress: ProgressRate) -> EmptyEnvironmentSettings {
+        self.progress = progress;
+        self
+    }
+
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> EmptyEnvironmentSettings {
+        self.error_policy = error_policy;
+        self
+    }
+
+    pub fn with_stop_time(mut self, stop_time: u64) -> EmptyEnvironmentSettings {
+        self.stop_time = Some(stop_time);
+        self
+    }
+
+    pub fn with_tick(mut self, tick: Duration) -> EmptyEnvironmentSettings {
+        self.tick = tick;
+        self
+    }
+
+    pub fn with_outgoing_capacity(mut self, outgoing_capacity: usize) -> EmptyEnvironmentSettings {
+        self.outgoing_capacity = outgoing_capacity;
+        self
+    }
+
+    /// Simulation of the same agents and settings, for front-ends that control the run through a
+    /// `SimulationController`.
+    pub fn simulation_builder(&self) -> SimulationBuilder {
+        let agents = infinite_loop_agents(self.agent_count, self.seed);
+        let events: Vec<(Event, u64)> = agents
+            .iter()
+            .map(|agent| (Event::new(agent.id), 0))
+            .collect();
+        let builder = SimulationBuilder::new()
+            .with_agents(
+                agents
+                    .into_iter()
+                    .map(|agent| Box::new(agent) as Box<dyn Agent>),
+            )
+            .with_events(events)
+            .with_seed(self.seed)
+            .with_sleep_every(self.iter_count, self.sleep_ms)
+            .with_max_events(self.max_iter)
+            .with_warm_up(self.warm_up.clone())
+            .with_pacing(self.pacing.clone())
+            .with_progress(self.progress)
+            .with_error_policy(self.error_policy)
+            .with_tick(self.tick)
+            .with_outgoing_capacity(self.outgoing_capacity);
+        match self.stop_time {
+            Some(stop_time) => builder.with_stop_time(stop_time),
+            None => builder,
         }
     }
 }
@@ -41,6 +138,38 @@ impl EnvironmentSettings for EmptyEnvironmentSettings {
     fn get_max_iter(&self) -> u64 {
         self.max_iter
     }
+
+    fn get_seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn get_warm_up(&self) -> WarmUp {
+        self.warm_up.clone()
+    }
+
+    fn get_pacing(&self) -> Pacing {
+        self.pacing.clone()
+    }
+
+    fn get_progress_rate(&self) -> ProgressRate {
+        self.progress
+    }
+
+    fn get_error_policy(&self) -> ErrorPolicy {
+        self.error_policy
+    }
+
+    fn get_stop_time(&self) -> Option<u64> {
+        self.stop_time
+    }
+
+    fn get_tick(&self) -> Duration {
+        self.tick
+    }
+
+    fn get_outgoing_capacity(&self) -> usize {
+        self.outgoing_capacity
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -64,6 +193,22 @@ impl Agent for InfiniteLoopAgent {
     fn get_id(&self) -> Uuid {
         self.id
     }
+
+    fn introspect(&self) -> Option<&dyn Introspect> {
+        Some(self)
+    }
+}
+
+impl Introspect for InfiniteLoopAgent {
+    fn type_name(&self) -> &str {
+        "InfiniteLoopAgent"
+    }
+
+    fn properties(&self) -> BTreeMap<String, Value> {
+        [("counter".to_string(), Value::from(self.counter))]
+            .into_iter()
+            .collect()
+    }
 }
 
 impl InfiniteLoopAgent {
@@ -75,6 +220,16 @@ impl InfiniteLoopAgent {
     }
 }
 
+fn infinite_loop_agents(count: usize, seed: u64) -> Vec<InfiniteLoopAgent> {
+    let mut ids = simulation_rng(seed, AGENT_ID_STREAM);
+    (0..count)
+        .map(|_| InfiniteLoopAgent::new(simulation_uuid(&mut ids)))
+        .collect()
+}
+
+/// `InfiniteLoopAgent`s driven through `AgentEnvironment`, the interface of the WASM port, the CLI
+/// and `experiment::run_to_completion`. Its agents stay typed, see `get_agents`.
+/// `EmptyEnvironmentSettings::simulation_builder` runs the same agents as a `Simulation`.
 pub struct InfiniteEmptyEnvironment<LogFunction, SleepFunction, SleepFuture>
 where
     LogFunction: FnMut(&str),
@@ -83,9 +238,16 @@ where
 {
     log: LogFunction,
     sleep: SleepFunction,
-    sender: Option<Sender<IncomingQueueMessage>>,
-    pub receiver: Option<Receiver<OutgoingQueueMessage>>,
+    sender: Option<IncomingSender>,
+    outgoing: Option<OutgoingStream>,
+    /// Ends of the channels opened by `new`, used by the first run.
+    receiver: Option<IncomingReceiver>,
+    out_sender: Option<OutgoingSender>,
     agents: Vec<InfiniteLoopAgent>,
+    statistics: Statistics,
+    trace: Option<TraceRecorder>,
+    replay: Option<TraceReplay>,
+    observers: Vec<Box<dyn Observer>>,
 }
 
 impl<LogFunction, SleepFunction, SleepFut> AgentEnvironment for InfiniteEmptyEnvironment<LogFunction, SleepFunction, SleepFut>where
@@ -100,12 +262,20 @@ impl<LogFunction, SleepFunction, SleepFut> AgentEnvironment for InfiniteEmptyEnv
 
     fn new(mut log: LogFunction, sleep: SleepFunction) -> Self {
         log("Creating new environment");
+        let (sender, receiver) = incoming_channel();
+        let (out_sender, outgoing) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
         Self {
             log,
             sleep,
-            sender: None,
-            receiver: None,
+            sender: Some(sender),
+            outgoing: Some(outgoing),
+            receiver: Some(receiver),
+            out_sender: Some(out_sender),
             agents: vec![],
+            statistics: Statistics::new(),
+            trace: None,
+            replay: None,
+            observers: vec![],
         }
     }
 
@@ -113,20 +283,29 @@ impl<LogFunction, SleepFunction, SleepFut> AgentEnvironment for InfiniteEmptyEnv
         &mut self,
         settings: EmptyEnvironmentSettings,
     ) -> Pin<Box<dyn Future<Output = Result<(), EventEngineError>> + '_>> {
-        self.agents = (0..settings.agent_count).map(|_|{InfiniteLoopAgent::new(Uuid::new_v4())}).collect();
+        self.agents = infinite_loop_agents(settings.agent_count, settings.get_seed());
         (self.log)("Starting");
-        let (in_sender, in_receiver) = mpsc::channel();
-        self.sender = Some(in_sender);
-        let (out_sender, out_receiver) = mpsc::channel();
-        self.receiver = Some(out_receiver);
+        let (in_receiver, out_sender) = match (self.receiver.take(), self.out_sender.take()) {
+            (Some(in_receiver), Some(out_sender)) => {
+                out_sender.set_capacity(settings.get_outgoing_capacity());
+                (in_receiver, out_sender)
+            }
+            _ => {
+                let (in_sender, in_receiver) = incoming_channel();
+                self.sender = Some(in_sender);
+                let (out_sender, outgoing) = outgoing_channel(settings.get_outgoing_capacity());
+                self.outgoing = Some(outgoing);
+                (in_receiver, out_sender)
+            }
+        };
         let event_vec = self
             .agents
             .iter()
             .map(|agent| (Event::new(agent.id), 0))
             .collect();
         let agent_vec = self.agents.mut_agent_vector();
-        (self.sleep)(Duration::from_millis(100));
-        return Box::pin(crate::event_queue::process_event_queue(
+        let trace = trace_sink(self.trace.as_mut(), self.replay.as_mut());
+        Box::pin(crate::event_queue::process_event_queue(
             agent_vec,
             event_vec,
             in_receiver,
@@ -134,7 +313,10 @@ impl<LogFunction, SleepFunction, SleepFut> AgentEnvironment for InfiniteEmptyEnv
             &mut self.sleep,
             settings,
             out_sender,
-        ));
+            Some(&self.statistics),
+            trace,
+            &mut self.observers,
+        ))
     }
 
     fn halt(&mut self) {
@@ -145,7 +327,7 @@ impl<LogFunction, SleepFunction, SleepFut> AgentEnvironment for InfiniteEmptyEnv
                 .sender
                 .as_ref()
                 .unwrap()
-                .send(IncomingQueueMessage::Halt);
+                .unbounded_send(IncomingQueueMessage::Halt);
             self.sender = None
         }
     }
@@ -158,7 +340,7 @@ impl<LogFunction, SleepFunction, SleepFut> AgentEnvironment for InfiniteEmptyEnv
                 .sender
                 .as_ref()
                 .unwrap()
-                .send(IncomingQueueMessage::ChangeSleepDurationMs(time_ms));
+                .unbounded_send(IncomingQueueMessage::ChangeSleepDurationMs(time_ms));
             self.sender = None
         }
     }
@@ -171,7 +353,7 @@ impl<LogFunction, SleepFunction, SleepFut> AgentEnvironment for InfiniteEmptyEnv
                 .sender
                 .as_ref()
                 .unwrap()
-                .send(IncomingQueueMessage::ChangeSleepIterCount(count));
+                .unbounded_send(IncomingQueueMessage::ChangeSleepIterCount(count));
             self.sender = None
         }
     }
@@ -184,11 +366,40 @@ impl<LogFunction, SleepFunction, SleepFut> AgentEnvironment for InfiniteEmptyEnv
                 .sender
                 .as_ref()
                 .unwrap()
-                .send(IncomingQueueMessage::ChangeMaxIter(count));
+                .unbounded_send(IncomingQueueMessage::ChangeMaxIter(count));
             self.sender = None
         }
     }
 
+    fn change_pacing(&mut self, pacing: Pacing) {
+        if let Some(sender) = &self.sender {
+            (self.log)("Changing pacing");
+            //FIXME: handle error somehow?
+            let _send_result = sender.unbounded_send(IncomingQueueMessage::ChangePacing(pacing));
+        }
+    }
+
+    fn query_agents(&mut self, query: AgentQuery) {
+        if let Some(sender) = &self.sender {
+            (self.log)("Querying agents");
+            //FIXME: handle error somehow?
+            let _send_result = sender.unbounded_send(IncomingQueueMessage::QueryAgents(query));
+        }
+    }
+
+    fn inject_event(&mut self, target: Uuid, at: InjectAt, args: InjectedEventArg) {
+        if let Some(sender) = &self.sender {
+            (self.log)("Injecting event");
+            //FIXME: handle error somehow?
+            let _send_result =
+                sender.unbounded_send(IncomingQueueMessage::InjectEvent { target, at, args });
+        }
+    }
+
+    fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observers.push(observer);
+    }
+
 
 
 }
@@ -200,12 +411,56 @@ where
     SleepFunction: Fn(std::time::Duration) -> SleepFut,
     SleepFut: Future<Output = ()>,
 {
+    /// Messages of the first run, which can be read while it runs, or of the last run. Later runs
+    /// open new channels when they start. Messages are kept until the stream is taken, the run
+    /// fails if the taken stream is dropped while it still sends.
+    pub fn take_outgoing(&mut self) -> Option<OutgoingStream> {
+        self.outgoing.take()
+    }
+
     pub fn get_agents(&self) -> Vec<InfiniteLoopAgent> {
         self.agents.clone()
     }
 
+    /// Matching agents of the last run, use `query_agents` while it runs.
+    pub fn snapshot_agents(&self, query: &AgentQuery) -> Vec<AgentSnapshot> {
+        query.select(
+            self.agents
+                .iter()
+                .map(|agent| (agent.id, agent as &dyn Introspect)),
+        )
+    }
+
+    pub fn get_statistics(&self) -> Statistics {
+        self.statistics.clone()
+    }
+
     pub fn report(&self) -> u64 {
-        self.agents.iter().map(|agent|{agent.counter}).sum()
+        self.agents.iter().map(|agent| agent.counter).sum()
+    }
+}
+
+impl<LogFunction, SleepFunction, SleepFut> TracedEnvironment
+    for InfiniteEmptyEnvironment<LogFunction, SleepFunction, SleepFut>
+where
+    LogFunction: FnMut(&str) + std::marker::Send,
+    SleepFunction: Fn(std::time::Duration) -> SleepFut,
+    SleepFut: Future<Output = ()>,
+{
+    fn set_trace_recorder(&mut self, recorder: TraceRecorder) {
+        self.trace = Some(recorder);
+    }
+
+    fn take_trace_recorder(&mut self) -> Option<TraceRecorder> {
+        self.trace.take()
+    }
+
+    fn set_trace_replay(&mut self, replay: TraceReplay) {
+        self.replay = Some(replay);
+    }
+
+    fn take_trace_replay(&mut self) -> Option<TraceReplay> {
+        self.replay.take()
     }
 }
 
@@ -213,7 +468,11 @@ where
 mod tests {
     use super::*;
     use crate::environment::AgentEnvironment;
-    use futures::pin_mut;
+    use crate::trace::tests::SharedBuffer;
+    use crate::trace::{TraceFormat, TraceReader};
+    use crate::message::OutgoingQueueMessage;
+    use futures::future::Either;
+    use futures::{pin_mut, StreamExt};
     use std::time::Duration;
 
     const ITER_COUNT_SLEEP: u64 = 5000;
@@ -235,6 +494,14 @@ mod tests {
                 sleep_ms: SLEEP_DURATION_MS,
                 iter_count: ITER_COUNT_SLEEP,
                 max_iter: u64::MAX,
+                seed: DEFAULT_SEED,
+                warm_up: WarmUp::None,
+                pacing: Pacing::EventCount,
+                progress: ProgressRate::Never,
+                error_policy: ErrorPolicy::Abort,
+                stop_time: None,
+                tick: DEFAULT_TICK,
+                outgoing_capacity: DEFAULT_OUTGOING_CAPACITY,
             }),
         );
         let result = t.await;
@@ -255,6 +522,14 @@ mod tests {
             sleep_ms: SLEEP_DURATION_MS,
             iter_count: ITER_COUNT_SLEEP,
             max_iter: u64::MAX,
+            seed: DEFAULT_SEED,
+            warm_up: WarmUp::None,
+            pacing: Pacing::EventCount,
+            progress: ProgressRate::Never,
+            error_policy: ErrorPolicy::Abort,
+            stop_time: None,
+            tick: DEFAULT_TICK,
+            outgoing_capacity: DEFAULT_OUTGOING_CAPACITY,
         });
 
         let wait = tokio::time::sleep(Duration::from_secs(1));
@@ -286,10 +561,165 @@ mod tests {
             sleep_ms: SLEEP_DURATION_MS,
             iter_count: ITER_COUNT_SLEEP,
             max_iter: 0,
+            seed: DEFAULT_SEED,
+            warm_up: WarmUp::None,
+            pacing: Pacing::EventCount,
+            progress: ProgressRate::Never,
+            error_policy: ErrorPolicy::Abort,
+            stop_time: None,
+            tick: DEFAULT_TICK,
+            outgoing_capacity: DEFAULT_OUTGOING_CAPACITY,
         }).await;
         assert!(run.is_ok());
         let agents = environment.get_agents();
         assert_eq!(agents.len(), 2);
-        assert_ne!(agents.get(0).unwrap().id, agents.get(1).unwrap().id)
+        assert_ne!(agents.first().unwrap().id, agents.get(1).unwrap().id)
+    }
+
+    #[tokio::test]
+    pub async fn it_runs_without_a_sleep_function() {
+        let mut environment = InfiniteEmptyEnvironment::without_sleep(|_: &str| {});
+        let run = environment
+            .run(
+                EmptyEnvironmentSettings::new(2, SLEEP_DURATION_MS, ITER_COUNT_SLEEP, 20_000)
+                    .with_pacing(Pacing::Yield { every: 100 }),
+            )
+            .await;
+        assert!(run.is_ok());
+        assert_eq!(environment.report(), 20_000);
+    }
+
+    #[tokio::test]
+    pub async fn messages_can_be_read_while_it_runs() {
+        let mut environment = InfiniteEmptyEnvironment::without_sleep(|_: &str| {});
+        let mut outgoing = environment.take_outgoing().unwrap();
+        let run = environment.run(
+            EmptyEnvironmentSettings::new(2, 0, ITER_COUNT_SLEEP, u64::MAX)
+                .with_pacing(Pacing::Yield { every: 100 }),
+        );
+        // The run does not end by itself
+        let finished = futures::future::select(run, outgoing.next()).await;
+        match finished {
+            Either::Left(_) => panic!("The run ended"),
+            Either::Right((message, _)) => {
+                assert!(matches!(message, Some(OutgoingQueueMessage::Started)))
+            }
+        }
+    }
+
+    #[tokio::test]
+    pub async fn agents_can_be_queried_after_a_run() {
+        let mut environment = InfiniteEmptyEnvironment::without_sleep(|_: &str| {});
+        let run = environment
+            .run(EmptyEnvironmentSettings::new(2, 0, ITER_COUNT_SLEEP, 10))
+            .await;
+        assert!(run.is_ok());
+        let snapshots =
+            environment.snapshot_agents(&AgentQuery::TypeName("InfiniteLoopAgent".to_string()));
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(
+            snapshots
+                .iter()
+                .map(|snapshot| snapshot.properties["counter"].as_u64().unwrap())
+                .sum::<u64>(),
+            10
+        );
+        let first = snapshots[0].id;
+        assert_eq!(
+            environment.snapshot_agents(&AgentQuery::Id(first)),
+            vec![snapshots[0].clone()]
+        );
+    }
+
+    #[tokio::test]
+    pub async fn runs_with_the_same_seed_replay_their_trace() {
+        let settings = || EmptyEnvironmentSettings::new(3, 0, ITER_COUNT_SLEEP, 30).with_seed(7);
+        let buffer = SharedBuffer::default();
+
+        let mut recording = InfiniteEmptyEnvironment::without_sleep(|_: &str| {});
+        recording.set_trace_recorder(TraceRecorder::new(buffer.clone(), TraceFormat::JsonLines));
+        assert!(recording.run(settings()).await.is_ok());
+
+        let mut replaying = InfiniteEmptyEnvironment::without_sleep(|_: &str| {});
+        let recorded = buffer.contents();
+        replaying.set_trace_replay(TraceReplay::new(TraceReader::new(
+            std::io::Cursor::new(recorded.clone()),
+            TraceFormat::JsonLines,
+        )));
+        assert!(replaying.run(settings()).await.is_ok());
+        let mut replay = replaying.take_trace_replay().unwrap();
+        assert!(replay.finish().is_ok());
+        assert_eq!(replay.matched(), 30);
+
+        replaying.set_trace_replay(TraceReplay::new(TraceReader::new(
+            std::io::Cursor::new(recorded),
+            TraceFormat::JsonLines,
+        )));
+        let result = replaying.run(settings().with_seed(8)).await;
+        assert!(matches!(result, Err(EventEngineError::TraceDiverged(_))));
+    }
+
+    #[tokio::test]
+    pub async fn observers_see_every_run_event() {
+        #[derive(Default)]
+        struct Counter {
+            spawned: usize,
+            dispatched: u64,
+            finished: Option<crate::observer::FinishReason>,
+        }
+
+        impl Observer for Counter {
+            fn on_agent_spawn(&mut self, _agent: Uuid) {
+                self.spawned += 1;
+            }
+
+            fn after_dispatch(
+                &mut self,
+                _dispatch: &crate::observer::Dispatch,
+                _produced: &[crate::observer::Produced],
+            ) {
+                self.dispatched += 1;
+            }
+
+            fn on_finish(&mut self, reason: crate::observer::FinishReason) {
+                self.finished = Some(reason);
+            }
+        }
+
+        let counter = std::rc::Rc::new(std::cell::RefCell::new(Counter::default()));
+        let mut environment = InfiniteEmptyEnvironment::without_sleep(|_: &str| {});
+        environment.add_observer(Box::new(counter.clone()));
+        let run = environment
+            .run(EmptyEnvironmentSettings::new(4, 0, ITER_COUNT_SLEEP, 100))
+            .await;
+        assert!(run.is_ok());
+        let counter = counter.borrow();
+        assert_eq!(counter.spawned, 4);
+        assert_eq!(counter.dispatched, 100);
+        assert_eq!(
+            counter.finished,
+            Some(crate::observer::FinishReason::MaxIter)
+        );
+    }
+
+    #[tokio::test]
+    pub async fn the_simulation_builder_runs_the_same_agents() {
+        let settings = || {
+            EmptyEnvironmentSettings::new(3, 0, ITER_COUNT_SLEEP, 30)
+                .with_seed(7)
+                .with_stop_time(5)
+                .with_tick(Duration::from_secs(60))
+        };
+        let mut environment = InfiniteEmptyEnvironment::without_sleep(|_: &str| {});
+        assert!(environment.run(settings()).await.is_ok());
+
+        let mut simulation = settings().simulation_builder().build().unwrap();
+        assert_eq!(simulation.get_settings().stop_time, Some(5));
+        assert_eq!(simulation.get_settings().tick, Duration::from_secs(60));
+        assert!(simulation.run().await.is_ok());
+        assert_eq!(
+            simulation.snapshot_agents(&AgentQuery::All),
+            environment.snapshot_agents(&AgentQuery::All)
+        );
     }
 }
@@ -1,10 +1,23 @@
-use crate::event_queue::EventEngineError;
+use crate::channel::DEFAULT_OUTGOING_CAPACITY;
+use crate::error::ErrorPolicy;
+use crate::error::EventEngineError;
+use crate::event::InjectedEventArg;
+use crate::introspection::AgentQuery;
+use crate::message::{InjectAt, ProgressRate};
+use crate::observer::Observer;
+use crate::pacing::{no_sleep, NoSleepFunction, Pacing, YieldNow};
+use crate::rng::DEFAULT_SEED;
+use crate::statistics::WarmUp;
+use crate::trace::{TraceRecorder, TraceReplay};
 use std::future::Future;
 use std::pin::Pin;
+use std::time::Duration;
+use uuid::Uuid;
 
 pub const DEFAULT_ITER_COUNT_SLEEP: u64 = 5000;
 pub const DEFAULT_SLEEP_DURATION_MS: u64 = 100;
 pub const DEFAULT_MAX_ITER: u64 = u64::MAX;
+pub const DEFAULT_TICK: Duration = Duration::from_secs(1);
 
 pub trait EnvironmentSettings {
     fn get_iter_count(&self) -> u64 {
@@ -16,10 +29,42 @@ pub trait EnvironmentSettings {
     fn get_max_iter(&self) -> u64 {
         DEFAULT_MAX_ITER
     }
+    /// Simulated time after which no event is dispatched, `None` to run until another stop
+    /// condition holds.
+    fn get_stop_time(&self) -> Option<u64> {
+        None
+    }
+    fn get_seed(&self) -> u64 {
+        DEFAULT_SEED
+    }
+    fn get_warm_up(&self) -> WarmUp {
+        WarmUp::None
+    }
+    fn get_pacing(&self) -> Pacing {
+        Pacing::EventCount
+    }
+    /// Simulated length of one tick, see `SimulationCalendar::get_tick`. The wall clock pacing
+    /// scales simulated seconds, not ticks.
+    fn get_tick(&self) -> Duration {
+        DEFAULT_TICK
+    }
+    fn get_progress_rate(&self) -> ProgressRate {
+        ProgressRate::Never
+    }
+    fn get_error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::Abort
+    }
+    /// Queued outgoing messages before progress messages are coalesced, see `outgoing_channel`.
+    fn get_outgoing_capacity(&self) -> usize {
+        DEFAULT_OUTGOING_CAPACITY
+    }
 }
 
 pub trait AgentEnvironment
 {
+    /// Receives the messages of the environment itself and the errors of
+    /// `ErrorPolicy::LogAndContinue`. Records of the engine only go through `tracing`, forward them
+    /// with `LogCallbackLayer`.
     type LogFunction: FnMut(&str) + std::marker::Send;
     //FIXME:    I'm 99% sure that it is possible to express sleep function with only one type
     //          However when I half-heartedly tried to eliminate type SleepFuture compiler got angry
@@ -30,6 +75,14 @@ pub trait AgentEnvironment
 
     fn new(log: Self::LogFunction, sleep: Self::SleepFunction) -> Self;
 
+    /// Environment that never waits for real time, sleeps of the pacing become yields.
+    fn without_sleep(log: Self::LogFunction) -> Self
+    where
+        Self: AgentEnvironment<SleepFunction = NoSleepFunction, SleepFuture = YieldNow> + Sized,
+    {
+        Self::new(log, no_sleep)
+    }
+
     fn run(
         &mut self,
         settings: Self::TEnvironmentSettings,
@@ -42,4 +95,31 @@ pub trait AgentEnvironment
     fn change_sleep_iter_count(&mut self, count: u64);
 
     fn change_max_iter_count(&mut self, count: u64);
+
+    fn change_pacing(&mut self, pacing: Pacing);
+
+    /// Asks the running engine for the matching agents, the answer is sent as
+    /// `OutgoingQueueMessage::Agents`.
+    fn query_agents(&mut self, query: AgentQuery);
+
+    /// Schedules an event for `target` in the running engine. A rejected event is reported as
+    /// `OutgoingQueueMessage::EventRejected`.
+    fn inject_event(&mut self, target: Uuid, at: InjectAt, args: InjectedEventArg);
+
+    /// Every following run reports to `observer`, in the order observers were added.
+    fn add_observer(&mut self, observer: Box<dyn Observer>);
+}
+
+/// Environment whose runs can be recorded to a trace or checked against one.
+pub trait TracedEnvironment: AgentEnvironment {
+    /// Every following run writes its dispatched events to `recorder`.
+    fn set_trace_recorder(&mut self, recorder: TraceRecorder);
+
+    fn take_trace_recorder(&mut self) -> Option<TraceRecorder>;
+
+    /// Every following run is checked against `replay` and stops at the first divergence.
+    /// A trace recorder takes precedence if both are set.
+    fn set_trace_replay(&mut self, replay: TraceReplay);
+
+    fn take_trace_replay(&mut self) -> Option<TraceReplay>;
 }
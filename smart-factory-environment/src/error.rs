@@ -0,0 +1,344 @@
+use crate::channel::SendError;
+use crate::scenario::ValidationError;
+use crate::trace::{Divergence, TraceError};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use uuid::Uuid;
+
+/// Error returned by `Agent::try_handle`. Any error type converts into it with `?`,
+/// plain messages with `.into()`.
+pub type AgentError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug)]
+pub enum EventEngineError {
+    EventHasNoAgent {
+        event: Uuid,
+        agent: Uuid,
+        time: u64,
+    },
+    /// The handler of `agent` returned an error.
+    AgentFailed {
+        event: Uuid,
+        agent: Uuid,
+        time: u64,
+        error: AgentError,
+    },
+    /// The handler of `agent` panicked. Only reported on native targets, see
+    /// `AgentSlot::handle_isolated`.
+    AgentPanicked {
+        event: Uuid,
+        agent: Uuid,
+        time: u64,
+        message: String,
+    },
+    CouldNotCommunicate(SendError),
+    CouldNotRecord(std::io::Error),
+    CouldNotReplay(TraceError),
+    TraceDiverged(Box<Divergence>),
+    /// A `Simulation` runs only once.
+    AlreadyRun,
+    /// An environment that builds a `Simulation` for every run was set up wrongly, see
+    /// `SimulationBuilder::validate`.
+    InvalidSimulation(Vec<ValidationError>),
+}
+
+impl Display for EventEngineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventEngineError::EventHasNoAgent { event, agent, time } => write!(
+                f,
+                "event {} at time {} targets unknown agent {}",
+                event, time, agent
+            ),
+            EventEngineError::AgentFailed {
+                event,
+                agent,
+                time,
+                error,
+            } => write!(
+                f,
+                "agent {} failed to handle event {} at time {}: {}",
+                agent, event, time, error
+            ),
+            EventEngineError::AgentPanicked {
+                event,
+                agent,
+                time,
+                message,
+            } => write!(
+                f,
+                "agent {} panicked while handling event {} at time {}: {}",
+                agent, event, time, message
+            ),
+            EventEngineError::CouldNotCommunicate(_) => {
+                write!(f, "could not send a message, the receiver is gone")
+            }
+            EventEngineError::CouldNotRecord(error) => {
+                write!(f, "could not record the trace: {}", error)
+            }
+            EventEngineError::CouldNotReplay(error) => {
+                write!(f, "could not replay the trace: {}", error)
+            }
+            EventEngineError::TraceDiverged(divergence) => write!(f, "{}", divergence),
+            EventEngineError::AlreadyRun => write!(f, "the simulation already ran"),
+            EventEngineError::InvalidSimulation(errors) => {
+                let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+                write!(f, "invalid simulation: {}", errors.join(", "))
+            }
+        }
+    }
+}
+
+impl Error for EventEngineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EventEngineError::AgentFailed { error, .. } => Some(error.as_ref()),
+            EventEngineError::CouldNotCommunicate(error) => Some(error),
+            EventEngineError::CouldNotRecord(error) => Some(error),
+            EventEngineError::CouldNotReplay(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<SendError> for EventEngineError {
+    fn from(err: SendError) -> Self {
+        EventEngineError::CouldNotCommunicate(err)
+    }
+}
+
+/// Why an injected event was not scheduled, or a setting sent during the run was not applied.
+/// The run continues either way.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InjectionError {
+    UnknownAgent(Uuid),
+    InThePast {
+        time: u64,
+        now: u64,
+    },
+    /// `IncomingQueueMessage::ChangeSleepIterCount(0)`; the engine keeps sleeping every `kept`
+    /// iterations.
+    ZeroSleepIterCount {
+        kept: u64,
+    },
+}
+
+impl Display for InjectionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InjectionError::UnknownAgent(agent) => {
+                write!(f, "injected event targets unknown agent {}", agent)
+            }
+            InjectionError::InThePast { time, now } => write!(
+                f,
+                "injected event at time {} lies before the current time {}",
+                time, now
+            ),
+            InjectionError::ZeroSleepIterCount { kept } => {
+                write!(f, "sleep iter count must be at least 1, keeping {}", kept)
+            }
+        }
+    }
+}
+
+impl Error for InjectionError {}
+
+/// What the engine does when an event can not be handled, i.e. it has no agent or the handler
+/// failed or panicked. Communication and trace errors always stop the run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPolicy {
+    /// Stops the run with the error.
+    #[default]
+    Abort,
+    /// Drops the event.
+    SkipEvent,
+    /// Drops the event and removes the agent from the run, later events to it are dropped too.
+    RetireAgent,
+    /// Drops the event and reports the error to the log function.
+    LogAndContinue,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, AgentSlot, AsyncAgent, NewEventsVec};
+    use crate::environment::EnvironmentSettings;
+    use crate::event::{Event, EventArg};
+    use crate::event_queue::tests::run_engine;
+
+    const FLAKY: Uuid = Uuid::from_u128(1);
+
+    struct FlakyAgent {
+        handled: Vec<u64>,
+        panics: bool,
+    }
+
+    impl Agent for FlakyAgent {
+        fn handle(&mut self, time: u64, args: EventArg) -> NewEventsVec {
+            self.try_handle(time, args).unwrap_or_default()
+        }
+
+        fn try_handle(&mut self, time: u64, _args: EventArg) -> Result<NewEventsVec, AgentError> {
+            if time == 2 && self.panics {
+                panic!("broken at 2");
+            }
+            if time == 2 {
+                return Err("broken at 2".into());
+            }
+            self.handled.push(time);
+            Ok(vec![])
+        }
+
+        fn get_id(&self) -> Uuid {
+            FLAKY
+        }
+    }
+
+    struct AsyncFlakyAgent(FlakyAgent);
+
+    #[async_trait::async_trait(?Send)]
+    impl AsyncAgent for AsyncFlakyAgent {
+        async fn handle(&mut self, time: u64, args: EventArg) -> NewEventsVec {
+            self.0.handle(time, args)
+        }
+
+        async fn try_handle(
+            &mut self,
+            time: u64,
+            args: EventArg,
+        ) -> Result<NewEventsVec, AgentError> {
+            self.0.try_handle(time, args)
+        }
+
+        fn get_id(&self) -> Uuid {
+            FLAKY
+        }
+    }
+
+    struct PolicySettings {
+        policy: ErrorPolicy,
+    }
+
+    impl EnvironmentSettings for PolicySettings {
+        fn get_error_policy(&self) -> ErrorPolicy {
+            self.policy
+        }
+    }
+
+    fn run(policy: ErrorPolicy) -> (Result<(), EventEngineError>, Vec<u64>, Vec<String>) {
+        run_agent(policy, false)
+    }
+
+    fn run_agent(
+        policy: ErrorPolicy,
+        panics: bool,
+    ) -> (Result<(), EventEngineError>, Vec<u64>, Vec<String>) {
+        let mut agent = FlakyAgent {
+            handled: vec![],
+            panics,
+        };
+        let mut init_state: Vec<(Event, u64)> =
+            (1..=4).map(|time| (Event::new(FLAKY), time)).collect();
+        init_state.push((Event::new(Uuid::from_u128(2)), 5));
+        let (result, logged) = run_engine(
+            Agent::solo_vec(&mut agent),
+            init_state,
+            PolicySettings { policy },
+            None,
+            &mut [],
+        );
+        (result, agent.handled, logged)
+    }
+
+    #[test]
+    fn abort_stops_with_the_failing_event() {
+        let (result, handled, _) = run(ErrorPolicy::Abort);
+        assert_eq!(handled, vec![1]);
+        let error = result.unwrap_err();
+        assert!(matches!(
+            error,
+            EventEngineError::AgentFailed {
+                agent: FLAKY,
+                time: 2,
+                ..
+            }
+        ));
+        assert!(error.to_string().ends_with("at time 2: broken at 2"));
+        assert_eq!(error.source().unwrap().to_string(), "broken at 2");
+    }
+
+    #[test]
+    fn skip_event_continues_with_the_next_event() {
+        let (result, handled, logged) = run(ErrorPolicy::SkipEvent);
+        assert!(result.is_ok());
+        assert_eq!(handled, vec![1, 3, 4]);
+        assert!(logged.is_empty());
+    }
+
+    #[test]
+    fn retire_agent_drops_its_later_events() {
+        let (result, handled, _) = run(ErrorPolicy::RetireAgent);
+        assert!(result.is_ok());
+        assert_eq!(handled, vec![1]);
+    }
+
+    #[test]
+    fn log_and_continue_reports_every_error() {
+        let (result, handled, logged) = run(ErrorPolicy::LogAndContinue);
+        assert!(result.is_ok());
+        assert_eq!(handled, vec![1, 3, 4]);
+        assert_eq!(logged.len(), 2);
+        assert!(logged[0].contains("failed to handle"));
+        assert!(logged[1].contains("targets unknown agent"));
+    }
+
+    #[test]
+    fn async_agents_fail_like_sync_agents() {
+        let mut agent = AsyncFlakyAgent(FlakyAgent {
+            handled: vec![],
+            panics: false,
+        });
+        let init_state = (1..=4).map(|time| (Event::new(FLAKY), time)).collect();
+        let (result, _) = run_engine(
+            vec![AgentSlot::Async(&mut agent)],
+            init_state,
+            PolicySettings {
+                policy: ErrorPolicy::SkipEvent,
+            },
+            None,
+            &mut [],
+        );
+        assert!(result.is_ok());
+        assert_eq!(agent.0.handled, vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn panics_are_reported_with_the_agent_and_event() {
+        let (result, handled, _) = run_agent(ErrorPolicy::Abort, true);
+        assert_eq!(handled, vec![1]);
+        let error = result.unwrap_err();
+        assert!(matches!(
+            &error,
+            EventEngineError::AgentPanicked {
+                agent: FLAKY,
+                time: 2,
+                message,
+                ..
+            } if message == "broken at 2"
+        ));
+        assert!(error.to_string().contains("panicked while handling event"));
+    }
+
+    #[test]
+    fn panics_follow_the_error_policy() {
+        let (result, handled, _) = run_agent(ErrorPolicy::SkipEvent, true);
+        assert!(result.is_ok());
+        assert_eq!(handled, vec![1, 3, 4]);
+
+        let (result, handled, _) = run_agent(ErrorPolicy::RetireAgent, true);
+        assert!(result.is_ok());
+        assert_eq!(handled, vec![1]);
+    }
+}
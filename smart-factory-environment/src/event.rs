@@ -3,9 +3,15 @@ use uuid::Uuid;
 
 pub type EventArg = Option<Box<dyn EventArgs>>;
 
+/// Arguments of an injected event. They are `Send` so that events can be injected through a
+/// `SimulationController` held by another thread than the one running the simulation.
+pub type InjectedEventArg = Option<Box<dyn EventArgs + Send>>;
+
 pub struct Event {
     id: Uuid,
     pub agent: Uuid,
+    /// Agent whose handler scheduled the event, filled in by the engine. `None` for initial events.
+    pub sender: Option<Uuid>,
     pub args: EventArg,
 }
 
@@ -14,6 +20,7 @@ impl Event {
         Self {
             id: Uuid::new_v4(),
             agent,
+            sender: None,
             args: None,
         }
     }
@@ -22,9 +29,14 @@ impl Event {
         Self {
             id: Uuid::new_v4(),
             agent,
+            sender: None,
             args: Some(args),
         }
     }
+
+    pub(crate) fn id(&self) -> Uuid {
+        self.id
+    }
 }
 
 impl Hash for Event {
@@ -43,4 +55,14 @@ impl Eq for Event {}
 
 pub trait EventArgs {
     fn as_any(&self) -> &dyn std::any::Any;
+
+    /// Name of the payload type written to traces.
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Payload written to traces, `None` if the arguments can not be serialized.
+    fn to_json(&self) -> Option<serde_json::Value> {
+        None
+    }
 }
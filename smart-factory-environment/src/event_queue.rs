@@ -1,102 +1,463 @@
-use crate::agent::Agent;
+use crate::agent::{AgentSlot, HandleError};
+use crate::channel::{IncomingReceiver, OutgoingSender};
 use crate::environment::EnvironmentSettings;
-use crate::event::Event;
-use crate::message::{IncomingQueueMessage, OutgoingQueueMessage};
+use crate::error::{ErrorPolicy, EventEngineError, InjectionError};
+use crate::event::{Event, EventArgs};
+use crate::message::{IncomingQueueMessage, InjectAt, OutgoingQueueMessage, ProgressTracker};
+use crate::observer::{Dispatch, FinishReason, Observer, Produced};
+use crate::pacing::{Pacer, Pause};
+use crate::statistics::Statistics;
+use crate::trace::{ScheduledEvent, TraceRecord, TraceSink};
+use futures::future::{select, Either};
+use futures::StreamExt;
 use priority_queue::PriorityQueue;
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
-use std::sync::mpsc::{Receiver, Sender, SendError};
+use std::pin::pin;
 use std::time::Duration;
 use uuid::Uuid;
+use web_time::Instant;
 
-impl From<SendError<OutgoingQueueMessage>> for EventEngineError {
-    fn from(err: SendError<OutgoingQueueMessage>) -> Self {
-        EventEngineError::CouldNotCommunicate(err)
-    }
-}
-
-#[derive(Debug)]
-pub enum EventEngineError {
-    EventHasNoAgent,
-    CouldNotCommunicate(SendError<OutgoingQueueMessage>)
-}
-
-pub async fn process_event_queue<LogFunction, SleepFunction, SleepFut, Settings>(
-    mut agents: Vec<&mut dyn Agent>,
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(name = "run", skip_all, fields(seed = settings.get_seed()))]
+pub async fn process_event_queue<'a, Agents, LogFunction, SleepFunction, SleepFut, Settings>(
+    agents: Agents,
     init_state: Vec<(Event, u64)>,
-    receiver: Receiver<IncomingQueueMessage>,
+    mut receiver: IncomingReceiver,
     log: &mut LogFunction,
     sleep: &mut SleepFunction,
     settings: Settings,
-    sender: Sender<OutgoingQueueMessage>,
+    sender: OutgoingSender,
+    statistics: Option<&Statistics>,
+    mut trace: Option<&mut dyn TraceSink>,
+    observers: &mut [Box<dyn Observer>],
 ) -> Result<(), EventEngineError>
 where
+    Agents: IntoIterator,
+    Agents::Item: Into<AgentSlot<'a>>,
     LogFunction: FnMut(&str),
     SleepFunction: Fn(Duration) -> SleepFut,
     SleepFut: Future<Output = ()>,
     Settings: EnvironmentSettings,
 {
+    // Earliest event has to be dispatched first
     let mut queue = PriorityQueue::new();
-    queue.extend(init_state);
-    let mut agents: HashMap<Uuid, &mut &mut dyn Agent> = agents
-        .iter_mut()
-        .map(|agent| (agent.get_id(), agent))
+    queue.extend(
+        init_state
+            .into_iter()
+            .map(|(event, time)| (event, Reverse(time))),
+    );
+    let mut agents: HashMap<Uuid, AgentSlot> = agents
+        .into_iter()
+        .map(|agent| {
+            let agent = agent.into();
+            (agent.get_id(), agent)
+        })
         .collect();
     let mut i = 0;
     let mut sleep_duration = Duration::from_millis(settings.get_sleep_ms());
     let mut max_iter_count = settings.get_max_iter();
+    let stop_time = settings.get_stop_time();
     let mut iter_count_sleep = settings.get_iter_count();
-
+    let mut pacer = Pacer::new(settings.get_pacing(), settings.get_tick());
+    let mut progress = ProgressTracker::new(settings.get_progress_rate());
+    let error_policy = settings.get_error_policy();
+    let mut retired = HashSet::new();
+    let mut now: u64 = 0;
+    // Injected since the last traced event
+    let mut injected = vec![];
+    if let Some(statistics) = statistics {
+        statistics.configure_warm_up(settings.get_warm_up());
+    }
 
     sender.send(OutgoingQueueMessage::Started)?;
-    loop {
-        if let Ok(message) = receiver.try_recv() {
+    tracing::info!(
+        agent_count = agents.len(),
+        max_iter = max_iter_count,
+        "Started"
+    );
+    for observer in observers.iter_mut() {
+        observer.on_start();
+        for agent in agents.keys() {
+            observer.on_agent_spawn(*agent);
+        }
+    }
+    // Message that ended a sleep early, and the rest of a pacing sleep that is taken once pending
+    // messages are handled
+    let mut received = None;
+    let mut pause_left = None;
+    let result = 'run: loop {
+        while let Some(message) = received.take().or_else(|| receiver.try_recv().ok()) {
             match message {
-                IncomingQueueMessage::Halt => return Ok(()),
-                IncomingQueueMessage::ChangeSleepIterCount(count) => iter_count_sleep = count,
+                IncomingQueueMessage::Halt => break 'run Ok(FinishReason::Halted),
+                IncomingQueueMessage::ChangeSleepIterCount(0) => {
+                    let error = InjectionError::ZeroSleepIterCount {
+                        kept: iter_count_sleep,
+                    };
+                    tracing::warn!("{}", error);
+                    if let Err(error) = sender.send(OutgoingQueueMessage::EventRejected(error)) {
+                        break 'run Err(error.into());
+                    }
+                }
+                IncomingQueueMessage::ChangeSleepIterCount(count) => {
+                    tracing::debug!(count, "Changed sleep iter count");
+                    iter_count_sleep = count
+                }
                 IncomingQueueMessage::ChangeSleepDurationMs(sleep_ms) => {
+                    tracing::debug!(sleep_ms, "Changed sleep duration");
                     sleep_duration = Duration::from_millis(sleep_ms)
                 }
-                IncomingQueueMessage::ChangeMaxIter(count) => max_iter_count = count,
+                IncomingQueueMessage::ChangeMaxIter(count) => {
+                    tracing::debug!(count, "Changed max iter count");
+                    max_iter_count = count
+                }
+                IncomingQueueMessage::ChangePacing(pacing) => {
+                    tracing::debug!(pacing = ?pacing, "Changed pacing");
+                    pacer.set_pacing(pacing)
+                }
+                IncomingQueueMessage::QueryAgents(query) => {
+                    let snapshots =
+                        query.select(agents.iter().filter_map(|(id, agent)| {
+                            agent.introspect().map(|agent| (*id, agent))
+                        }));
+                    tracing::debug!(matched = snapshots.len(), "Answered agent query");
+                    if let Err(error) = sender.send(OutgoingQueueMessage::Agents(snapshots)) {
+                        break 'run Err(error.into());
+                    }
+                }
+                IncomingQueueMessage::InjectEvent { target, at, args } => {
+                    let time = match at {
+                        InjectAt::Time(time) => time,
+                        InjectAt::Delay(delay) => now.saturating_add(delay),
+                    };
+                    let rejection = if !agents.contains_key(&target) {
+                        Some(InjectionError::UnknownAgent(target))
+                    } else if time < now {
+                        Some(InjectionError::InThePast { time, now })
+                    } else {
+                        None
+                    };
+                    if let Some(error) = rejection {
+                        tracing::warn!("{}", error);
+                        if let Err(error) = sender.send(OutgoingQueueMessage::EventRejected(error))
+                        {
+                            break 'run Err(error.into());
+                        }
+                    } else {
+                        tracing::debug!(agent = %target, time, "Injected event");
+                        let mut event = Event::new(target);
+                        event.args = args.map(|args| args as Box<dyn EventArgs>);
+                        if trace.is_some() {
+                            injected.push(ScheduledEvent {
+                                target,
+                                time,
+                                payload_type: event
+                                    .args
+                                    .as_ref()
+                                    .map(|args| args.type_name().to_string()),
+                            });
+                        }
+                        queue.push(event, Reverse(time));
+                    }
+                }
+            }
+        }
+        if let Some(left) = pause_left.take() {
+            match sleep_or_receive(&*sleep, left, &mut receiver).await {
+                Some((message, left)) => {
+                    received = Some(message);
+                    pause_left = Some(left);
+                }
+                None => pacer.resume(),
             }
+            continue;
         }
 
         if i >= max_iter_count {
-            return Ok(());
+            break Ok(FinishReason::MaxIter);
         }
 
-        let item = queue.pop();
-        if item.is_none() {
-            return Ok(());
+        let time = match queue.peek() {
+            Some((_, Reverse(time))) => *time,
+            None => break Ok(FinishReason::QueueEmpty),
+        };
+        if stop_time.is_some_and(|stop_time| time > stop_time) {
+            break Ok(FinishReason::StopTime);
         }
-        let (event, time) = item.unwrap();
-        let agent = agents.get_mut(&event.agent);
-        if agent.is_none() {
-            return Err(EventEngineError::EventHasNoAgent);
+        // The event stays queued while waiting, so that messages are handled in between and
+        // injected events that are due earlier go first.
+        if let Some(delay) = pacer.delay_before(time) {
+            for observer in observers.iter_mut() {
+                observer.on_pause(i, Pause::Sleep(delay));
+            }
+            if progress.is_enabled() {
+                if let Err(error) = sender.send(OutgoingQueueMessage::Paused(delay)) {
+                    break Err(error.into());
+                }
+            }
+            // The delay is computed again once the message is handled
+            received = sleep_or_receive(&*sleep, delay, &mut receiver)
+                .await
+                .map(|(message, _)| message);
+            continue;
+        }
+        let (event, _) = queue.pop().expect("Queue has the peeked event");
+        now = time;
+        tracing::trace!(
+            sequence = i,
+            time,
+            agent = %event.agent,
+            event = %event.id(),
+            "Dispatching event"
+        );
+        let target = event.agent;
+        let failure = match agents.get_mut(&target) {
+            None if retired.contains(&target) => {
+                tracing::trace!(agent = %target, "Dropped event of retired agent");
+                continue;
+            }
+            None => Some(EventEngineError::EventHasNoAgent {
+                event: event.id(),
+                agent: target,
+                time,
+            }),
+            Some(agent) => {
+                if let Some(statistics) = statistics {
+                    statistics.advance(time);
+                }
+                let event_id = event.id();
+                let dispatch = Dispatch {
+                    sequence: i,
+                    time,
+                    sender: event.sender,
+                    target,
+                };
+                for observer in observers.iter_mut() {
+                    observer.before_dispatch(&dispatch, event.args.as_deref());
+                }
+                let mut record = trace.as_ref().map(|_| TraceRecord {
+                    sequence: i,
+                    time,
+                    sender: event.sender,
+                    target,
+                    payload_type: event.args.as_ref().map(|args| args.type_name().to_string()),
+                    payload: event.args.as_ref().and_then(|args| args.to_json()),
+                    scheduled: vec![],
+                    injected: std::mem::take(&mut injected),
+                });
+                match agent.handle_isolated(time, event.args).await {
+                    Err(HandleError::Failed(error)) => Some(EventEngineError::AgentFailed {
+                        event: event_id,
+                        agent: target,
+                        time,
+                        error,
+                    }),
+                    Err(HandleError::Panicked(message)) => Some(EventEngineError::AgentPanicked {
+                        event: event_id,
+                        agent: target,
+                        time,
+                        message,
+                    }),
+                    Ok(mut new_events) => {
+                        for (new_event, time) in new_events.iter_mut() {
+                            new_event.sender = Some(target);
+                            if let Some(record) = record.as_mut() {
+                                record.scheduled.push(ScheduledEvent {
+                                    target: new_event.agent,
+                                    time: *time,
+                                    payload_type: new_event
+                                        .args
+                                        .as_ref()
+                                        .map(|args| args.type_name().to_string()),
+                                });
+                            }
+                        }
+                        if !observers.is_empty() {
+                            let produced: Vec<Produced> = new_events
+                                .iter()
+                                .map(|(new_event, time)| Produced {
+                                    target: new_event.agent,
+                                    time: *time,
+                                    args: new_event.args.as_deref(),
+                                })
+                                .collect();
+                            for observer in observers.iter_mut() {
+                                observer.after_dispatch(&dispatch, &produced);
+                            }
+                        }
+                        queue.extend(
+                            new_events
+                                .into_iter()
+                                .map(|(new_event, time)| (new_event, Reverse(time))),
+                        );
+                        if let (Some(trace), Some(record)) = (trace.as_mut(), record) {
+                            if let Err(error) = trace.record(&record) {
+                                break Err(error);
+                            }
+                        }
+                        None
+                    }
+                }
+            }
+        };
+        if let Some(error) = failure {
+            // Errors reported to the log function are not traced a second time
+            match error_policy {
+                ErrorPolicy::Abort => tracing::error!("{}", error),
+                ErrorPolicy::LogAndContinue => {}
+                _ => tracing::warn!(policy = ?error_policy, "{}", error),
+            }
+            match error_policy {
+                ErrorPolicy::Abort => break Err(error),
+                ErrorPolicy::SkipEvent => {}
+                ErrorPolicy::LogAndContinue => (log)(&error.to_string()),
+                ErrorPolicy::RetireAgent => {
+                    if agents.remove(&target).is_some() {
+                        retired.insert(target);
+                        for observer in observers.iter_mut() {
+                            observer.on_agent_retire(target);
+                        }
+                    }
+                }
+            }
+            for observer in observers.iter_mut() {
+                observer.on_error(&error);
+            }
         }
-        let new_events = agent.unwrap().handle(time, event.args);
-        queue.extend(new_events);
 
         i += 1;
 
         if i % iter_count_sleep == 0 {
-            sender.send(OutgoingQueueMessage::Iter(i))?;
-            (log)("Entered sleep");
-            (sleep)(sleep_duration).await;
+            if let Err(error) = sender.send(OutgoingQueueMessage::Iter(i)) {
+                break Err(error.into());
+            }
+        }
+        if progress.is_due(i) {
+            let report = progress.report(
+                time,
+                i,
+                queue.len(),
+                agents.iter().filter_map(|(id, agent)| {
+                    agent.introspect().map(|agent| (*id, agent.properties()))
+                }),
+            );
+            if let Err(error) = sender.send(OutgoingQueueMessage::Progress(report)) {
+                break Err(error.into());
+            }
+        }
+        let pause = pacer.pause_after(i, iter_count_sleep, sleep_duration);
+        if let Some(pause) = pause {
+            for observer in observers.iter_mut() {
+                observer.on_pause(i, pause);
+            }
+        }
+        match pause {
+            Some(Pause::Sleep(duration)) => {
+                if progress.is_enabled() {
+                    if let Err(error) = sender.send(OutgoingQueueMessage::Paused(duration)) {
+                        break Err(error.into());
+                    }
+                }
+                tracing::debug!(
+                    processed = i,
+                    duration_ms = duration.as_millis() as u64,
+                    "Entered sleep"
+                );
+                pause_left = Some(duration);
+            }
+            Some(Pause::Yield) => (sleep)(Duration::ZERO).await,
+            None => {}
+        }
+    };
+
+    if let Some(statistics) = statistics {
+        statistics.finish_warm_up();
+    }
+    let reason = *result.as_ref().unwrap_or(&FinishReason::Failed);
+    match &result {
+        Ok(reason) => tracing::info!(reason = ?reason, events = i, "Finished"),
+        Err(error) => tracing::error!(error = %error, events = i, "Failed"),
+    }
+    // The run is over, a receiver that is gone by now does not matter
+    if progress.is_enabled() {
+        let report = progress.report(
+            now,
+            i,
+            queue.len(),
+            agents.iter().filter_map(|(id, agent)| {
+                agent.introspect().map(|agent| (*id, agent.properties()))
+            }),
+        );
+        let _ = sender.send(OutgoingQueueMessage::Progress(report));
+    }
+    let _ = sender.send(match &result {
+        Ok(reason) => OutgoingQueueMessage::Finished(*reason),
+        Err(error) => OutgoingQueueMessage::Error(error.to_string()),
+    });
+    for observer in observers.iter_mut() {
+        for agent in agents.keys() {
+            observer.on_agent_retire(*agent);
+        }
+        observer.on_finish(reason);
+    }
+    result.map(|_| ())
+}
+
+/// Sleeps for `duration` unless a message arrives first. Returns the message and the part of the
+/// sleep that is left then.
+async fn sleep_or_receive<SleepFunction, SleepFut>(
+    sleep: &SleepFunction,
+    duration: Duration,
+    receiver: &mut IncomingReceiver,
+) -> Option<(IncomingQueueMessage, Duration)>
+where
+    SleepFunction: Fn(Duration) -> SleepFut,
+    SleepFut: Future<Output = ()>,
+{
+    let start = Instant::now();
+    let slept = pin!((sleep)(duration));
+    match select(slept, receiver.next()).await {
+        Either::Left(_) => None,
+        Either::Right((Some(message), _)) => {
+            Some((message, duration.saturating_sub(start.elapsed())))
+        }
+        // Nothing can be received anymore
+        Either::Right((None, slept)) => {
+            slept.await;
+            None
         }
     }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use crate::agent::{Agent, AgentToMapExt, NewEventsVec};
+    use crate::agent::{Agent, AgentSlot, AgentToMapExt, AsyncAgent, NewEventsVec};
+    use crate::channel::{
+        incoming_channel, outgoing_channel, IncomingSender, DEFAULT_OUTGOING_CAPACITY,
+    };
+    use crate::error::{EventEngineError, InjectionError};
     use crate::event::{Event, EventArg, EventArgs};
-    use crate::event_queue::{process_event_queue, EventEngineError};
+    use crate::event_queue::process_event_queue;
 
     use crate::environment::{EnvironmentSettings, DEFAULT_ITER_COUNT_SLEEP};
-    use crate::message::{IncomingQueueMessage, OutgoingQueueMessage};
+    use crate::introspection::{AgentQuery, AgentSnapshot, Introspect};
+    use crate::message::{
+        AgentStateChange, IncomingQueueMessage, InjectAt, OutgoingQueueMessage, Progress,
+        ProgressRate,
+    };
+    use crate::observer::{FinishReason, Observer};
+    use crate::pacing::Pacing;
+    use crate::trace::tests::SharedBuffer;
+    use crate::trace::{
+        ScheduledEvent, TraceFormat, TraceReader, TraceRecord, TraceRecorder, TraceReplay,
+        TraceSink,
+    };
+    use serde_json::Value;
     use std::any::Any;
-    use std::sync::mpsc;
+    use std::cell::{Cell, RefCell};
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
     use std::time::Duration;
     use uuid::Uuid;
 
@@ -123,9 +484,9 @@ pub mod tests {
     #[tokio::test]
     pub async fn it_errors_when_init_event_does_not_point_to_agent() {
         let events: Vec<(Event, u64)> = vec![(Event::new(Default::default()), 1)];
-        let agents = vec![];
-        let (_send, recv) = mpsc::channel();
-        let (send, _recv) = mpsc::channel();
+        let agents: Vec<&mut dyn Agent> = vec![];
+        let (_send, recv) = incoming_channel();
+        let (send, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
         let result = process_event_queue(
             agents,
             events,
@@ -134,9 +495,15 @@ pub mod tests {
             &mut |_| async {},
             TestSettings {},
             send,
+            None,
+            None,
+            &mut [],
         )
         .await;
-        assert!(matches!(result, Err(EventEngineError::EventHasNoAgent)));
+        assert!(matches!(
+            result,
+            Err(EventEngineError::EventHasNoAgent { time: 1, .. })
+        ));
     }
 
     #[tokio::test]
@@ -148,8 +515,8 @@ pub mod tests {
         };
         let agents = vec![&mut agent as &mut dyn Agent];
 
-        let (_send, recv) = mpsc::channel();
-        let (out_send, out_recv) = mpsc::channel();
+        let (_send, recv) = incoming_channel();
+        let (out_send, mut out_recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
 
         let result = process_event_queue(
             agents,
@@ -159,13 +526,16 @@ pub mod tests {
             &mut |_| async {},
             TestSettings {},
             out_send,
+            None,
+            None,
+            &mut [],
         )
         .await;
 
         assert!(result.is_ok());
         assert!(agent.handler_was_called);
         let result = out_recv.try_recv();
-        assert!(result.is_ok());
+        assert!(result.is_some());
         assert!(matches!(result.unwrap(), OutgoingQueueMessage::Started));
     }
 
@@ -205,8 +575,8 @@ pub mod tests {
 
         let init_state = vec![(Event::new(caller_uuid), 0)];
 
-        let (_send, recv) = mpsc::channel();
-        let (send, _recv) = mpsc::channel();
+        let (_send, recv) = incoming_channel();
+        let (send, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
 
         let result = process_event_queue(
             agents,
@@ -216,6 +586,9 @@ pub mod tests {
             &mut |_| async {},
             TestSettings {},
             send,
+            None,
+            None,
+            &mut [],
         )
         .await;
         assert!(result.is_ok());
@@ -247,8 +620,7 @@ pub mod tests {
                         vec![]
                     }
                     None => {
-                        assert!(false, "Expected any to be EventArg");
-                        vec![]
+                        panic!("Expected any to be EventArg");
                     }
                 }
             }
@@ -266,8 +638,8 @@ pub mod tests {
         let event = Event::new_with_args(agent.get_id(), Box::new(TestEventArg { x: 42 }));
         let agents = Agent::solo_vec(&mut agent);
 
-        let (_send, recv) = mpsc::channel();
-        let (send, _recv) = mpsc::channel();
+        let (_send, recv) = incoming_channel();
+        let (send, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
 
         let result = process_event_queue(
             agents,
@@ -277,12 +649,63 @@ pub mod tests {
             &mut |_| async {},
             TestSettings {},
             send,
+            None,
+            None,
+            &mut [],
         )
         .await;
         assert!(result.is_ok());
         assert_eq!(agent.x, 42);
     }
 
+    #[test]
+    pub fn event_args_need_not_be_send() {
+        struct SharedCount(Rc<Cell<u64>>);
+        impl EventArgs for SharedCount {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+        struct Counter {
+            id: Uuid,
+        }
+        impl Agent for Counter {
+            fn handle(&mut self, _time: u64, args: EventArg) -> NewEventsVec {
+                if let Some(count) = args
+                    .as_ref()
+                    .and_then(|args| args.as_any().downcast_ref::<SharedCount>())
+                {
+                    count.0.set(count.0.get() + 1);
+                }
+                vec![]
+            }
+
+            fn get_id(&self) -> Uuid {
+                self.id
+            }
+        }
+
+        let count = Rc::new(Cell::new(0));
+        let mut agent = Counter {
+            id: Uuid::from_u128(1),
+        };
+        let events = (0..3)
+            .map(|time| {
+                let args = Box::new(SharedCount(count.clone()));
+                (Event::new_with_args(agent.id, args), time)
+            })
+            .collect();
+        let (result, _) = run_engine(
+            Agent::solo_vec(&mut agent),
+            events,
+            TestSettings {},
+            None,
+            &mut [],
+        );
+        assert!(result.is_ok());
+        assert_eq!(count.get(), 3);
+    }
+
     #[tokio::test]
     pub async fn test_event_args_diff_types() {
         pub struct EventInc {
@@ -356,8 +779,8 @@ pub mod tests {
             ),
         ];
 
-        let (_send, recv) = mpsc::channel();
-        let (send, _recv) = mpsc::channel();
+        let (_send, recv) = incoming_channel();
+        let (send, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
 
         let result = process_event_queue(
             agents.mut_agent_vector(),
@@ -367,13 +790,66 @@ pub mod tests {
             &mut |_| async {},
             TestSettings {},
             send,
+            None,
+            None,
+            &mut [],
         )
         .await;
         assert!(result.is_ok());
-        assert_eq!(agents.get(0).unwrap().x, 42);
+        assert_eq!(agents.first().unwrap().x, 42);
         assert_eq!(agents.get(1).unwrap().x, -42);
     }
 
+    #[tokio::test]
+    pub async fn it_dispatches_the_earliest_event_first() {
+        struct Recorder {
+            id: Uuid,
+            handled: Vec<u64>,
+        }
+
+        impl Agent for Recorder {
+            fn handle(&mut self, time: u64, _args: EventArg) -> NewEventsVec {
+                self.handled.push(time);
+                if time == 1 {
+                    vec![(Event::new(self.id), 2), (Event::new(self.id), 9)]
+                } else {
+                    vec![]
+                }
+            }
+
+            fn get_id(&self) -> Uuid {
+                self.id
+            }
+        }
+
+        let id = Uuid::new_v4();
+        let mut recorder = Recorder {
+            id,
+            handled: vec![],
+        };
+        let (_send, recv) = incoming_channel();
+        let (send, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        let result = process_event_queue(
+            Agent::solo_vec(&mut recorder),
+            vec![
+                (Event::new(id), 5),
+                (Event::new(id), 1),
+                (Event::new(id), 3),
+            ],
+            recv,
+            &mut |_| {},
+            &mut |_| async {},
+            TestSettings {},
+            send,
+            None,
+            None,
+            &mut [],
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(recorder.handled, vec![1, 2, 3, 5, 9]);
+    }
+
     pub struct InfiniteLoopAgent {
         id: Uuid,
     }
@@ -398,9 +874,9 @@ pub mod tests {
 
         let event = Event::new(id);
 
-        let (send, recv) = mpsc::channel();
-        let (outsend, _recv) = mpsc::channel();
-        let send_result = send.send(IncomingQueueMessage::Halt);
+        let (send, recv) = incoming_channel();
+        let (outsend, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        let send_result = send.unbounded_send(IncomingQueueMessage::Halt);
         assert!(send_result.is_ok());
         let result = process_event_queue(
             agents,
@@ -410,6 +886,9 @@ pub mod tests {
             &mut |duration| tokio::time::sleep(duration),
             TestSettings {},
             outsend,
+            None,
+            None,
+            &mut [],
         )
         .await;
         assert!(result.is_ok());
@@ -442,8 +921,8 @@ pub mod tests {
 
                 let event = Event::new(id);
 
-                let (send, recv) = mpsc::channel();
-                let (outsend, _recv) = mpsc::channel();
+                let (send, recv) = incoming_channel();
+                let (outsend, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
 
                 let result = process_event_queue(
                     agents,
@@ -453,9 +932,13 @@ pub mod tests {
                     &mut self.sleep,
                     TestSettings {},
                     outsend,
+                    None,
+                    None,
+                    &mut [],
                 );
 
-                let send_result = send.send(IncomingQueueMessage::ChangeSleepDurationMs(50000));
+                let send_result =
+                    send.unbounded_send(IncomingQueueMessage::ChangeSleepDurationMs(50000));
                 assert!(send_result.is_ok());
 
                 let t = tokio::time::timeout(Duration::from_secs(1), result);
@@ -488,8 +971,8 @@ pub mod tests {
         };
         let agents = vec![&mut agent as &mut dyn Agent];
 
-        let (_send, recv) = mpsc::channel();
-        let (outsend, _recv) = mpsc::channel();
+        let (_send, recv) = incoming_channel();
+        let (outsend, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
 
         let result = process_event_queue(
             agents,
@@ -499,6 +982,9 @@ pub mod tests {
             &mut |_| async {},
             TestSettingsZeroMaxIter {},
             outsend,
+            None,
+            None,
+            &mut [],
         )
         .await;
 
@@ -516,10 +1002,10 @@ pub mod tests {
 
         let event = Event::new(id);
 
-        let (send, recv) = mpsc::channel();
-        let (outsend, outrecv) = mpsc::channel();
+        let (send, recv) = incoming_channel();
+        let (outsend, mut outrecv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
 
-        let send_result = send.send(IncomingQueueMessage::ChangeMaxIter(
+        let send_result = send.unbounded_send(IncomingQueueMessage::ChangeMaxIter(
             DEFAULT_ITER_COUNT_SLEEP * 2 - 1,
         ));
         assert!(send_result.is_ok());
@@ -533,15 +1019,865 @@ pub mod tests {
                 &mut |duration| tokio::time::sleep(duration),
                 TestSettings {},
                 outsend,
+                None,
+                None,
+                &mut [],
             ),
         )
         .await;
         assert!(result.is_ok());
         let result = outrecv.try_recv();
-        assert!(result.is_ok());
+        assert!(result.is_some());
 
         if let OutgoingQueueMessage::Iter(iter) = result.unwrap() {
             assert_eq!(iter, DEFAULT_ITER_COUNT_SLEEP);
         }
     }
+
+    struct PacedSettings {
+        pacing: Pacing,
+        max_iter: u64,
+    }
+
+    impl EnvironmentSettings for PacedSettings {
+        fn get_max_iter(&self) -> u64 {
+            self.max_iter
+        }
+
+        fn get_pacing(&self) -> Pacing {
+            self.pacing.clone()
+        }
+    }
+
+    /// Paced settings whose ticks last `tick` of simulated time.
+    struct TickedSettings {
+        paced: PacedSettings,
+        tick: Duration,
+    }
+
+    impl EnvironmentSettings for TickedSettings {
+        fn get_max_iter(&self) -> u64 {
+            self.paced.max_iter
+        }
+
+        fn get_pacing(&self) -> Pacing {
+            self.paced.pacing.clone()
+        }
+
+        fn get_tick(&self) -> Duration {
+            self.tick
+        }
+    }
+
+    /// Runs `agents` to the end without sleeping, returns the result and the logged messages.
+    pub(crate) fn run_engine<'a>(
+        agents: Vec<impl Into<AgentSlot<'a>>>,
+        init_state: Vec<(Event, u64)>,
+        settings: impl EnvironmentSettings,
+        trace: Option<&mut dyn TraceSink>,
+        observers: &mut [Box<dyn Observer>],
+    ) -> (Result<(), EventEngineError>, Vec<String>) {
+        let mut logged = vec![];
+        let (_send, recv) = incoming_channel();
+        let (send, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        let result = futures::executor::block_on(process_event_queue(
+            agents,
+            init_state,
+            recv,
+            &mut |message: &str| logged.push(message.to_string()),
+            &mut |_| async {},
+            settings,
+            send,
+            None,
+            trace,
+            observers,
+        ));
+        (result, logged)
+    }
+
+    async fn run_paced(
+        settings: impl EnvironmentSettings,
+        messages: Vec<IncomingQueueMessage>,
+    ) -> Vec<Duration> {
+        let id = Uuid::new_v4();
+        let mut agent = InfiniteLoopAgent { id };
+        let (send, recv) = incoming_channel();
+        let (outsend, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        for message in messages {
+            assert!(send.unbounded_send(message).is_ok());
+        }
+        let sleeps = RefCell::new(vec![]);
+        let result = process_event_queue(
+            Agent::solo_vec(&mut agent),
+            vec![(Event::new(id), 0)],
+            recv,
+            &mut |_| {},
+            &mut |duration| {
+                sleeps.borrow_mut().push(duration);
+                tokio::time::sleep(duration)
+            },
+            settings,
+            outsend,
+            None,
+            None,
+            &mut [],
+        )
+        .await;
+        assert!(result.is_ok());
+        sleeps.into_inner()
+    }
+
+    #[tokio::test]
+    pub async fn unthrottled_pacing_never_sleeps() {
+        let sleeps = run_paced(
+            PacedSettings {
+                pacing: Pacing::Unthrottled,
+                max_iter: DEFAULT_ITER_COUNT_SLEEP * 3,
+            },
+            vec![],
+        )
+        .await;
+        assert!(sleeps.is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn wall_clock_pacing_follows_simulated_time() {
+        let start = std::time::Instant::now();
+        let sleeps = run_paced(
+            PacedSettings {
+                pacing: Pacing::WallClockScaled { scale: 0.01 },
+                max_iter: 21,
+            },
+            vec![],
+        )
+        .await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(!sleeps.is_empty());
+        assert!(sleeps
+            .iter()
+            .all(|sleep| *sleep <= Duration::from_millis(10)));
+    }
+
+    #[tokio::test]
+    pub async fn wall_clock_pacing_scales_simulated_seconds() {
+        let start = std::time::Instant::now();
+        // A one-minute tick lasts 10ms
+        run_paced(
+            TickedSettings {
+                paced: PacedSettings {
+                    pacing: Pacing::WallClockScaled { scale: 0.01 / 60.0 },
+                    max_iter: 21,
+                },
+                tick: Duration::from_secs(60),
+            },
+            vec![],
+        )
+        .await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    pub async fn long_wall_clock_waits_can_be_halted() {
+        let id = Uuid::new_v4();
+        let mut agent = InfiniteLoopAgent { id };
+        let (send, recv) = incoming_channel();
+        let (outsend, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        let sleeps = RefCell::new(vec![]);
+        let mut sleep = |duration| {
+            sleeps.borrow_mut().push(duration);
+            tokio::time::sleep(duration)
+        };
+        let mut log = |_: &str| {};
+        // Every tick lasts an hour of real time
+        let run = process_event_queue(
+            Agent::solo_vec(&mut agent),
+            vec![(Event::new(id), 0)],
+            recv,
+            &mut log,
+            &mut sleep,
+            PacedSettings {
+                pacing: Pacing::WallClockScaled { scale: 3600.0 },
+                max_iter: 10,
+            },
+            outsend,
+            None,
+            None,
+            &mut [],
+        );
+        let halt = async {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            assert!(send.unbounded_send(IncomingQueueMessage::Halt).is_ok());
+        };
+        // Without the halt the run would take ten hours
+        let (result, ()) =
+            tokio::time::timeout(Duration::from_secs(2), futures::future::join(run, halt))
+                .await
+                .unwrap();
+        assert!(result.is_ok());
+        assert_eq!(sleeps.into_inner().len(), 1);
+    }
+
+    /// Sleeps `sleep_ms` after every event.
+    struct SleepySettings {
+        sleep_ms: u64,
+        max_iter: u64,
+    }
+
+    impl EnvironmentSettings for SleepySettings {
+        fn get_iter_count(&self) -> u64 {
+            1
+        }
+
+        fn get_sleep_ms(&self) -> u64 {
+            self.sleep_ms
+        }
+
+        fn get_max_iter(&self) -> u64 {
+            self.max_iter
+        }
+    }
+
+    #[tokio::test]
+    pub async fn queries_are_answered_during_sleeps_that_then_go_on() {
+        let id = Uuid::new_v4();
+        let mut agent = InfiniteLoopAgent { id };
+        let (send, recv) = incoming_channel();
+        let (outsend, mut out_recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        let mut sleep = |duration| tokio::time::sleep(duration);
+        let mut log = |_: &str| {};
+        let start = std::time::Instant::now();
+        let run = process_event_queue(
+            Agent::solo_vec(&mut agent),
+            vec![(Event::new(id), 0)],
+            recv,
+            &mut log,
+            &mut sleep,
+            SleepySettings {
+                sleep_ms: 300,
+                max_iter: 1,
+            },
+            outsend,
+            None,
+            None,
+            &mut [],
+        );
+        let query = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let message = IncomingQueueMessage::QueryAgents(AgentQuery::All);
+            assert!(send.unbounded_send(message).is_ok());
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            out_recv
+                .try_iter()
+                .any(|message| matches!(message, OutgoingQueueMessage::Agents(_)))
+        };
+        let (result, answered) = futures::future::join(run, query).await;
+        assert!(result.is_ok());
+        assert!(answered);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    pub async fn halts_end_sleeps_early() {
+        let id = Uuid::new_v4();
+        let mut agent = InfiniteLoopAgent { id };
+        let (send, recv) = incoming_channel();
+        let (outsend, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        let mut sleep = |duration| tokio::time::sleep(duration);
+        let mut log = |_: &str| {};
+        let run = process_event_queue(
+            Agent::solo_vec(&mut agent),
+            vec![(Event::new(id), 0)],
+            recv,
+            &mut log,
+            &mut sleep,
+            SleepySettings {
+                sleep_ms: 3_600_000,
+                max_iter: 10,
+            },
+            outsend,
+            None,
+            None,
+            &mut [],
+        );
+        let halt = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(send.unbounded_send(IncomingQueueMessage::Halt).is_ok());
+        };
+        let (result, ()) =
+            tokio::time::timeout(Duration::from_secs(2), futures::future::join(run, halt))
+                .await
+                .unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    pub async fn frame_budget_pacing_yields_without_delay() {
+        let sleeps = run_paced(
+            PacedSettings {
+                pacing: Pacing::FrameBudget { budget_ms: 0 },
+                max_iter: 10,
+            },
+            vec![],
+        )
+        .await;
+        assert_eq!(sleeps, vec![Duration::ZERO; 10]);
+    }
+
+    #[tokio::test]
+    pub async fn pacing_changes_at_runtime() {
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            run_paced(
+                PacedSettings {
+                    pacing: Pacing::EventCount,
+                    max_iter: DEFAULT_ITER_COUNT_SLEEP * 2,
+                },
+                vec![
+                    IncomingQueueMessage::ChangeSleepDurationMs(50000),
+                    IncomingQueueMessage::ChangePacing(Pacing::Unthrottled),
+                ],
+            ),
+        )
+        .await;
+        assert_eq!(result.unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    pub async fn yield_pacing_lets_other_tasks_run() {
+        let sleeps = run_paced(
+            PacedSettings {
+                pacing: Pacing::Yield { every: 2 },
+                max_iter: 10,
+            },
+            vec![],
+        )
+        .await;
+        assert_eq!(sleeps, vec![Duration::ZERO; 5]);
+
+        // Without yielding the timeout would never get polled
+        let result = tokio::time::timeout(
+            Duration::from_millis(100),
+            run_paced(
+                PacedSettings {
+                    pacing: Pacing::Yield { every: 100 },
+                    max_iter: u64::MAX,
+                },
+                vec![],
+            ),
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    pub async fn it_drives_async_agents() {
+        struct AwaitingAgent {
+            id: Uuid,
+            callee: Uuid,
+            handled: Vec<u64>,
+        }
+
+        #[async_trait::async_trait(?Send)]
+        impl AsyncAgent for AwaitingAgent {
+            async fn handle(&mut self, time: u64, _args: EventArg) -> NewEventsVec {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                self.handled.push(time);
+                vec![(Event::new(self.callee), time + 1)]
+            }
+
+            fn get_id(&self) -> Uuid {
+                self.id
+            }
+        }
+
+        let callee_id = Uuid::new_v4();
+        let mut callee = TestAgentWasCalled {
+            id: callee_id,
+            handler_was_called: false,
+        };
+        let mut caller = AwaitingAgent {
+            id: Uuid::new_v4(),
+            callee: callee_id,
+            handled: vec![],
+        };
+        let init_state = vec![(Event::new(caller.id), 3), (Event::new(caller.id), 5)];
+        let agents = vec![AgentSlot::Async(&mut caller), AgentSlot::Sync(&mut callee)];
+
+        let (_send, recv) = incoming_channel();
+        let (send, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        let result = process_event_queue(
+            agents,
+            init_state,
+            recv,
+            &mut |_| {},
+            &mut |_| async {},
+            TestSettings {},
+            send,
+            None,
+            None,
+            &mut [],
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(caller.handled, vec![3, 5]);
+        assert!(callee.handler_was_called);
+    }
+
+    #[tokio::test]
+    pub async fn it_sends_progress_with_changed_agent_properties() {
+        struct CountingAgent {
+            id: Uuid,
+            count: u64,
+        }
+
+        impl Agent for CountingAgent {
+            fn handle(&mut self, time: u64, _args: EventArg) -> NewEventsVec {
+                self.count += 1;
+                vec![(Event::new(self.id), time + 1)]
+            }
+
+            fn get_id(&self) -> Uuid {
+                self.id
+            }
+
+            fn introspect(&self) -> Option<&dyn Introspect> {
+                Some(self)
+            }
+        }
+
+        impl Introspect for CountingAgent {
+            fn type_name(&self) -> &str {
+                "CountingAgent"
+            }
+
+            fn properties(&self) -> BTreeMap<String, Value> {
+                [("count".to_string(), Value::from(self.count))]
+                    .into_iter()
+                    .collect()
+            }
+        }
+
+        struct IdleAgent {
+            id: Uuid,
+        }
+
+        impl Agent for IdleAgent {
+            fn handle(&mut self, _time: u64, _args: EventArg) -> NewEventsVec {
+                vec![]
+            }
+
+            fn get_id(&self) -> Uuid {
+                self.id
+            }
+
+            fn introspect(&self) -> Option<&dyn Introspect> {
+                Some(self)
+            }
+        }
+
+        impl Introspect for IdleAgent {
+            fn type_name(&self) -> &str {
+                "IdleAgent"
+            }
+
+            fn properties(&self) -> BTreeMap<String, Value> {
+                BTreeMap::new()
+            }
+        }
+
+        struct ProgressSettings {}
+
+        impl EnvironmentSettings for ProgressSettings {
+            fn get_max_iter(&self) -> u64 {
+                4
+            }
+
+            fn get_progress_rate(&self) -> ProgressRate {
+                ProgressRate::Events(2)
+            }
+        }
+
+        let mut counting = CountingAgent {
+            id: Uuid::from_u128(1),
+            count: 0,
+        };
+        let mut idle = IdleAgent {
+            id: Uuid::from_u128(2),
+        };
+        let (_send, recv) = incoming_channel();
+        let (send, mut out_recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        let result = process_event_queue(
+            vec![&mut counting as &mut dyn Agent, &mut idle as &mut dyn Agent],
+            vec![(Event::new(Uuid::from_u128(1)), 10)],
+            recv,
+            &mut |_| {},
+            &mut |_| async {},
+            ProgressSettings {},
+            send,
+            None,
+            None,
+            &mut [],
+        )
+        .await;
+        assert!(result.is_ok());
+
+        let count = |count: u64| BTreeMap::from([("count".to_string(), Value::from(count))]);
+        let messages: Vec<OutgoingQueueMessage> = out_recv.try_iter().collect();
+        assert_eq!(messages.len(), 5);
+        assert!(matches!(messages[0], OutgoingQueueMessage::Started));
+        let progress: Vec<&Progress> = messages[1..4]
+            .iter()
+            .map(|message| match message {
+                OutgoingQueueMessage::Progress(progress) => progress,
+                _ => panic!("Expected progress"),
+            })
+            .collect();
+        assert_eq!(progress[0].time, 11);
+        assert_eq!(progress[0].events, 2);
+        assert_eq!(progress[0].queue_length, 1);
+        assert_eq!(
+            progress[0].changed_agents,
+            vec![
+                AgentStateChange {
+                    agent: counting.id,
+                    properties: count(2),
+                },
+                AgentStateChange {
+                    agent: idle.id,
+                    properties: BTreeMap::new(),
+                },
+            ]
+        );
+        assert_eq!(progress[1].events, 4);
+        assert_eq!(
+            progress[1].changed_agents,
+            vec![AgentStateChange {
+                agent: counting.id,
+                properties: count(4),
+            }]
+        );
+        assert_eq!((progress[2].time, progress[2].events), (13, 4));
+        assert!(progress[2].changed_agents.is_empty());
+        assert!(matches!(
+            messages[4],
+            OutgoingQueueMessage::Finished(FinishReason::MaxIter)
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn it_reports_errors_to_the_receiver() {
+        let (_send, recv) = incoming_channel();
+        let (send, mut out_recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        let result = process_event_queue(
+            Vec::<&mut dyn Agent>::new(),
+            vec![(Event::new(Uuid::new_v4()), 0)],
+            recv,
+            &mut |_| {},
+            &mut |_| async {},
+            TestSettings {},
+            send,
+            None,
+            None,
+            &mut [],
+        )
+        .await;
+        assert!(result.is_err());
+        let last = out_recv.try_iter().last();
+        assert!(matches!(
+            last,
+            Some(OutgoingQueueMessage::Error(error)) if error.contains("targets unknown agent")
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn it_answers_agent_queries() {
+        struct Counter {
+            id: Uuid,
+            counter: u64,
+        }
+        impl Agent for Counter {
+            fn handle(&mut self, _time: u64, _args: EventArg) -> NewEventsVec {
+                self.counter += 1;
+                vec![]
+            }
+
+            fn get_id(&self) -> Uuid {
+                self.id
+            }
+
+            fn introspect(&self) -> Option<&dyn Introspect> {
+                Some(self)
+            }
+        }
+        impl Introspect for Counter {
+            fn type_name(&self) -> &str {
+                "Counter"
+            }
+
+            fn properties(&self) -> BTreeMap<String, Value> {
+                [("counter".to_string(), Value::from(self.counter))]
+                    .into_iter()
+                    .collect()
+            }
+        }
+        struct Hidden {
+            id: Uuid,
+        }
+        impl Agent for Hidden {
+            fn handle(&mut self, _time: u64, _args: EventArg) -> NewEventsVec {
+                vec![]
+            }
+
+            fn get_id(&self) -> Uuid {
+                self.id
+            }
+        }
+
+        let mut counter = Counter {
+            id: Uuid::from_u128(1),
+            counter: 0,
+        };
+        let mut hidden = Hidden {
+            id: Uuid::from_u128(2),
+        };
+        let (send, recv) = incoming_channel();
+        let (out_send, mut out_recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        assert!(send
+            .unbounded_send(IncomingQueueMessage::QueryAgents(AgentQuery::All))
+            .is_ok());
+        let result = process_event_queue(
+            vec![&mut counter as &mut dyn Agent, &mut hidden],
+            vec![(Event::new(Uuid::from_u128(1)), 0)],
+            recv,
+            &mut |_| {},
+            &mut |_| async {},
+            TestSettings {},
+            out_send,
+            None,
+            None,
+            &mut [],
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(counter.counter, 1);
+        let answers: Vec<Vec<AgentSnapshot>> = out_recv
+            .try_iter()
+            .filter_map(|message| match message {
+                OutgoingQueueMessage::Agents(snapshots) => Some(snapshots),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            answers,
+            vec![vec![AgentSnapshot {
+                id: Uuid::from_u128(1),
+                type_name: "Counter".to_string(),
+                properties: [("counter".to_string(), Value::from(0))]
+                    .into_iter()
+                    .collect(),
+            }]]
+        );
+    }
+
+    #[tokio::test]
+    pub async fn it_schedules_injected_events() {
+        struct RushOrder;
+        impl EventArgs for RushOrder {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+        struct Recorder {
+            id: Uuid,
+            handled: Vec<(u64, bool)>,
+            control: IncomingSender,
+        }
+        impl Agent for Recorder {
+            fn handle(&mut self, time: u64, args: EventArg) -> NewEventsVec {
+                let rush = args.is_some_and(|args| args.as_any().is::<RushOrder>());
+                self.handled.push((time, rush));
+                if rush {
+                    let message = IncomingQueueMessage::InjectEvent {
+                        target: self.id,
+                        at: InjectAt::Time(3),
+                        args: None,
+                    };
+                    assert!(self.control.unbounded_send(message).is_ok());
+                }
+                vec![]
+            }
+
+            fn get_id(&self) -> Uuid {
+                self.id
+            }
+        }
+
+        let id = Uuid::from_u128(1);
+        let unknown = Uuid::from_u128(2);
+        let (send, recv) = incoming_channel();
+        let (out_send, mut out_recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        let mut recorder = Recorder {
+            id,
+            handled: vec![],
+            control: send.clone(),
+        };
+        // All of them are read before the first dispatch, at time 0
+        for (target, at, args) in [
+            (unknown, InjectAt::Time(1), None),
+            (
+                id,
+                InjectAt::Time(5),
+                Some(Box::new(RushOrder) as Box<dyn EventArgs + Send>),
+            ),
+            (id, InjectAt::Delay(2), None),
+        ] {
+            let message = IncomingQueueMessage::InjectEvent { target, at, args };
+            assert!(send.unbounded_send(message).is_ok());
+        }
+        let result = process_event_queue(
+            Agent::solo_vec(&mut recorder),
+            vec![(Event::new(id), 0)],
+            recv,
+            &mut |_| {},
+            &mut |_| async {},
+            TestSettings {},
+            out_send,
+            None,
+            None,
+            &mut [],
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(recorder.handled, vec![(0, false), (2, false), (5, true)]);
+        let rejections: Vec<InjectionError> = out_recv
+            .try_iter()
+            .filter_map(|message| match message {
+                OutgoingQueueMessage::EventRejected(error) => Some(error),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            rejections,
+            vec![
+                InjectionError::UnknownAgent(unknown),
+                InjectionError::InThePast { time: 3, now: 5 },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    pub async fn it_rejects_a_zero_sleep_iter_count() {
+        struct SleepSettings {}
+        impl EnvironmentSettings for SleepSettings {
+            fn get_iter_count(&self) -> u64 {
+                2
+            }
+        }
+
+        let id = Uuid::from_u128(1);
+        let mut agent = TestAgentWasCalled {
+            id,
+            handler_was_called: false,
+        };
+        let (send, recv) = incoming_channel();
+        let (out_send, mut out_recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        assert!(send
+            .unbounded_send(IncomingQueueMessage::ChangeSleepIterCount(0))
+            .is_ok());
+        let result = process_event_queue(
+            Agent::solo_vec(&mut agent),
+            (0..5).map(|time| (Event::new(id), time)).collect(),
+            recv,
+            &mut |_| {},
+            &mut |_| async {},
+            SleepSettings {},
+            out_send,
+            None,
+            None,
+            &mut [],
+        )
+        .await;
+        assert!(result.is_ok());
+        assert!(agent.handler_was_called);
+        let rejections: Vec<InjectionError> = out_recv
+            .try_iter()
+            .filter_map(|message| match message {
+                OutgoingQueueMessage::EventRejected(error) => Some(error),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            rejections,
+            vec![InjectionError::ZeroSleepIterCount { kept: 2 }]
+        );
+    }
+
+    #[tokio::test]
+    pub async fn injected_events_are_traced_and_diverge_on_replay() {
+        struct RushOrder;
+        impl EventArgs for RushOrder {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        let id = Uuid::from_u128(1);
+        let run = |inject: bool, trace: &mut dyn TraceSink| {
+            let mut agent = TestAgentWasCalled {
+                id,
+                handler_was_called: false,
+            };
+            let (send, recv) = incoming_channel();
+            let (out_send, _out_recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+            if inject {
+                let message = IncomingQueueMessage::InjectEvent {
+                    target: id,
+                    at: InjectAt::Time(5),
+                    args: Some(Box::new(RushOrder)),
+                };
+                assert!(send.unbounded_send(message).is_ok());
+            }
+            futures::executor::block_on(process_event_queue(
+                Agent::solo_vec(&mut agent),
+                vec![(Event::new(id), 0)],
+                recv,
+                &mut |_| {},
+                &mut |_| async {},
+                TestSettings {},
+                out_send,
+                None,
+                Some(trace),
+                &mut [],
+            ))
+        };
+
+        let buffer = SharedBuffer::default();
+        let mut recorder = TraceRecorder::new(buffer.clone(), TraceFormat::JsonLines);
+        assert!(run(true, &mut recorder).is_ok());
+        let records: Vec<TraceRecord> = TraceReader::new(
+            std::io::Cursor::new(buffer.contents()),
+            TraceFormat::JsonLines,
+        )
+        .collect::<Result<_, _>>()
+        .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0].injected,
+            vec![ScheduledEvent {
+                target: id,
+                time: 5,
+                payload_type: Some(RushOrder.type_name().to_string()),
+            }]
+        );
+        assert!(records[1].injected.is_empty());
+
+        let mut replay = TraceReplay::new(TraceReader::new(
+            std::io::Cursor::new(buffer.contents()),
+            TraceFormat::JsonLines,
+        ));
+        let error = run(false, &mut replay).unwrap_err();
+        assert!(error.to_string().contains("injected"), "{}", error);
+    }
 }
@@ -0,0 +1,417 @@
+use crate::environment::AgentEnvironment;
+use crate::error::EventEngineError;
+use crate::rng::{simulation_rng, DEFAULT_SEED};
+use rand::RngCore;
+use std::collections::BTreeMap;
+
+/// Named results of a single run, e.g. `StatisticsReport::flatten`.
+pub type Kpis = BTreeMap<String, f64>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplicationSettings {
+    pub max_replications: usize,
+    pub min_replications: usize,
+    pub base_seed: u64,
+    /// Confidence level of the reported intervals, e.g. 0.95.
+    pub confidence: f64,
+    /// Stop as soon as every KPI has `half_width / |mean|` not above this value.
+    pub target_relative_precision: Option<f64>,
+    /// Replications run at the same time. Ignored on WASM where everything runs sequentially.
+    pub parallelism: usize,
+}
+
+impl Default for ReplicationSettings {
+    fn default() -> Self {
+        ReplicationSettings {
+            max_replications: 10,
+            min_replications: 3,
+            base_seed: DEFAULT_SEED,
+            confidence: 0.95,
+            target_relative_precision: None,
+            parallelism: default_parallelism(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConfidenceInterval {
+    pub count: usize,
+    pub mean: f64,
+    pub std_dev: f64,
+    pub half_width: f64,
+}
+
+impl ConfidenceInterval {
+    pub fn from_samples(samples: &[f64], confidence: f64) -> ConfidenceInterval {
+        let count = samples.len();
+        if count == 0 {
+            return ConfidenceInterval::default();
+        }
+        let mean = samples.iter().sum::<f64>() / count as f64;
+        if count == 1 {
+            return ConfidenceInterval {
+                count,
+                mean,
+                std_dev: 0.0,
+                half_width: f64::INFINITY,
+            };
+        }
+        let variance = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (count - 1) as f64;
+        let std_dev = variance.sqrt();
+        let t = student_t_quantile(1.0 - (1.0 - confidence) / 2.0, (count - 1) as f64);
+        ConfidenceInterval {
+            count,
+            mean,
+            std_dev,
+            half_width: t * std_dev / (count as f64).sqrt(),
+        }
+    }
+
+    pub fn lower(&self) -> f64 {
+        self.mean - self.half_width
+    }
+
+    pub fn upper(&self) -> f64 {
+        self.mean + self.half_width
+    }
+
+    pub fn relative_precision(&self) -> f64 {
+        if self.half_width == 0.0 {
+            0.0
+        } else {
+            self.half_width / self.mean.abs()
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replication {
+    pub index: usize,
+    pub seed: u64,
+    pub kpis: Kpis,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplicationReport {
+    pub replications: Vec<Replication>,
+    pub summary: BTreeMap<String, ConfidenceInterval>,
+    pub reached_precision: bool,
+}
+
+impl ReplicationReport {
+    fn new(replications: Vec<Replication>, settings: &ReplicationSettings) -> ReplicationReport {
+        let mut samples: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+        for replication in &replications {
+            for (name, value) in &replication.kpis {
+                samples.entry(name.clone()).or_default().push(*value);
+            }
+        }
+        let summary: BTreeMap<String, ConfidenceInterval> = samples
+            .into_iter()
+            .map(|(name, values)| {
+                (
+                    name,
+                    ConfidenceInterval::from_samples(&values, settings.confidence),
+                )
+            })
+            .collect();
+        let reached_precision = settings.target_relative_precision.is_some_and(|target| {
+            summary
+                .values()
+                .all(|interval| interval.relative_precision() <= target)
+        });
+        ReplicationReport {
+            replications,
+            summary,
+            reached_precision,
+        }
+    }
+}
+
+/// Seed of the `index`-th replication. Different experiments reuse the same seeds for the same
+/// `base_seed`, which gives common random numbers when comparing scenarios.
+pub fn replication_seed(base_seed: u64, index: usize) -> u64 {
+    simulation_rng(base_seed, index as u64).next_u64()
+}
+
+/// Runs `run` with a different seed for each replication until the precision target or the
+/// maximal number of replications is reached.
+pub fn run_replications<Run>(
+    settings: &ReplicationSettings,
+    run: Run,
+) -> Result<ReplicationReport, EventEngineError>
+where
+    Run: Fn(u64) -> Result<Kpis, EventEngineError> + Sync,
+{
+    let mut replications: Vec<Replication> = vec![];
+    loop {
+        let done = replications.len();
+        let remaining = settings.max_replications.saturating_sub(done);
+        let batch_size = if done < settings.min_replications {
+            settings.min_replications - done
+        } else {
+            settings.parallelism.max(1)
+        }
+        .min(remaining);
+        if batch_size == 0 {
+            break;
+        }
+        let seeds: Vec<(usize, u64)> = (done..done + batch_size)
+            .map(|index| (index, replication_seed(settings.base_seed, index)))
+            .collect();
+        for (index, seed, kpis) in run_batch(&seeds, settings.parallelism, &run) {
+            replications.push(Replication {
+                index,
+                seed,
+                kpis: kpis?,
+            });
+        }
+
+        if replications.len() >= settings.min_replications
+            && ReplicationReport::new(replications.clone(), settings).reached_precision
+        {
+            break;
+        }
+    }
+    Ok(ReplicationReport::new(replications, settings))
+}
+
+/// Drives an environment to the end on the current thread.
+pub fn run_to_completion<Environment: AgentEnvironment>(
+    environment: &mut Environment,
+    settings: Environment::TEnvironmentSettings,
+) -> Result<(), EventEngineError> {
+    futures::executor::block_on(environment.run(settings))
+}
+
+type BatchResult = Vec<(usize, u64, Result<Kpis, EventEngineError>)>;
+
+#[cfg(not(target_arch = "wasm32"))]
+fn run_batch<Run>(seeds: &[(usize, u64)], parallelism: usize, run: &Run) -> BatchResult
+where
+    Run: Fn(u64) -> Result<Kpis, EventEngineError> + Sync,
+{
+    if parallelism <= 1 || seeds.len() <= 1 {
+        return seeds
+            .iter()
+            .map(|(index, seed)| (*index, *seed, run(*seed)))
+            .collect();
+    }
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = seeds
+            .iter()
+            .map(|(index, seed)| scope.spawn(move || (*index, *seed, run(*seed))))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("Replication thread panicked"))
+            .collect()
+    })
+}
+
+#[cfg(target_arch = "wasm32")]
+fn run_batch<Run>(seeds: &[(usize, u64)], _parallelism: usize, run: &Run) -> BatchResult
+where
+    Run: Fn(u64) -> Result<Kpis, EventEngineError> + Sync,
+{
+    seeds
+        .iter()
+        .map(|(index, seed)| (*index, *seed, run(*seed)))
+        .collect()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn default_parallelism() -> usize {
+    std::thread::available_parallelism().map_or(1, |count| count.get())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn default_parallelism() -> usize {
+    1
+}
+
+/// Inverse of the standard normal distribution function (Acklam's approximation).
+pub fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969683028665376e+01,
+        2.209460984245205e+02,
+        -2.759285104469687e+02,
+        1.38357751867269e+02,
+        -3.066479806614716e+01,
+        2.506628277459239e+00,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e+01,
+        1.615858368580409e+02,
+        -1.556989798598866e+02,
+        6.680131188771972e+01,
+        -1.328068155288572e+01,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-03,
+        -3.223964580411365e-01,
+        -2.400758277161838e+00,
+        -2.549732539343734e+00,
+        4.374664141464968e+00,
+        2.938163982698783e+00,
+    ];
+    const D: [f64; 4] = [
+        7.784695709041462e-03,
+        3.224671290700398e-01,
+        2.445134137142996e+00,
+        3.754408661907416e+00,
+    ];
+    const LOW: f64 = 0.02425;
+
+    let tail = |q: f64| {
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    };
+    if p < LOW {
+        tail((-2.0 * p.ln()).sqrt())
+    } else if p > 1.0 - LOW {
+        -tail((-2.0 * (1.0 - p).ln()).sqrt())
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    }
+}
+
+/// Quantile of the Student's t distribution with `df` degrees of freedom.
+pub fn student_t_quantile(p: f64, df: f64) -> f64 {
+    if df == 1.0 {
+        return (std::f64::consts::PI * (p - 0.5)).tan();
+    }
+    if df == 2.0 {
+        return (2.0 * p - 1.0) / (2.0 * p * (1.0 - p)).sqrt();
+    }
+    // Cornish-Fisher expansion around the normal quantile
+    let z = normal_quantile(p);
+    let z3 = z.powi(3);
+    let z5 = z.powi(5);
+    let z7 = z.powi(7);
+    let z9 = z.powi(9);
+    z + (z3 + z) / (4.0 * df)
+        + (5.0 * z5 + 16.0 * z3 + 3.0 * z) / (96.0 * df.powi(2))
+        + (3.0 * z7 + 19.0 * z5 + 17.0 * z3 - 15.0 * z) / (384.0 * df.powi(3))
+        + (79.0 * z9 + 776.0 * z7 + 1482.0 * z5 - 1920.0 * z3 - 945.0 * z) / (92160.0 * df.powi(4))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::empty_environment::{EmptyEnvironmentSettings, InfiniteEmptyEnvironment};
+    use crate::rng::simulation_rng;
+    use rand::Rng;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    fn noisy_kpis(seed: u64) -> Result<Kpis, EventEngineError> {
+        let mut rng = simulation_rng(seed, 0);
+        let mut kpis = Kpis::new();
+        kpis.insert("throughput".to_string(), 10.0 + rng.gen::<f64>());
+        kpis.insert("wip".to_string(), 3.0 + 2.0 * rng.gen::<f64>());
+        Ok(kpis)
+    }
+
+    #[test]
+    fn quantiles_match_tables() {
+        assert!((normal_quantile(0.975) - 1.959964).abs() < 1e-5);
+        assert!((normal_quantile(0.5)).abs() < 1e-12);
+        assert!((student_t_quantile(0.975, 1.0) - 12.7062).abs() < 1e-3);
+        assert!((student_t_quantile(0.975, 2.0) - 4.3027).abs() < 1e-3);
+        assert!((student_t_quantile(0.975, 4.0) - 2.7764).abs() < 1e-2);
+        assert!((student_t_quantile(0.975, 9.0) - 2.2622).abs() < 1e-3);
+        assert!((student_t_quantile(0.995, 29.0) - 2.7564).abs() < 1e-3);
+    }
+
+    #[test]
+    fn it_computes_confidence_intervals() {
+        let interval = ConfidenceInterval::from_samples(&[9.0, 10.0, 11.0, 10.0], 0.95);
+        assert_eq!(interval.mean, 10.0);
+        let std_dev = (2.0f64 / 3.0).sqrt();
+        assert!((interval.std_dev - std_dev).abs() < 1e-12);
+        assert!((interval.half_width - 3.1824 * std_dev / 2.0).abs() < 1e-2);
+        assert!(interval.lower() < 10.0 && interval.upper() > 10.0);
+    }
+
+    #[test]
+    fn it_runs_all_replications_with_distinct_seeds() {
+        let settings = ReplicationSettings {
+            max_replications: 7,
+            ..ReplicationSettings::default()
+        };
+        let report = run_replications(&settings, noisy_kpis).unwrap();
+        assert_eq!(report.replications.len(), 7);
+        assert!(!report.reached_precision);
+        let seeds: HashSet<u64> = report.replications.iter().map(|r| r.seed).collect();
+        assert_eq!(seeds.len(), 7);
+        assert!(report
+            .replications
+            .iter()
+            .enumerate()
+            .all(|(index, replication)| replication.index == index));
+        let throughput = &report.summary["throughput"];
+        assert_eq!(throughput.count, 7);
+        assert!(throughput.lower() < 10.5 && throughput.upper() > 10.5);
+    }
+
+    #[test]
+    fn parallel_and_sequential_runs_agree() {
+        let sequential = ReplicationSettings {
+            max_replications: 8,
+            parallelism: 1,
+            ..ReplicationSettings::default()
+        };
+        let parallel = ReplicationSettings {
+            parallelism: 4,
+            ..sequential.clone()
+        };
+        assert_eq!(
+            run_replications(&sequential, noisy_kpis).unwrap(),
+            run_replications(&parallel, noisy_kpis).unwrap()
+        );
+    }
+
+    #[test]
+    fn it_stops_when_precision_is_reached() {
+        let settings = ReplicationSettings {
+            max_replications: 1000,
+            min_replications: 5,
+            target_relative_precision: Some(0.01),
+            parallelism: 1,
+            ..ReplicationSettings::default()
+        };
+        let report = run_replications(&settings, noisy_kpis).unwrap();
+        assert!(report.reached_precision);
+        assert!(report.replications.len() < 1000);
+        assert!(report
+            .summary
+            .values()
+            .all(|interval| interval.relative_precision() <= 0.01));
+    }
+
+    #[test]
+    fn it_replicates_an_environment() {
+        let settings = ReplicationSettings {
+            max_replications: 3,
+            ..ReplicationSettings::default()
+        };
+        let report = run_replications(&settings, |seed| {
+            let mut environment =
+                InfiniteEmptyEnvironment::new(|_: &str| {}, |_: Duration| async {});
+            run_to_completion(
+                &mut environment,
+                EmptyEnvironmentSettings::new(3, 0, 100, 300).with_seed(seed),
+            )?;
+            let mut kpis = Kpis::new();
+            kpis.insert("events".to_string(), environment.report() as f64);
+            Ok(kpis)
+        })
+        .unwrap();
+        assert_eq!(report.summary["events"].mean, 300.0);
+        assert_eq!(report.summary["events"].half_width, 0.0);
+    }
+}
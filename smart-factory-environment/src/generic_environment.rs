@@ -0,0 +1,419 @@
+use crate::agent::{Agent, AsyncAgent, BoxedAgent};
+use crate::channel::OutgoingStream;
+use crate::environment::{AgentEnvironment, TracedEnvironment};
+use crate::error::EventEngineError;
+use crate::event::{Event, InjectedEventArg};
+use crate::introspection::{AgentQuery, AgentSnapshot};
+use crate::message::{IncomingQueueMessage, InjectAt};
+use crate::observer::Observer;
+use crate::pacing::Pacing;
+use crate::simulation::{snapshot_agents, Simulation, SimulationBuilder, SimulationSettings};
+use crate::statistics::Statistics;
+use crate::trace::{TraceRecorder, TraceReplay};
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use uuid::Uuid;
+
+/// Environment running any set of agents, e.g. the agents of a model that needs no setup of its
+/// own. Agents keep their state between runs, initial events are used by the next run only.
+///
+/// Every run builds a `Simulation`, so runs are checked like `SimulationBuilder::build` checks
+/// them and fail with `EventEngineError::InvalidSimulation`.
+pub struct GenericEnvironment<LogFunction, SleepFunction, SleepFuture>
+where
+    LogFunction: FnMut(&str),
+    SleepFunction: Fn(std::time::Duration) -> SleepFuture,
+    SleepFuture: Future<Output = ()>,
+{
+    /// Set up of the next run, `None` while `simulation` holds the agents.
+    builder: Option<SimulationBuilder<LogFunction, SleepFunction>>,
+    /// Simulation of the last run, turned back into a builder when the set up changes.
+    simulation: Option<Simulation<LogFunction, SleepFunction>>,
+    outgoing: Option<OutgoingStream>,
+    statistics: Statistics,
+    sleep_future: PhantomData<fn() -> SleepFuture>,
+}
+
+impl<LogFunction, SleepFunction, SleepFuture> AgentEnvironment
+    for GenericEnvironment<LogFunction, SleepFunction, SleepFuture>
+where
+    LogFunction: FnMut(&str) + std::marker::Send,
+    SleepFunction: Fn(std::time::Duration) -> SleepFuture,
+    SleepFuture: Future<Output = ()>,
+{
+    type LogFunction = LogFunction;
+    type SleepFunction = SleepFunction;
+    type SleepFuture = SleepFuture;
+    type TEnvironmentSettings = SimulationSettings;
+
+    fn new(log: LogFunction, sleep: SleepFunction) -> Self {
+        Self {
+            builder: Some(SimulationBuilder::new().with_log(log).with_sleep(sleep)),
+            simulation: None,
+            outgoing: None,
+            statistics: Statistics::new(),
+            sleep_future: PhantomData,
+        }
+    }
+
+    fn run(
+        &mut self,
+        settings: SimulationSettings,
+    ) -> Pin<Box<dyn Future<Output = Result<(), EventEngineError>> + '_>> {
+        let builder = self
+            .take_builder()
+            .with_settings(settings)
+            .with_statistics(self.statistics.clone());
+        if let Err(errors) = builder.validate() {
+            self.builder = Some(builder);
+            return Box::pin(async { Err(EventEngineError::InvalidSimulation(errors)) });
+        }
+        let simulation = self.simulation.insert(builder.build_validated());
+        simulation.log("Starting");
+        self.outgoing = simulation.take_outgoing();
+        Box::pin(simulation.run())
+    }
+
+    fn halt(&mut self) {
+        self.send("Halting", IncomingQueueMessage::Halt);
+    }
+
+    fn change_sleep_time(&mut self, time_ms: u64) {
+        self.send(
+            "Changing sleep time",
+            IncomingQueueMessage::ChangeSleepDurationMs(time_ms),
+        );
+    }
+
+    fn change_sleep_iter_count(&mut self, count: u64) {
+        self.send(
+            "Changing sleep iter count",
+            IncomingQueueMessage::ChangeSleepIterCount(count),
+        );
+    }
+
+    fn change_max_iter_count(&mut self, count: u64) {
+        self.send(
+            "Changing max iter count",
+            IncomingQueueMessage::ChangeMaxIter(count),
+        );
+    }
+
+    fn change_pacing(&mut self, pacing: Pacing) {
+        self.send(
+            "Changing pacing",
+            IncomingQueueMessage::ChangePacing(pacing),
+        );
+    }
+
+    fn query_agents(&mut self, query: AgentQuery) {
+        self.send("Querying agents", IncomingQueueMessage::QueryAgents(query));
+    }
+
+    fn inject_event(&mut self, target: Uuid, at: InjectAt, args: InjectedEventArg) {
+        self.send(
+            "Injecting event",
+            IncomingQueueMessage::InjectEvent { target, at, args },
+        );
+    }
+
+    fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.update(|builder| builder.with_observer(observer));
+    }
+}
+
+impl<LogFunction, SleepFunction, SleepFuture>
+    GenericEnvironment<LogFunction, SleepFunction, SleepFuture>
+where
+    LogFunction: FnMut(&str),
+    SleepFunction: Fn(std::time::Duration) -> SleepFuture,
+    SleepFuture: Future<Output = ()>,
+{
+    pub fn with_agent(mut self, agent: impl Agent + 'static) -> Self {
+        self.update(|builder| builder.with_agent(agent));
+        self
+    }
+
+    pub fn with_async_agent(mut self, agent: impl AsyncAgent + 'static) -> Self {
+        self.update(|builder| builder.with_async_agent(agent));
+        self
+    }
+
+    /// Agents of different types, e.g. `Box<dyn Agent>` or `BoxedAgent`.
+    pub fn with_agents<Agents>(mut self, agents: Agents) -> Self
+    where
+        Agents: IntoIterator,
+        Agents::Item: Into<BoxedAgent>,
+    {
+        self.update(|builder| builder.with_agents(agents));
+        self
+    }
+
+    /// Initial event of the next run, dispatched at `time`.
+    pub fn add_event(&mut self, event: Event, time: u64) {
+        self.update(|builder| builder.with_event(event, time));
+    }
+
+    pub fn with_event(mut self, event: Event, time: u64) -> Self {
+        self.add_event(event, time);
+        self
+    }
+
+    pub fn agents(&self) -> &[BoxedAgent] {
+        match (&self.simulation, &self.builder) {
+            (Some(simulation), _) => simulation.agents(),
+            (None, Some(builder)) => builder.agents(),
+            (None, None) => &[],
+        }
+    }
+
+    /// Matching agents of the last run, use `query_agents` while it runs.
+    pub fn snapshot_agents(&self, query: &AgentQuery) -> Vec<AgentSnapshot> {
+        snapshot_agents(self.agents(), query)
+    }
+
+    /// Messages of the last run. Messages are kept until the stream is taken, the run fails if
+    /// the taken stream is dropped while it still sends.
+    pub fn take_outgoing(&mut self) -> Option<OutgoingStream> {
+        self.outgoing.take()
+    }
+
+    pub fn get_statistics(&self) -> Statistics {
+        self.statistics.clone()
+    }
+
+    fn take_builder(&mut self) -> SimulationBuilder<LogFunction, SleepFunction> {
+        match self.simulation.take() {
+            Some(simulation) => simulation.into_builder(),
+            None => self
+                .builder
+                .take()
+                .expect("the environment holds either a builder or a simulation"),
+        }
+    }
+
+    fn update(
+        &mut self,
+        change: impl FnOnce(
+            SimulationBuilder<LogFunction, SleepFunction>,
+        ) -> SimulationBuilder<LogFunction, SleepFunction>,
+    ) {
+        let builder = self.take_builder();
+        self.builder = Some(change(builder));
+    }
+
+    fn send(&mut self, action: &str, message: IncomingQueueMessage) {
+        if let Some(simulation) = &mut self.simulation {
+            simulation.log(action);
+            if simulation.controller().send(message).is_err() {
+                simulation.log(&format!("{} failed, the simulation is not running", action));
+            }
+        }
+    }
+}
+
+impl<LogFunction, SleepFunction, SleepFuture> TracedEnvironment
+    for GenericEnvironment<LogFunction, SleepFunction, SleepFuture>
+where
+    LogFunction: FnMut(&str) + std::marker::Send,
+    SleepFunction: Fn(std::time::Duration) -> SleepFuture,
+    SleepFuture: Future<Output = ()>,
+{
+    fn set_trace_recorder(&mut self, recorder: TraceRecorder) {
+        self.update(|builder| builder.with_trace_recorder(recorder));
+    }
+
+    fn take_trace_recorder(&mut self) -> Option<TraceRecorder> {
+        match (&mut self.simulation, &mut self.builder) {
+            (Some(simulation), _) => simulation.take_trace_recorder(),
+            (None, Some(builder)) => builder.take_trace_recorder(),
+            (None, None) => None,
+        }
+    }
+
+    fn set_trace_replay(&mut self, replay: TraceReplay) {
+        self.update(|builder| builder.with_trace_replay(replay));
+    }
+
+    fn take_trace_replay(&mut self) -> Option<TraceReplay> {
+        match (&mut self.simulation, &mut self.builder) {
+            (Some(simulation), _) => simulation.take_trace_replay(),
+            (None, Some(builder)) => builder.take_trace_replay(),
+            (None, None) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::NewEventsVec;
+    use crate::event::EventArg;
+    use crate::experiment::run_to_completion;
+    use crate::golden::{check_trace, GoldenMode};
+    use crate::introspection::Introspect;
+    use crate::message::OutgoingQueueMessage;
+    use crate::observer::FinishReason;
+    use async_trait::async_trait;
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    const SOURCE: Uuid = Uuid::from_u128(1);
+    const STATION: Uuid = Uuid::from_u128(2);
+    const SINK: Uuid = Uuid::from_u128(3);
+
+    /// Releases a job to the station every two ticks.
+    struct Source;
+
+    impl Agent for Source {
+        fn handle(&mut self, time: u64, _args: EventArg) -> NewEventsVec {
+            vec![(Event::new(SOURCE), time + 2), (Event::new(STATION), time)]
+        }
+
+        fn get_id(&self) -> Uuid {
+            SOURCE
+        }
+    }
+
+    struct Station;
+
+    #[async_trait(?Send)]
+    impl AsyncAgent for Station {
+        async fn handle(&mut self, time: u64, _args: EventArg) -> NewEventsVec {
+            vec![(Event::new(SINK), time + 1)]
+        }
+
+        fn get_id(&self) -> Uuid {
+            STATION
+        }
+    }
+
+    #[derive(Default)]
+    struct Sink {
+        finished: u64,
+    }
+
+    impl Agent for Sink {
+        fn handle(&mut self, _time: u64, _args: EventArg) -> NewEventsVec {
+            self.finished += 1;
+            vec![]
+        }
+
+        fn get_id(&self) -> Uuid {
+            SINK
+        }
+
+        fn introspect(&self) -> Option<&dyn Introspect> {
+            Some(self)
+        }
+    }
+
+    impl Introspect for Sink {
+        fn type_name(&self) -> &str {
+            "Sink"
+        }
+
+        fn properties(&self) -> BTreeMap<String, Value> {
+            [("finished".to_string(), Value::from(self.finished))]
+                .into_iter()
+                .collect()
+        }
+    }
+
+    fn line(
+    ) -> GenericEnvironment<fn(&str), crate::pacing::NoSleepFunction, crate::pacing::YieldNow> {
+        GenericEnvironment::without_sleep((|_| {}) as fn(&str))
+            .with_agent(Source)
+            .with_async_agent(Station)
+            .with_agent(Sink::default())
+            .with_event(Event::new(SOURCE), 0)
+    }
+
+    fn settings() -> SimulationSettings {
+        SimulationSettings {
+            stop_time: Some(10),
+            ..SimulationSettings::default()
+        }
+    }
+
+    #[test]
+    fn it_runs_agents_of_different_kinds() {
+        let mut environment = line();
+        assert!(run_to_completion(&mut environment, settings()).is_ok());
+        // Jobs released at 0, 2, .., 8 are finished by 10, the one released at 10 is not
+        let sinks = environment.snapshot_agents(&AgentQuery::TypeName("Sink".to_string()));
+        assert_eq!(sinks.len(), 1);
+        assert_eq!(sinks[0].properties["finished"], Value::from(5));
+        assert_eq!(environment.agents().len(), 3);
+        let mut outgoing = environment.take_outgoing().unwrap();
+        assert!(matches!(
+            outgoing.try_iter().last(),
+            Some(OutgoingQueueMessage::Finished(FinishReason::StopTime))
+        ));
+    }
+
+    #[test]
+    fn it_rejects_invalid_runs_and_keeps_its_agents() {
+        let mut environment = line()
+            .with_agent(Sink::default())
+            .with_event(Event::new(Uuid::from_u128(4)), 0);
+        let settings = SimulationSettings {
+            iter_count: 0,
+            ..settings()
+        };
+        let paths: Vec<String> = match run_to_completion(&mut environment, settings) {
+            Err(EventEngineError::InvalidSimulation(errors)) => {
+                errors.into_iter().map(|error| error.path).collect()
+            }
+            other => panic!("Unexpected result {:?}", other),
+        };
+        assert_eq!(paths, vec!["iter_count", "agents[3]", "events[1].agent"]);
+        assert_eq!(environment.agents().len(), 4);
+        assert!(environment.take_outgoing().is_none());
+    }
+
+    #[test]
+    fn its_agents_keep_their_state_between_runs() {
+        let mut environment = line();
+        assert!(run_to_completion(&mut environment, settings()).is_ok());
+        environment.add_event(Event::new(SOURCE), 11);
+        let settings = SimulationSettings {
+            stop_time: Some(20),
+            ..settings()
+        };
+        assert!(run_to_completion(&mut environment, settings).is_ok());
+        let sinks = environment.snapshot_agents(&AgentQuery::Id(SINK));
+        assert_eq!(sinks[0].properties["finished"], Value::from(10));
+    }
+
+    #[test]
+    fn it_logs_messages_sent_after_the_run() {
+        let logged = Arc::new(Mutex::new(vec![]));
+        let log = {
+            let logged = logged.clone();
+            move |message: &str| logged.lock().unwrap().push(message.to_string())
+        };
+        let mut environment = GenericEnvironment::without_sleep(log)
+            .with_agent(Source)
+            .with_async_agent(Station)
+            .with_agent(Sink::default())
+            .with_event(Event::new(SOURCE), 0);
+        assert!(run_to_completion(&mut environment, settings()).is_ok());
+        environment.halt();
+        assert_eq!(
+            logged.lock().unwrap().last().unwrap(),
+            "Halting failed, the simulation is not running"
+        );
+    }
+
+    #[test]
+    fn its_runs_can_be_checked_against_a_trace() {
+        let golden = std::env::temp_dir()
+            .join(format!("smart-factory-generic-{}", std::process::id()))
+            .join("line.jsonl");
+        assert!(check_trace(&mut line(), settings(), &golden, GoldenMode::Update).is_ok());
+        assert!(check_trace(&mut line(), settings(), &golden, GoldenMode::Compare).is_ok());
+    }
+}
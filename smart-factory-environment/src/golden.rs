@@ -0,0 +1,298 @@
+use crate::environment::TracedEnvironment;
+use crate::error::EventEngineError;
+use crate::experiment::{run_to_completion, Kpis};
+use crate::trace::{TraceRecorder, TraceReplay};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// `UPDATE_GOLDENS=1 cargo test` rewrites golden files instead of comparing against them.
+pub const UPDATE_GOLDENS: &str = "UPDATE_GOLDENS";
+
+/// Relative difference up to which KPIs are considered equal, covers float parsing round-off.
+pub const KPI_TOLERANCE: f64 = 1e-12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GoldenMode {
+    Compare,
+    Update,
+}
+
+impl GoldenMode {
+    /// `Update` if `UPDATE_GOLDENS` is set to anything but an empty string or `0`.
+    pub fn from_env() -> GoldenMode {
+        match std::env::var(UPDATE_GOLDENS) {
+            Ok(value) if !value.is_empty() && value != "0" => GoldenMode::Update,
+            _ => GoldenMode::Compare,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KpiDifference {
+    pub name: String,
+    /// `None` if the golden file has no such KPI or it is not finite.
+    pub expected: Option<f64>,
+    /// `None` if the run did not report the KPI or it is not finite.
+    pub actual: Option<f64>,
+}
+
+#[derive(Debug)]
+pub enum GoldenError {
+    Missing(PathBuf),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Engine(EventEngineError),
+    KpisDiffer(Vec<KpiDifference>),
+}
+
+impl From<std::io::Error> for GoldenError {
+    fn from(error: std::io::Error) -> Self {
+        GoldenError::Io(error)
+    }
+}
+
+impl From<EventEngineError> for GoldenError {
+    fn from(error: EventEngineError) -> Self {
+        GoldenError::Engine(error)
+    }
+}
+
+impl Display for GoldenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GoldenError::Missing(path) => write!(
+                f,
+                "Golden file {} does not exist, run with {}=1 to create it",
+                path.display(),
+                UPDATE_GOLDENS
+            ),
+            GoldenError::Io(error) => write!(f, "Could not access golden file: {}", error),
+            GoldenError::Json(error) => write!(f, "Invalid golden file: {}", error),
+            GoldenError::Engine(EventEngineError::TraceDiverged(divergence)) => write!(
+                f,
+                "{}\nRun with {}=1 if the change is intended",
+                divergence, UPDATE_GOLDENS
+            ),
+            GoldenError::Engine(error) => write!(f, "Simulation failed: {}", error),
+            GoldenError::KpisDiffer(differences) => {
+                write!(f, "KPIs differ from the golden file:")?;
+                for difference in differences {
+                    write!(
+                        f,
+                        "\n  {}:\n    - {:?}\n    + {:?}",
+                        difference.name, difference.expected, difference.actual
+                    )?;
+                }
+                write!(
+                    f,
+                    "\nRun with {}=1 if the change is intended",
+                    UPDATE_GOLDENS
+                )
+            }
+        }
+    }
+}
+
+/// Runs the environment to completion and compares every dispatched event with the golden trace,
+/// or records the golden trace in `GoldenMode::Update`. The seed comes from `settings`, so it has
+/// to stay fixed for the comparison to mean anything.
+pub fn check_trace<Environment: TracedEnvironment>(
+    environment: &mut Environment,
+    settings: Environment::TEnvironmentSettings,
+    golden: &Path,
+    mode: GoldenMode,
+) -> Result<(), GoldenError> {
+    match mode {
+        GoldenMode::Compare => {
+            if !golden.exists() {
+                return Err(GoldenError::Missing(golden.to_path_buf()));
+            }
+            environment.set_trace_replay(TraceReplay::open(golden)?);
+            let result = run_to_completion(environment, settings);
+            let replay = environment.take_trace_replay();
+            result?;
+            replay
+                .expect("Replay is kept by the environment")
+                .finish()?;
+        }
+        GoldenMode::Update => {
+            create_parent(golden)?;
+            environment.set_trace_recorder(TraceRecorder::create(golden)?);
+            let result = run_to_completion(environment, settings);
+            let recorder = environment.take_trace_recorder();
+            result?;
+            recorder
+                .expect("Recorder is kept by the environment")
+                .flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Compares KPIs with a golden JSON file, or writes them to it in `GoldenMode::Update`.
+/// Non-finite values are stored as `null`.
+pub fn check_kpis(kpis: &Kpis, golden: &Path, mode: GoldenMode) -> Result<(), GoldenError> {
+    let actual: BTreeMap<&String, Option<f64>> = kpis
+        .iter()
+        .map(|(name, value)| (name, Some(*value).filter(|value| value.is_finite())))
+        .collect();
+    match mode {
+        GoldenMode::Compare => {
+            if !golden.exists() {
+                return Err(GoldenError::Missing(golden.to_path_buf()));
+            }
+            let expected: BTreeMap<String, Option<f64>> =
+                serde_json::from_str(&std::fs::read_to_string(golden)?)
+                    .map_err(GoldenError::Json)?;
+            let mut names: Vec<&String> = expected.keys().chain(actual.keys().copied()).collect();
+            names.sort();
+            names.dedup();
+            let differences: Vec<KpiDifference> = names
+                .into_iter()
+                .filter_map(|name| {
+                    let expected = expected.get(name).copied();
+                    let actual = actual.get(&name).copied();
+                    let equal = match (expected, actual) {
+                        (Some(Some(expected)), Some(Some(actual))) => {
+                            (expected - actual).abs()
+                                <= KPI_TOLERANCE * expected.abs().max(actual.abs())
+                        }
+                        (Some(None), Some(None)) => true,
+                        _ => false,
+                    };
+                    (!equal).then(|| KpiDifference {
+                        name: name.clone(),
+                        expected: expected.flatten(),
+                        actual: actual.flatten(),
+                    })
+                })
+                .collect();
+            if !differences.is_empty() {
+                return Err(GoldenError::KpisDiffer(differences));
+            }
+        }
+        GoldenMode::Update => {
+            create_parent(golden)?;
+            let mut json = serde_json::to_string_pretty(&actual).map_err(GoldenError::Json)?;
+            json.push('\n');
+            std::fs::write(golden, json)?;
+        }
+    }
+    Ok(())
+}
+
+/// `check_trace` in the mode given by `UPDATE_GOLDENS`, panics with the diff on a mismatch.
+pub fn assert_trace_matches_golden<Environment: TracedEnvironment>(
+    environment: &mut Environment,
+    settings: Environment::TEnvironmentSettings,
+    golden: impl AsRef<Path>,
+) {
+    if let Err(error) = check_trace(
+        environment,
+        settings,
+        golden.as_ref(),
+        GoldenMode::from_env(),
+    ) {
+        panic!("{}", error);
+    }
+}
+
+/// `check_kpis` in the mode given by `UPDATE_GOLDENS`, panics with the diff on a mismatch.
+pub fn assert_kpis_match_golden(kpis: &Kpis, golden: impl AsRef<Path>) {
+    if let Err(error) = check_kpis(kpis, golden.as_ref(), GoldenMode::from_env()) {
+        panic!("{}", error);
+    }
+}
+
+fn create_parent(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::empty_environment::{EmptyEnvironmentSettings, InfiniteEmptyEnvironment};
+    use crate::environment::AgentEnvironment;
+
+    fn golden_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("smart-factory-golden-{}", std::process::id()))
+            .join(name)
+    }
+
+    fn check(seed: u64, golden: &Path, mode: GoldenMode) -> Result<(), GoldenError> {
+        let mut environment = InfiniteEmptyEnvironment::without_sleep(|_: &str| {});
+        check_trace(
+            &mut environment,
+            EmptyEnvironmentSettings::new(2, 0, 100, 10).with_seed(seed),
+            golden,
+            mode,
+        )
+    }
+
+    #[test]
+    fn updated_trace_goldens_match_the_same_seed() {
+        let golden = golden_path("trace.jsonl");
+        assert!(check(1, &golden, GoldenMode::Update).is_ok());
+        assert!(check(1, &golden, GoldenMode::Compare).is_ok());
+
+        let error = check(2, &golden, GoldenMode::Compare).unwrap_err();
+        assert!(matches!(
+            error,
+            GoldenError::Engine(EventEngineError::TraceDiverged(_))
+        ));
+        assert!(error.to_string().contains(UPDATE_GOLDENS));
+    }
+
+    #[test]
+    fn missing_goldens_are_reported() {
+        let error = check(1, &golden_path("missing.jsonl"), GoldenMode::Compare).unwrap_err();
+        assert!(matches!(error, GoldenError::Missing(_)));
+    }
+
+    #[test]
+    fn kpi_goldens_report_every_difference() {
+        let golden = golden_path("kpis.json");
+        let kpis: Kpis = [
+            ("events".to_string(), 10.0),
+            ("lead_time.mean".to_string(), f64::NAN),
+            ("wip.mean".to_string(), 0.25),
+        ]
+        .into_iter()
+        .collect();
+        assert!(check_kpis(&kpis, &golden, GoldenMode::Update).is_ok());
+        assert!(check_kpis(&kpis, &golden, GoldenMode::Compare).is_ok());
+
+        let mut changed = kpis.clone();
+        changed.insert("events".to_string(), 11.0);
+        changed.remove("wip.mean");
+        changed.insert("tardiness.mean".to_string(), 0.0);
+        match check_kpis(&changed, &golden, GoldenMode::Compare) {
+            Err(GoldenError::KpisDiffer(differences)) => assert_eq!(
+                differences,
+                vec![
+                    KpiDifference {
+                        name: "events".to_string(),
+                        expected: Some(10.0),
+                        actual: Some(11.0),
+                    },
+                    KpiDifference {
+                        name: "tardiness.mean".to_string(),
+                        expected: None,
+                        actual: Some(0.0),
+                    },
+                    KpiDifference {
+                        name: "wip.mean".to_string(),
+                        expected: Some(0.25),
+                        actual: None,
+                    },
+                ]
+            ),
+            other => panic!("Expected differences, got {:?}", other),
+        }
+    }
+}
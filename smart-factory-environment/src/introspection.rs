@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// Agent that exposes its state as named properties. Agents opt in by returning themselves from
+/// `Agent::introspect`, agents that do not can not be queried.
+pub trait Introspect {
+    /// Name queries select the agent by, usually the name of the Rust type.
+    fn type_name(&self) -> &str;
+
+    fn properties(&self) -> BTreeMap<String, Value>;
+}
+
+/// State of one agent at the time it was queried.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AgentSnapshot {
+    pub id: Uuid,
+    pub type_name: String,
+    pub properties: BTreeMap<String, Value>,
+}
+
+impl AgentSnapshot {
+    pub fn of(id: Uuid, agent: &dyn Introspect) -> AgentSnapshot {
+        AgentSnapshot {
+            id,
+            type_name: agent.type_name().to_string(),
+            properties: agent.properties(),
+        }
+    }
+}
+
+pub type AgentPredicate = Box<dyn Fn(&AgentSnapshot) -> bool + Send>;
+
+/// Selects agents by their snapshot. In JSON e.g. `"all"`, `{"id": "..."}`,
+/// `{"type_name": "InfiniteLoopAgent"}` or `{"property": {"name": "counter", "value": 3}}`.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentQuery {
+    All,
+    Id(Uuid),
+    TypeName(String),
+    /// Agents with a property equal to `value`.
+    Property {
+        name: String,
+        value: Value,
+    },
+    /// Only usable from Rust, serializing it fails.
+    #[serde(skip)]
+    Predicate(AgentPredicate),
+}
+
+impl AgentQuery {
+    pub fn matches(&self, snapshot: &AgentSnapshot) -> bool {
+        match self {
+            AgentQuery::All => true,
+            AgentQuery::Id(id) => snapshot.id == *id,
+            AgentQuery::TypeName(type_name) => snapshot.type_name == *type_name,
+            AgentQuery::Property { name, value } => snapshot.properties.get(name) == Some(value),
+            AgentQuery::Predicate(predicate) => predicate(snapshot),
+        }
+    }
+
+    /// Snapshots of the matching agents, sorted by id so that answers do not depend on the order
+    /// agents are stored in.
+    pub fn select<'a>(
+        &self,
+        agents: impl IntoIterator<Item = (Uuid, &'a dyn Introspect)>,
+    ) -> Vec<AgentSnapshot> {
+        let mut snapshots: Vec<AgentSnapshot> = agents
+            .into_iter()
+            .filter(|(id, _)| !matches!(self, AgentQuery::Id(wanted) if wanted != id))
+            .map(|(id, agent)| AgentSnapshot::of(id, agent))
+            .filter(|snapshot| self.matches(snapshot))
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.id);
+        snapshots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    struct Machine {
+        broken: bool,
+    }
+
+    impl Introspect for Machine {
+        fn type_name(&self) -> &str {
+            "Machine"
+        }
+
+        fn properties(&self) -> BTreeMap<String, Value> {
+            [("broken".to_string(), json!(self.broken))]
+                .into_iter()
+                .collect()
+        }
+    }
+
+    struct Order;
+
+    impl Introspect for Order {
+        fn type_name(&self) -> &str {
+            "Order"
+        }
+
+        fn properties(&self) -> BTreeMap<String, Value> {
+            BTreeMap::new()
+        }
+    }
+
+    fn select(query: AgentQuery) -> Vec<Uuid> {
+        let agents: Vec<(Uuid, &dyn Introspect)> = vec![
+            (Uuid::from_u128(3), &Machine { broken: true }),
+            (Uuid::from_u128(1), &Machine { broken: false }),
+            (Uuid::from_u128(2), &Order),
+        ];
+        query
+            .select(agents)
+            .into_iter()
+            .map(|snapshot| snapshot.id)
+            .collect()
+    }
+
+    #[test]
+    fn queries_select_by_id_type_and_predicate() {
+        let ids = |ids: &[u128]| {
+            ids.iter()
+                .map(|id| Uuid::from_u128(*id))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(select(AgentQuery::All), ids(&[1, 2, 3]));
+        assert_eq!(select(AgentQuery::Id(Uuid::from_u128(2))), ids(&[2]));
+        assert_eq!(
+            select(AgentQuery::TypeName("Machine".to_string())),
+            ids(&[1, 3])
+        );
+        assert_eq!(
+            select(AgentQuery::Property {
+                name: "broken".to_string(),
+                value: json!(true),
+            }),
+            ids(&[3])
+        );
+        assert_eq!(
+            select(AgentQuery::Predicate(Box::new(|snapshot| {
+                snapshot.properties.is_empty()
+            }))),
+            ids(&[2])
+        );
+    }
+
+    #[test]
+    fn queries_are_read_from_json() {
+        let query: AgentQuery =
+            serde_json::from_str(r#"{"property": {"name": "broken", "value": false}}"#).unwrap();
+        assert_eq!(select(query), vec![Uuid::from_u128(1)]);
+        let query: AgentQuery = serde_json::from_str(r#""all""#).unwrap();
+        assert_eq!(select(query).len(), 3);
+        assert!(serde_json::to_string(&AgentQuery::Predicate(Box::new(|_| true))).is_err());
+    }
+}
@@ -0,0 +1,543 @@
+use crate::orders::Order;
+use crate::statistics::{Statistics, TimeWeighted, WarmUp};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+/// Shared between the agents updating statistics and whoever reads reports during the run.
+pub type SharedProductionStatistics = Rc<RefCell<ProductionStatistics>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MachineState {
+    /// Not planned to work, e.g. outside of shifts. Excluded from OEE availability.
+    Off,
+    /// Planned but waiting for work, counted as OEE performance loss.
+    Idle,
+    Setup,
+    Busy,
+    /// Unplanned downtime.
+    Down,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DistributionSummary {
+    pub count: usize,
+    pub mean: f64,
+    pub min: u64,
+    pub max: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p95: u64,
+}
+
+impl DistributionSummary {
+    fn from_values(mut values: Vec<u64>) -> DistributionSummary {
+        if values.is_empty() {
+            return DistributionSummary::default();
+        }
+        values.sort_unstable();
+        let percentile = |p: f64| values[((values.len() - 1) as f64 * p).round() as usize];
+        DistributionSummary {
+            count: values.len(),
+            mean: values.iter().sum::<u64>() as f64 / values.len() as f64,
+            min: values[0],
+            max: values[values.len() - 1],
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p95: percentile(0.95),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrderPerformance {
+    pub id: u64,
+    pub product: String,
+    pub lead_time: u64,
+    pub flow_time: u64,
+    /// Negative when the order was finished early.
+    pub lateness: i64,
+    pub tardiness: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MachineReport {
+    pub utilization: f64,
+    pub availability: f64,
+    pub performance: f64,
+    pub quality: f64,
+    pub oee: f64,
+    pub parts_produced: u64,
+    pub good_parts: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProductionReport {
+    pub period: u64,
+    pub orders_arrived: u64,
+    pub orders_completed: u64,
+    /// Completed orders per tick.
+    pub throughput: f64,
+    pub lead_time: DistributionSummary,
+    pub flow_time: DistributionSummary,
+    pub current_wip: u64,
+    pub average_wip: f64,
+    pub max_wip: u64,
+    pub mean_tardiness: f64,
+    pub mean_lateness: f64,
+    pub tardy_orders: u64,
+    pub orders: Vec<OrderPerformance>,
+    pub machines: BTreeMap<String, MachineReport>,
+}
+
+struct OpenOrder {
+    order: Order,
+    released_at: u64,
+}
+
+#[derive(Default)]
+struct MachineRecord {
+    state: Option<(MachineState, u64)>,
+    time_in_state: HashMap<MachineState, u64>,
+    parts_produced: u64,
+    good_parts: u64,
+    ideal_production_time: u64,
+}
+
+impl MachineRecord {
+    fn time_in(&self, state: MachineState, now: u64) -> u64 {
+        let recorded = self.time_in_state.get(&state).copied().unwrap_or_default();
+        match self.state {
+            Some((current, since)) if current == state => recorded + now.saturating_sub(since),
+            _ => recorded,
+        }
+    }
+
+    fn report(&self, period_start: u64, now: u64) -> MachineReport {
+        let total = now.saturating_sub(period_start);
+        let busy = self.time_in(MachineState::Busy, now);
+        let planned = total.saturating_sub(self.time_in(MachineState::Off, now));
+        let running = planned.saturating_sub(self.time_in(MachineState::Down, now));
+        let ratio = |numerator: u64, denominator: u64| {
+            if denominator == 0 {
+                0.0
+            } else {
+                numerator as f64 / denominator as f64
+            }
+        };
+        let availability = ratio(running, planned);
+        let performance = ratio(self.ideal_production_time, running);
+        let quality = ratio(self.good_parts, self.parts_produced);
+        MachineReport {
+            utilization: ratio(busy, total),
+            availability,
+            performance,
+            quality,
+            oee: availability * performance * quality,
+            parts_produced: self.parts_produced,
+            good_parts: self.good_parts,
+        }
+    }
+}
+
+enum Update {
+    OrderArrived(Order),
+    OrderReleased(u64),
+    OrderCompleted(u64),
+    MachineState(String, MachineState),
+    PartProduced {
+        machine: String,
+        ideal_cycle_time: u64,
+        good: bool,
+    },
+}
+
+/// Collects order and machine events reported by agents and turns them into production KPIs.
+pub struct ProductionStatistics {
+    period_start: u64,
+    open_orders: HashMap<u64, OpenOrder>,
+    orders_arrived: u64,
+    completed: Vec<OrderPerformance>,
+    wip: TimeWeighted,
+    machines: BTreeMap<String, MachineRecord>,
+    // Updates kept until an MSER-5 reset, which happens after the truncation point
+    history: Option<Vec<(u64, Update)>>,
+}
+
+impl Default for ProductionStatistics {
+    fn default() -> Self {
+        ProductionStatistics::new(0)
+    }
+}
+
+impl ProductionStatistics {
+    pub fn new(start_time: u64) -> ProductionStatistics {
+        ProductionStatistics {
+            period_start: start_time,
+            open_orders: HashMap::new(),
+            orders_arrived: 0,
+            completed: vec![],
+            wip: TimeWeighted::new(start_time, 0.0),
+            machines: BTreeMap::new(),
+            history: None,
+        }
+    }
+
+    pub fn shared(start_time: u64) -> SharedProductionStatistics {
+        Rc::new(RefCell::new(ProductionStatistics::new(start_time)))
+    }
+
+    pub fn order_arrived(&mut self, order: &Order, time: u64) {
+        self.remember(time, || Update::OrderArrived(order.clone()));
+        self.orders_arrived += 1;
+        self.open_orders.insert(
+            order.id,
+            OpenOrder {
+                order: order.clone(),
+                released_at: time,
+            },
+        );
+        self.wip.set(time, self.current_wip() as f64);
+    }
+
+    /// Marks the moment an order enters production. Flow time is measured from here,
+    /// lead time from the order creation.
+    pub fn order_released(&mut self, order_id: u64, time: u64) {
+        self.remember(time, || Update::OrderReleased(order_id));
+        if let Some(open) = self.open_orders.get_mut(&order_id) {
+            open.released_at = time;
+        }
+    }
+
+    pub fn order_completed(&mut self, order_id: u64, time: u64) {
+        self.remember(time, || Update::OrderCompleted(order_id));
+        if let Some(open) = self.open_orders.remove(&order_id) {
+            self.wip.set(time, self.current_wip() as f64);
+            let lateness = time as i64 - open.order.due_date as i64;
+            self.completed.push(OrderPerformance {
+                id: order_id,
+                product: open.order.product,
+                lead_time: time.saturating_sub(open.order.created_at),
+                flow_time: time.saturating_sub(open.released_at),
+                lateness,
+                tardiness: lateness.max(0) as u64,
+            });
+        }
+    }
+
+    pub fn machine_state(&mut self, machine: &str, state: MachineState, time: u64) {
+        self.remember(time, || Update::MachineState(machine.to_string(), state));
+        let record = self.machines.entry(machine.to_string()).or_default();
+        if let Some((previous, since)) = record.state {
+            *record.time_in_state.entry(previous).or_default() += time.saturating_sub(since);
+        }
+        record.state = Some((state, time));
+    }
+
+    /// `ideal_cycle_time` is the theoretical fastest time to produce the part, used for OEE performance.
+    pub fn part_produced(&mut self, machine: &str, time: u64, ideal_cycle_time: u64, good: bool) {
+        self.remember(time, || Update::PartProduced {
+            machine: machine.to_string(),
+            ideal_cycle_time,
+            good,
+        });
+        let record = self.machines.entry(machine.to_string()).or_default();
+        record.parts_produced += 1;
+        record.ideal_production_time += ideal_cycle_time;
+        if good {
+            record.good_parts += 1;
+        }
+    }
+
+    /// Discards everything collected before `time`, orders still in progress are kept. `time` may
+    /// lie in the past while updates are kept, see `keep_history`.
+    pub fn reset(&mut self, time: u64) {
+        if let Some(history) = self.history.take() {
+            let mut replayed = ProductionStatistics::new(self.period_start);
+            let mut was_reset = false;
+            for (update_time, update) in &history {
+                if !was_reset && *update_time >= time {
+                    replayed.discard_before(time);
+                    was_reset = true;
+                }
+                replayed.replay(*update_time, update);
+            }
+            if !was_reset {
+                replayed.discard_before(time);
+            }
+            *self = replayed;
+        } else {
+            self.discard_before(time);
+        }
+    }
+
+    fn discard_before(&mut self, time: u64) {
+        self.period_start = time;
+        self.orders_arrived = 0;
+        self.completed.clear();
+        self.wip.reset(time);
+        for record in self.machines.values_mut() {
+            *record = MachineRecord {
+                state: record.state.map(|(state, since)| (state, since.max(time))),
+                ..MachineRecord::default()
+            };
+        }
+    }
+
+    /// Keeps every update until the next reset, so that it can go back to an earlier time.
+    pub fn keep_history(&mut self, keep: bool) {
+        self.history = keep.then(Vec::new);
+    }
+
+    /// Resets the shared statistics together with `statistics`, e.g. at the end of the warm-up.
+    /// Under `WarmUp::Mser5` updates are kept until the truncation point is fixed.
+    pub fn reset_with(shared: &SharedProductionStatistics, statistics: &Statistics) {
+        let listener = shared.clone();
+        statistics.add_warm_up_listener(move |warm_up| {
+            listener
+                .borrow_mut()
+                .keep_history(matches!(warm_up, WarmUp::Mser5(_)))
+        });
+        let listener = shared.clone();
+        statistics.add_settle_listener(move || listener.borrow_mut().keep_history(false));
+        let shared = shared.clone();
+        statistics.add_reset_listener(move |time| shared.borrow_mut().reset(time));
+    }
+
+    fn remember(&mut self, time: u64, update: impl FnOnce() -> Update) {
+        if let Some(history) = self.history.as_mut() {
+            history.push((time, update()));
+        }
+    }
+
+    fn replay(&mut self, time: u64, update: &Update) {
+        match update {
+            Update::OrderArrived(order) => self.order_arrived(order, time),
+            Update::OrderReleased(order_id) => self.order_released(*order_id, time),
+            Update::OrderCompleted(order_id) => self.order_completed(*order_id, time),
+            Update::MachineState(machine, state) => self.machine_state(machine, *state, time),
+            Update::PartProduced {
+                machine,
+                ideal_cycle_time,
+                good,
+            } => self.part_produced(machine, time, *ideal_cycle_time, *good),
+        }
+    }
+
+    pub fn current_wip(&self) -> u64 {
+        self.open_orders.len() as u64
+    }
+
+    pub fn report(&self, now: u64) -> ProductionReport {
+        let period = now.saturating_sub(self.period_start);
+        let wip = self.wip.summary(now);
+        let completed = self.completed.len() as u64;
+        let mean = |values: &mut dyn Iterator<Item = f64>| {
+            if completed == 0 {
+                0.0
+            } else {
+                values.sum::<f64>() / completed as f64
+            }
+        };
+        ProductionReport {
+            period,
+            orders_arrived: self.orders_arrived,
+            orders_completed: completed,
+            throughput: if period == 0 {
+                0.0
+            } else {
+                completed as f64 / period as f64
+            },
+            lead_time: DistributionSummary::from_values(
+                self.completed.iter().map(|order| order.lead_time).collect(),
+            ),
+            flow_time: DistributionSummary::from_values(
+                self.completed.iter().map(|order| order.flow_time).collect(),
+            ),
+            current_wip: self.current_wip(),
+            average_wip: wip.mean,
+            max_wip: wip.max as u64,
+            mean_tardiness: mean(&mut self.completed.iter().map(|order| order.tardiness as f64)),
+            mean_lateness: mean(&mut self.completed.iter().map(|order| order.lateness as f64)),
+            tardy_orders: self
+                .completed
+                .iter()
+                .filter(|order| order.tardiness > 0)
+                .count() as u64,
+            orders: self.completed.clone(),
+            machines: self
+                .machines
+                .iter()
+                .map(|(name, record)| (name.clone(), record.report(self.period_start, now)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(id: u64, created_at: u64, due_date: u64) -> Order {
+        Order {
+            id,
+            product: "A".to_string(),
+            created_at,
+            due_date,
+        }
+    }
+
+    #[test]
+    fn it_computes_order_kpis() {
+        let mut statistics = ProductionStatistics::new(0);
+        statistics.order_arrived(&order(1, 0, 10), 0);
+        statistics.order_arrived(&order(2, 2, 5), 2);
+        statistics.order_released(2, 4);
+        statistics.order_completed(2, 8);
+        statistics.order_completed(1, 9);
+        statistics.order_arrived(&order(3, 9, 20), 9);
+
+        let report = statistics.report(10);
+        assert_eq!(report.orders_arrived, 3);
+        assert_eq!(report.orders_completed, 2);
+        assert_eq!(report.throughput, 0.2);
+        assert_eq!(report.lead_time.min, 6);
+        assert_eq!(report.lead_time.max, 9);
+        assert_eq!(report.lead_time.mean, 7.5);
+        assert_eq!(report.flow_time.min, 4);
+        assert_eq!(report.tardy_orders, 1);
+        assert_eq!(report.mean_tardiness, 1.5);
+        assert_eq!(report.mean_lateness, 1.0);
+        assert_eq!(report.orders[0].lateness, 3);
+        assert_eq!(report.orders[1].lateness, -1);
+        // 1 order for [0, 2), 2 for [2, 8), 1 for [8, 9), 1 for [9, 10)
+        assert_eq!(report.average_wip, 1.6);
+        assert_eq!(report.current_wip, 1);
+        assert_eq!(report.max_wip, 2);
+    }
+
+    #[test]
+    fn it_computes_machine_oee() {
+        let mut statistics = ProductionStatistics::new(0);
+        statistics.machine_state("M1", MachineState::Off, 0);
+        statistics.machine_state("M1", MachineState::Busy, 20);
+        for i in 0..4 {
+            statistics.part_produced("M1", 20 + 10 * i, 10, true);
+        }
+        statistics.part_produced("M1", 70, 10, false);
+        statistics.machine_state("M1", MachineState::Down, 80);
+        statistics.machine_state("M1", MachineState::Idle, 100);
+
+        let report = statistics.report(120);
+        let machine = report.machines.get("M1").unwrap();
+        assert_eq!(machine.utilization, 0.5);
+        // 100 ticks planned, 20 of them down, idle time lowers performance only
+        assert_eq!(machine.availability, 0.8);
+        assert_eq!(machine.performance, 50.0 / 80.0);
+        assert_eq!(machine.quality, 0.8);
+        assert!((machine.oee - 0.4).abs() < 1e-12);
+        assert_eq!(machine.parts_produced, 5);
+    }
+
+    #[test]
+    fn live_report_includes_running_state() {
+        let statistics = ProductionStatistics::shared(0);
+        statistics
+            .borrow_mut()
+            .machine_state("M1", MachineState::Busy, 0);
+        assert_eq!(
+            statistics.borrow().report(10).machines["M1"].utilization,
+            1.0
+        );
+        statistics
+            .borrow_mut()
+            .machine_state("M1", MachineState::Idle, 10);
+        assert_eq!(
+            statistics.borrow().report(20).machines["M1"].utilization,
+            0.5
+        );
+    }
+
+    #[test]
+    fn empty_report_has_no_nans() {
+        let report = ProductionStatistics::new(0).report(0);
+        assert_eq!(report.throughput, 0.0);
+        assert_eq!(report.mean_tardiness, 0.0);
+        assert_eq!(report.lead_time, DistributionSummary::default());
+    }
+
+    #[test]
+    fn reset_discards_warm_up() {
+        let shared = ProductionStatistics::shared(0);
+        let statistics = Statistics::new();
+        ProductionStatistics::reset_with(&shared, &statistics);
+        {
+            let mut production = shared.borrow_mut();
+            production.order_arrived(&order(1, 0, 10), 0);
+            production.order_arrived(&order(2, 0, 10), 0);
+            production.order_completed(1, 5);
+            production.machine_state("M1", MachineState::Idle, 0);
+            production.machine_state("M1", MachineState::Busy, 5);
+            production.part_produced("M1", 5, 5, true);
+        }
+        statistics.reset(10);
+        shared.borrow_mut().order_completed(2, 15);
+
+        let report = shared.borrow().report(20);
+        assert_eq!(report.period, 10);
+        assert_eq!(report.orders_completed, 1);
+        assert_eq!(report.orders[0].id, 2);
+        assert_eq!(report.average_wip, 0.5);
+        assert_eq!(report.machines["M1"].utilization, 1.0);
+        assert_eq!(report.machines["M1"].parts_produced, 0);
+    }
+
+    #[test]
+    fn mser5_reset_goes_back_to_the_truncation_point() {
+        let shared = ProductionStatistics::shared(0);
+        let statistics = Statistics::new();
+        ProductionStatistics::reset_with(&shared, &statistics);
+        statistics.configure_warm_up(WarmUp::Mser5("lead time".to_string()));
+        // Orders take 5 ticks, the first 100 of them are reported with a long lead time
+        for time in 0..305u64 {
+            statistics.advance(time);
+            let mut production = shared.borrow_mut();
+            if time < 300 {
+                production.order_arrived(&order(time, time, time + 10), time);
+            }
+            if time >= 5 {
+                production.order_completed(time - 5, time);
+                statistics.record("lead time", if time < 105 { 50.0 } else { 5.0 });
+            }
+        }
+        statistics.finish_warm_up();
+
+        let report = shared.borrow().report(405);
+        assert_eq!(statistics.report(405).truncation_point, Some(105));
+        assert_eq!(report.period, 300);
+        assert_eq!(report.orders_arrived, 195);
+        assert_eq!(report.orders_completed, 200);
+        assert_eq!(report.orders[0].id, 100);
+    }
+
+    #[test]
+    fn mser5_without_truncation_stops_keeping_updates() {
+        let shared = ProductionStatistics::shared(0);
+        let statistics = Statistics::new();
+        ProductionStatistics::reset_with(&shared, &statistics);
+        statistics.configure_warm_up(WarmUp::Mser5("lead time".to_string()));
+        for time in 0..50u64 {
+            statistics.advance(time);
+            shared
+                .borrow_mut()
+                .order_arrived(&order(time, time, time + 10), time);
+            statistics.record("lead time", 5.0);
+        }
+        assert!(shared.borrow().history.is_some());
+        statistics.finish_warm_up();
+
+        assert_eq!(statistics.report(50).truncation_point, None);
+        assert!(shared.borrow().history.is_none());
+        assert_eq!(shared.borrow().report(50).orders_arrived, 50);
+    }
+}
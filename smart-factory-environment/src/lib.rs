@@ -1,9 +1,29 @@
 pub mod agent;
+pub mod calendar;
+pub mod channel;
+pub mod doe;
 pub mod empty_environment;
 pub mod environment;
-mod event;
+pub mod error;
+pub mod event;
 mod event_queue;
+pub mod experiment;
+pub mod generic_environment;
+pub mod golden;
+pub mod introspection;
+pub mod kpi;
+pub mod logging;
 pub mod message;
+pub mod observer;
+pub mod orders;
+pub mod pacing;
+pub mod rng;
+pub mod scenario;
+pub mod simulation;
+pub mod statistics;
+pub mod trace;
+
+pub use error::EventEngineError;
 
 //FIXME: temporary function for testing purposes. Remove when smart-factory-server finally has relevant tests
 pub fn greet_message(name: &str) -> String {
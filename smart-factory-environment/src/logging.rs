@@ -0,0 +1,226 @@
+use std::fmt::Write;
+use std::sync::Mutex;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
+
+/// Passes `tracing` records to a plain log callback, e.g. the browser console of the WASM port.
+/// Records are formatted as `LEVEL target: span{field=value}: message field=value`.
+pub struct LogCallbackLayer<LogFunction> {
+    log: Mutex<LogFunction>,
+    max_level: LevelFilter,
+}
+
+impl<LogFunction> LogCallbackLayer<LogFunction>
+where
+    LogFunction: FnMut(&str) + Send + 'static,
+{
+    /// Forwards records up to `INFO`.
+    pub fn new(log: LogFunction) -> LogCallbackLayer<LogFunction> {
+        LogCallbackLayer {
+            log: Mutex::new(log),
+            max_level: LevelFilter::INFO,
+        }
+    }
+
+    pub fn with_max_level(mut self, max_level: LevelFilter) -> LogCallbackLayer<LogFunction> {
+        self.max_level = max_level;
+        self
+    }
+}
+
+/// Fields of a span, formatted once when the span is created.
+struct SpanFields(String);
+
+#[derive(Default)]
+struct FieldFormatter {
+    message: String,
+    fields: String,
+}
+
+impl Visit for FieldFormatter {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.fields, " {}={}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+impl<S, LogFunction> Layer<S> for LogCallbackLayer<LogFunction>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    LogFunction: FnMut(&str) + Send + 'static,
+{
+    fn enabled(&self, metadata: &tracing::Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        *metadata.level() <= self.max_level
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.max_level)
+    }
+
+    fn on_new_span(&self, attributes: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut formatter = FieldFormatter::default();
+        attributes.record(&mut formatter);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(formatter.fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let mut formatter = FieldFormatter::default();
+        values.record(&mut formatter);
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+                fields.0.push_str(&formatter.fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut line = format!("{} {}: ", metadata.level(), metadata.target());
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                let fields = extensions
+                    .get::<SpanFields>()
+                    .map(|fields| fields.0.trim_start())
+                    .unwrap_or_default();
+                let _ = write!(line, "{}{{{}}}: ", span.name(), fields);
+            }
+        }
+        let mut formatter = FieldFormatter::default();
+        event.record(&mut formatter);
+        line.push_str(&formatter.message);
+        line.push_str(&formatter.fields);
+        if let Ok(mut log) = self.log.lock() {
+            (log)(&line);
+        }
+    }
+}
+
+/// Subscriber that only forwards records to `log`, see [`LogCallbackLayer`].
+/// Install it with `tracing::subscriber::set_global_default`.
+pub fn log_callback_subscriber<LogFunction>(
+    log: LogFunction,
+    max_level: LevelFilter,
+) -> impl Subscriber + Send + Sync
+where
+    LogFunction: FnMut(&str) + Send + 'static,
+{
+    Registry::default().with(LogCallbackLayer::new(log).with_max_level(max_level))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, NewEventsVec};
+    use crate::empty_environment::{EmptyEnvironmentSettings, InfiniteEmptyEnvironment};
+    use crate::environment::AgentEnvironment;
+    use crate::event::{Event, EventArg};
+    use crate::simulation::SimulationBuilder;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    fn capture(max_level: LevelFilter) -> Vec<String> {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let sink = lines.clone();
+        let subscriber = log_callback_subscriber(
+            move |line: &str| sink.lock().unwrap().push(line.to_string()),
+            max_level,
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            let mut environment = InfiniteEmptyEnvironment::without_sleep(|_: &str| {});
+            let run = futures::executor::block_on(
+                environment.run(EmptyEnvironmentSettings::new(1, 0, 2, 3).with_seed(7)),
+            );
+            assert!(run.is_ok());
+        });
+        let lines = lines.lock().unwrap().clone();
+        lines
+    }
+
+    #[test]
+    fn records_carry_levels_spans_and_fields() {
+        let lines = capture(LevelFilter::DEBUG);
+        assert!(lines.contains(
+            &"INFO smart_factory_environment::event_queue: run{seed=7}: Started agent_count=1 max_iter=3"
+                .to_string()
+        ));
+        assert!(lines.contains(
+            &"DEBUG smart_factory_environment::event_queue: run{seed=7}: Entered sleep processed=2 duration_ms=0"
+                .to_string()
+        ));
+        assert!(lines.contains(
+            &"INFO smart_factory_environment::event_queue: run{seed=7}: Finished reason=MaxIter events=3"
+                .to_string()
+        ));
+        assert!(!lines.iter().any(|line| line.starts_with("TRACE")));
+        // The environment reports "Starting" to its log function only
+        assert!(!lines.iter().any(|line| line.ends_with(": Starting")));
+    }
+
+    #[test]
+    fn records_of_agents_are_inside_the_run_span() {
+        struct Loud(Uuid);
+
+        impl Agent for Loud {
+            fn handle(&mut self, time: u64, _args: EventArg) -> NewEventsVec {
+                tracing::info!(time, "Handled");
+                vec![]
+            }
+
+            fn get_id(&self) -> Uuid {
+                self.0
+            }
+        }
+
+        let lines = Arc::new(Mutex::new(vec![]));
+        let sink = lines.clone();
+        let subscriber = log_callback_subscriber(
+            move |line: &str| sink.lock().unwrap().push(line.to_string()),
+            LevelFilter::INFO,
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            let id = Uuid::from_u128(1);
+            let mut simulation = SimulationBuilder::new()
+                .with_agent(Loud(id))
+                .with_event(Event::new(id), 4)
+                .with_seed(3)
+                .build()
+                .unwrap();
+            assert!(simulation.run_to_completion().is_ok());
+        });
+        assert!(lines.lock().unwrap().contains(
+            &"INFO smart_factory_environment::logging::tests: run{seed=3}: Handled time=4"
+                .to_string()
+        ));
+    }
+
+    #[test]
+    fn dispatches_are_traced_with_time_and_ids() {
+        let lines = capture(LevelFilter::TRACE);
+        let dispatches: Vec<&String> = lines
+            .iter()
+            .filter(|line| line.contains("Dispatching event"))
+            .collect();
+        assert_eq!(dispatches.len(), 3);
+        assert!(dispatches[2].contains("sequence=2 time=2 agent="));
+        assert!(dispatches[2].contains(" event="));
+    }
+}
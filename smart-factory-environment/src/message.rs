@@ -1,11 +1,151 @@
+use crate::error::InjectionError;
+use crate::event::InjectedEventArg;
+use crate::introspection::{AgentQuery, AgentSnapshot};
+use crate::observer::FinishReason;
+use crate::pacing::Pacing;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use uuid::Uuid;
+use web_time::Instant;
+
 pub enum IncomingQueueMessage {
     Halt,
+    /// Rejected with `OutgoingQueueMessage::EventRejected` if the count is 0.
     ChangeSleepIterCount(u64),
     ChangeSleepDurationMs(u64),
     ChangeMaxIter(u64),
+    ChangePacing(Pacing),
+    /// Answered with `OutgoingQueueMessage::Agents` before the next event is dispatched.
+    QueryAgents(AgentQuery),
+    /// Schedules an event from outside the simulation, e.g. a rush order entered in the UI.
+    /// Rejected with `OutgoingQueueMessage::EventRejected` if `target` is not an agent of the run
+    /// or the time lies before the last dispatched event.
+    InjectEvent {
+        target: Uuid,
+        at: InjectAt,
+        args: InjectedEventArg,
+    },
+}
+
+/// When an injected event is dispatched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InjectAt {
+    Time(u64),
+    /// After the time of the last dispatched event.
+    Delay(u64),
 }
 
 pub enum OutgoingQueueMessage {
     Started,
     Iter(u64),
+    /// Sent at the rate given by `EnvironmentSettings::get_progress_rate` and once more at the end.
+    Progress(Progress),
+    /// The engine sleeps for pacing. Only sent when progress messages are enabled.
+    Paused(Duration),
+    Finished(FinishReason),
+    /// Answer to `IncomingQueueMessage::QueryAgents`.
+    Agents(Vec<AgentSnapshot>),
+    /// An `IncomingQueueMessage::InjectEvent` was not scheduled or a
+    /// `ChangeSleepIterCount(0)` was ignored.
+    EventRejected(InjectionError),
+    /// The run stopped with an error, its display representation.
+    Error(String),
+}
+
+/// How often the engine sends progress messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressRate {
+    #[default]
+    Never,
+    /// After every `n` dispatched events.
+    Events(u64),
+    /// At most every `n` milliseconds of real time.
+    Millis(u64),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    /// Simulated time of the last dispatched event.
+    pub time: u64,
+    pub events: u64,
+    pub queue_length: usize,
+    /// Since the previous progress message.
+    pub events_per_second: f64,
+    /// Agents whose properties changed since the previous progress message.
+    pub changed_agents: Vec<AgentStateChange>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AgentStateChange {
+    pub agent: Uuid,
+    /// All properties of the agent, see `Introspect::properties`.
+    pub properties: BTreeMap<String, Value>,
+}
+
+pub(crate) struct ProgressTracker {
+    rate: ProgressRate,
+    last_events: u64,
+    last_report: Instant,
+    properties: HashMap<Uuid, BTreeMap<String, Value>>,
+}
+
+impl ProgressTracker {
+    pub(crate) fn new(rate: ProgressRate) -> ProgressTracker {
+        ProgressTracker {
+            rate,
+            last_events: 0,
+            last_report: Instant::now(),
+            properties: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.rate != ProgressRate::Never
+    }
+
+    /// Whether a progress message is due after `events` were dispatched.
+    pub(crate) fn is_due(&self, events: u64) -> bool {
+        match self.rate {
+            ProgressRate::Never => false,
+            ProgressRate::Events(every) => events.is_multiple_of(every.max(1)),
+            ProgressRate::Millis(millis) => {
+                self.last_report.elapsed() >= Duration::from_millis(millis)
+            }
+        }
+    }
+
+    pub(crate) fn report(
+        &mut self,
+        time: u64,
+        events: u64,
+        queue_length: usize,
+        agents: impl Iterator<Item = (Uuid, BTreeMap<String, Value>)>,
+    ) -> Progress {
+        let elapsed = self.last_report.elapsed().as_secs_f64();
+        let events_per_second = if elapsed > 0.0 {
+            (events - self.last_events) as f64 / elapsed
+        } else {
+            0.0
+        };
+        let mut changed_agents = vec![];
+        for (agent, properties) in agents {
+            if self.properties.get(&agent) != Some(&properties) {
+                self.properties.insert(agent, properties.clone());
+                changed_agents.push(AgentStateChange { agent, properties });
+            }
+        }
+        changed_agents.sort_by_key(|change| change.agent);
+        self.last_events = events;
+        self.last_report = Instant::now();
+        Progress {
+            time,
+            events,
+            queue_length,
+            events_per_second,
+            changed_agents,
+        }
+    }
 }
@@ -0,0 +1,240 @@
+use crate::error::EventEngineError;
+use crate::event::EventArgs;
+use crate::pacing::Pause;
+use std::cell::RefCell;
+use std::rc::Rc;
+use uuid::Uuid;
+
+/// Event the engine is dispatching.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dispatch {
+    /// Number of events dispatched before this one.
+    pub sequence: u64,
+    pub time: u64,
+    /// `None` for initial events.
+    pub sender: Option<Uuid>,
+    pub target: Uuid,
+}
+
+/// Event scheduled by the handler of a dispatched event.
+#[derive(Clone, Copy)]
+pub struct Produced<'a> {
+    pub target: Uuid,
+    pub time: u64,
+    pub args: Option<&'a dyn EventArgs>,
+}
+
+/// Why a run stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FinishReason {
+    /// No events are left.
+    QueueEmpty,
+    /// The maximum number of events was dispatched.
+    MaxIter,
+    /// The next event lies after the stop time, see `EnvironmentSettings::get_stop_time`.
+    StopTime,
+    Halted,
+    /// The engine returned an error.
+    Failed,
+}
+
+/// Hooks called by the engine while it runs. Every hook does nothing by default, so observers
+/// only implement what they need. Observers only watch, they can not change the run.
+pub trait Observer {
+    fn on_start(&mut self) {}
+
+    /// Called for every agent when the engine takes it over, after `on_start`.
+    fn on_agent_spawn(&mut self, _agent: Uuid) {}
+
+    fn before_dispatch(&mut self, _dispatch: &Dispatch, _args: Option<&dyn EventArgs>) {}
+
+    fn after_dispatch(&mut self, _dispatch: &Dispatch, _produced: &[Produced]) {}
+
+    /// Called before the engine sleeps or yields, `processed` events were dispatched so far.
+    fn on_pause(&mut self, _processed: u64, _pause: Pause) {}
+
+    /// Called for errors the run continues after, see `ErrorPolicy`.
+    fn on_error(&mut self, _error: &EventEngineError) {}
+
+    /// Called for every agent once the run stopped, before `on_finish`, and for agents retired
+    /// by `ErrorPolicy::RetireAgent`.
+    fn on_agent_retire(&mut self, _agent: Uuid) {}
+
+    fn on_finish(&mut self, _reason: FinishReason) {}
+}
+
+/// Lets the caller keep a handle on an observer that was registered with an environment.
+impl<T: Observer> Observer for Rc<RefCell<T>> {
+    fn on_start(&mut self) {
+        self.borrow_mut().on_start()
+    }
+
+    fn on_agent_spawn(&mut self, agent: Uuid) {
+        self.borrow_mut().on_agent_spawn(agent)
+    }
+
+    fn before_dispatch(&mut self, dispatch: &Dispatch, args: Option<&dyn EventArgs>) {
+        self.borrow_mut().before_dispatch(dispatch, args)
+    }
+
+    fn after_dispatch(&mut self, dispatch: &Dispatch, produced: &[Produced]) {
+        self.borrow_mut().after_dispatch(dispatch, produced)
+    }
+
+    fn on_pause(&mut self, processed: u64, pause: Pause) {
+        self.borrow_mut().on_pause(processed, pause)
+    }
+
+    fn on_error(&mut self, error: &EventEngineError) {
+        self.borrow_mut().on_error(error)
+    }
+
+    fn on_agent_retire(&mut self, agent: Uuid) {
+        self.borrow_mut().on_agent_retire(agent)
+    }
+
+    fn on_finish(&mut self, reason: FinishReason) {
+        self.borrow_mut().on_finish(reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, NewEventsVec};
+    use crate::environment::EnvironmentSettings;
+    use crate::event::{Event, EventArg};
+    use crate::event_queue::tests::run_engine;
+    use std::any::Any;
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct CallLog {
+        calls: Vec<String>,
+    }
+
+    impl Observer for CallLog {
+        fn on_start(&mut self) {
+            self.calls.push("start".to_string());
+        }
+
+        fn on_agent_spawn(&mut self, _agent: Uuid) {
+            self.calls.push("spawn".to_string());
+        }
+
+        fn before_dispatch(&mut self, dispatch: &Dispatch, args: Option<&dyn EventArgs>) {
+            self.calls.push(format!(
+                "before {} at {} from {:?} with {}",
+                dispatch.sequence,
+                dispatch.time,
+                dispatch.sender.map(|_| "agent"),
+                args.is_some()
+            ));
+        }
+
+        fn after_dispatch(&mut self, dispatch: &Dispatch, produced: &[Produced]) {
+            let produced: Vec<String> = produced
+                .iter()
+                .map(|event| format!("{}:{}", event.time, event.args.is_some()))
+                .collect();
+            self.calls
+                .push(format!("after {} {:?}", dispatch.sequence, produced));
+        }
+
+        fn on_pause(&mut self, processed: u64, pause: Pause) {
+            self.calls.push(format!("pause {} {:?}", processed, pause));
+        }
+
+        fn on_agent_retire(&mut self, _agent: Uuid) {
+            self.calls.push("retire".to_string());
+        }
+
+        fn on_finish(&mut self, reason: FinishReason) {
+            self.calls.push(format!("finish {:?}", reason));
+        }
+    }
+
+    struct Tick {}
+
+    impl EventArgs for Tick {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct TickingAgent {
+        id: Uuid,
+    }
+
+    impl Agent for TickingAgent {
+        fn handle(&mut self, time: u64, _args: EventArg) -> NewEventsVec {
+            vec![(Event::new_with_args(self.id, Box::new(Tick {})), time + 1)]
+        }
+
+        fn get_id(&self) -> Uuid {
+            self.id
+        }
+    }
+
+    struct TwoEvents {}
+
+    impl EnvironmentSettings for TwoEvents {
+        fn get_iter_count(&self) -> u64 {
+            2
+        }
+
+        fn get_sleep_ms(&self) -> u64 {
+            0
+        }
+
+        fn get_max_iter(&self) -> u64 {
+            2
+        }
+    }
+
+    fn run(agent: &mut TickingAgent, target: Uuid, log: &Rc<RefCell<CallLog>>) {
+        let mut observers: Vec<Box<dyn Observer>> = vec![Box::new(log.clone())];
+        let _ = run_engine(
+            Agent::solo_vec(agent),
+            vec![(Event::new(target), 1)],
+            TwoEvents {},
+            None,
+            &mut observers,
+        );
+    }
+
+    #[test]
+    fn hooks_follow_the_run() {
+        let id = Uuid::new_v4();
+        let log = Rc::new(RefCell::new(CallLog::default()));
+        run(&mut TickingAgent { id }, id, &log);
+        assert_eq!(
+            log.borrow().calls,
+            vec![
+                "start".to_string(),
+                "spawn".to_string(),
+                "before 0 at 1 from None with false".to_string(),
+                "after 0 [\"2:true\"]".to_string(),
+                "before 1 at 2 from Some(\"agent\") with true".to_string(),
+                "after 1 [\"3:true\"]".to_string(),
+                format!("pause 2 {:?}", Pause::Sleep(Duration::ZERO)),
+                "retire".to_string(),
+                "finish MaxIter".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_runs_are_finished() {
+        let log = Rc::new(RefCell::new(CallLog::default()));
+        run(
+            &mut TickingAgent { id: Uuid::new_v4() },
+            Uuid::new_v4(),
+            &log,
+        );
+        assert_eq!(
+            log.borrow().calls,
+            vec!["start", "spawn", "retire", "finish Failed"]
+        );
+    }
+}
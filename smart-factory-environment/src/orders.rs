@@ -0,0 +1,588 @@
+use crate::agent::{Agent, NewEventsVec};
+use crate::calendar::SimulationCalendar;
+use crate::environment::EnvironmentSettings;
+use crate::event::{Event, EventArg, EventArgs};
+use crate::rng::{simulation_rng, simulation_uuid, SimulationRng};
+use chrono::NaiveDateTime;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use uuid::Uuid;
+
+const DATE_TIME_FORMATS: [&str; 3] = ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"];
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Order {
+    pub id: u64,
+    pub product: String,
+    pub created_at: u64,
+    pub due_date: u64,
+}
+
+/// Event argument delivered to the order target when a new order enters the factory.
+pub struct OrderArrived {
+    pub order: Order,
+}
+
+impl EventArgs for OrderArrived {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn to_json(&self) -> Option<serde_json::Value> {
+        serde_json::to_value(&self.order).ok()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScheduledOrder {
+    pub time: u64,
+    pub product: String,
+    pub due_date: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ArrivalProcess {
+    /// Exponential inter-arrival times with `rate` orders per tick.
+    /// Products are drawn from `product_mix` proportionally to their weights.
+    Poisson {
+        rate: f64,
+        product_mix: Vec<(String, f64)>,
+        due_date_allowance: u64,
+    },
+    /// Fixed schedule, optionally repeated every `repeat_every` ticks.
+    /// Due dates are relative to the arrival time. Orders of a cycle never arrive before the
+    /// orders of the previous one, a `repeat_every` shorter than the schedule delays them.
+    Schedule {
+        orders: Vec<ScheduledOrder>,
+        repeat_every: Option<u64>,
+    },
+    /// Replay of historical orders with absolute arrival times and due dates.
+    Trace(Vec<ScheduledOrder>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OrderTraceError {
+    MissingColumn(&'static str),
+    InvalidValue {
+        line: usize,
+        column: &'static str,
+        value: String,
+    },
+    DateTimeWithoutCalendar {
+        line: usize,
+    },
+    /// The CSV itself could not be read, e.g. it is not valid UTF-8.
+    Malformed {
+        line: usize,
+        message: String,
+    },
+}
+
+impl OrderTraceError {
+    fn malformed(error: &csv::Error) -> OrderTraceError {
+        OrderTraceError::Malformed {
+            line: error
+                .position()
+                .map_or(0, |position| position.line() as usize),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl ArrivalProcess {
+    /// Parses a CSV trace with a header containing `time`, `product` and `due_date` columns.
+    /// Fields may be quoted, e.g. products containing commas. Times are either ticks or
+    /// date-times, the latter require a calendar.
+    pub fn trace_from_csv(
+        csv: &str,
+        calendar: Option<&SimulationCalendar>,
+    ) -> Result<ArrivalProcess, OrderTraceError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(csv.as_bytes());
+        let header = match reader.headers() {
+            Ok(header) if !header.is_empty() => header.clone(),
+            Ok(_) => return Ok(ArrivalProcess::Trace(vec![])),
+            Err(error) => return Err(OrderTraceError::malformed(&error)),
+        };
+        let column = |name: &'static str| {
+            header
+                .iter()
+                .position(|column| column == name)
+                .ok_or(OrderTraceError::MissingColumn(name))
+        };
+        let (time_column, product_column, due_date_column) =
+            (column("time")?, column("product")?, column("due_date")?);
+
+        let mut orders = reader
+            .records()
+            .map(|record| {
+                let record = record.map_err(|error| OrderTraceError::malformed(&error))?;
+                let line_number = record
+                    .position()
+                    .map_or(0, |position| position.line() as usize);
+                let field = |position: usize, name: &'static str| {
+                    record.get(position).ok_or(OrderTraceError::InvalidValue {
+                        line: line_number,
+                        column: name,
+                        value: String::new(),
+                    })
+                };
+                Ok(ScheduledOrder {
+                    time: parse_time(field(time_column, "time")?, "time", line_number, calendar)?,
+                    product: field(product_column, "product")?.to_string(),
+                    due_date: parse_time(
+                        field(due_date_column, "due_date")?,
+                        "due_date",
+                        line_number,
+                        calendar,
+                    )?,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        orders.sort_by_key(|order| order.time);
+        Ok(ArrivalProcess::Trace(orders))
+    }
+}
+
+fn parse_time(
+    value: &str,
+    column: &'static str,
+    line: usize,
+    calendar: Option<&SimulationCalendar>,
+) -> Result<u64, OrderTraceError> {
+    if let Ok(time) = value.parse::<u64>() {
+        return Ok(time);
+    }
+    let invalid = || OrderTraceError::InvalidValue {
+        line,
+        column,
+        value: value.to_string(),
+    };
+    let date_time = DATE_TIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .ok_or_else(invalid)?;
+    let calendar = calendar.ok_or(OrderTraceError::DateTimeWithoutCalendar { line })?;
+    calendar.to_time(date_time).ok_or_else(invalid)
+}
+
+/// Agent creating orders according to an arrival process and sending them to `target`.
+pub struct OrderSource {
+    id: Uuid,
+    target: Uuid,
+    process: ArrivalProcess,
+    rng: SimulationRng,
+    next_order_id: u64,
+    position: usize,
+    cycle_start: u64,
+    next_arrival: f64,
+}
+
+impl OrderSource {
+    pub fn new(
+        id: Uuid,
+        target: Uuid,
+        mut process: ArrivalProcess,
+        rng: SimulationRng,
+    ) -> OrderSource {
+        match &mut process {
+            ArrivalProcess::Schedule { orders, .. } | ArrivalProcess::Trace(orders) => {
+                orders.sort_by_key(|order| order.time)
+            }
+            ArrivalProcess::Poisson { .. } => {}
+        }
+        OrderSource {
+            id,
+            target,
+            process,
+            rng,
+            next_order_id: 0,
+            position: 0,
+            cycle_start: 0,
+            next_arrival: 0.0,
+        }
+    }
+
+    /// Uses the `stream` of the simulation seed, so each source gets its own reproducible numbers.
+    /// Its id is the first draw of the stream.
+    pub fn from_settings<Settings: EnvironmentSettings>(
+        settings: &Settings,
+        stream: u64,
+        target: Uuid,
+        process: ArrivalProcess,
+    ) -> OrderSource {
+        let mut rng = simulation_rng(settings.get_seed(), stream);
+        let id = simulation_uuid(&mut rng);
+        OrderSource::new(id, target, process, rng)
+    }
+
+    pub fn created_orders(&self) -> u64 {
+        self.next_order_id
+    }
+
+    /// Event that has to be put into the queue for the source to start producing orders.
+    pub fn initial_event(&mut self) -> Option<(Event, u64)> {
+        self.next_arrival_time(0)
+            .map(|time| (Event::new(self.id), time))
+    }
+
+    fn next_arrival_time(&mut self, now: u64) -> Option<u64> {
+        match &self.process {
+            ArrivalProcess::Poisson { rate, .. } => {
+                if *rate <= 0.0 {
+                    return None;
+                }
+                let uniform: f64 = self.rng.gen();
+                self.next_arrival += -(1.0 - uniform).ln() / rate;
+                Some((self.next_arrival.ceil() as u64).max(now))
+            }
+            ArrivalProcess::Schedule {
+                orders,
+                repeat_every,
+            } => {
+                if self.position >= orders.len() {
+                    match repeat_every {
+                        Some(period) if *period > 0 && !orders.is_empty() => {
+                            self.position = 0;
+                            self.cycle_start += period;
+                        }
+                        _ => return None,
+                    }
+                }
+                // A cycle shorter than the schedule starts before the previous one is over, its
+                // first orders arrive once the previous cycle is done
+                Some((self.cycle_start + orders[self.position].time).max(now))
+            }
+            ArrivalProcess::Trace(orders) => orders.get(self.position).map(|order| order.time),
+        }
+    }
+
+    fn create_orders(&mut self, time: u64) -> Vec<Order> {
+        let mut created = vec![];
+        match &self.process {
+            ArrivalProcess::Poisson {
+                product_mix,
+                due_date_allowance,
+                ..
+            } => {
+                let total: f64 = product_mix.iter().map(|(_, weight)| weight).sum();
+                let mut choice = self.rng.gen::<f64>() * total;
+                let product = product_mix
+                    .iter()
+                    .find(|(_, weight)| {
+                        choice -= weight;
+                        choice < 0.0
+                    })
+                    .or(product_mix.last())
+                    .map(|(product, _)| product.clone())
+                    .unwrap_or_default();
+                created.push((product, time + due_date_allowance));
+            }
+            ArrivalProcess::Schedule { orders, .. } => {
+                while let Some(order) = orders.get(self.position) {
+                    if self.cycle_start + order.time > time {
+                        break;
+                    }
+                    created.push((order.product.clone(), time + order.due_date));
+                    self.position += 1;
+                }
+            }
+            ArrivalProcess::Trace(orders) => {
+                while let Some(order) = orders.get(self.position) {
+                    if order.time != time {
+                        break;
+                    }
+                    created.push((order.product.clone(), order.due_date));
+                    self.position += 1;
+                }
+            }
+        }
+        created
+            .into_iter()
+            .map(|(product, due_date)| {
+                self.next_order_id += 1;
+                Order {
+                    id: self.next_order_id,
+                    product,
+                    created_at: time,
+                    due_date,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Agent for OrderSource {
+    fn handle(&mut self, time: u64, _args: EventArg) -> NewEventsVec {
+        let mut events: NewEventsVec = self
+            .create_orders(time)
+            .into_iter()
+            .map(|order| {
+                (
+                    Event::new_with_args(self.target, Box::new(OrderArrived { order })),
+                    time,
+                )
+            })
+            .collect();
+        if let Some(next) = self.next_arrival_time(time) {
+            events.push((Event::new(self.id), next));
+        }
+        events
+    }
+
+    fn get_id(&self) -> Uuid {
+        self.id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::{incoming_channel, outgoing_channel, DEFAULT_OUTGOING_CAPACITY};
+    use crate::event_queue::process_event_queue;
+    use crate::rng::DEFAULT_SEED;
+    use chrono::NaiveDate;
+    use std::time::Duration;
+
+    struct TestSettings {}
+    impl EnvironmentSettings for TestSettings {}
+
+    struct OrderSink {
+        id: Uuid,
+        orders: Vec<Order>,
+    }
+
+    impl Agent for OrderSink {
+        fn handle(&mut self, time: u64, args: EventArg) -> NewEventsVec {
+            let args = args.unwrap();
+            let arrived = args.as_any().downcast_ref::<OrderArrived>().unwrap();
+            assert_eq!(arrived.order.created_at, time);
+            self.orders.push(arrived.order.clone());
+            vec![]
+        }
+
+        fn get_id(&self) -> Uuid {
+            self.id
+        }
+    }
+
+    async fn collect_orders(process: ArrivalProcess, max_iter: u64) -> Vec<Order> {
+        struct MaxIterSettings(u64);
+        impl EnvironmentSettings for MaxIterSettings {
+            fn get_max_iter(&self) -> u64 {
+                self.0
+            }
+        }
+
+        let mut sink = OrderSink {
+            id: Uuid::new_v4(),
+            orders: vec![],
+        };
+        let mut source = OrderSource::from_settings(&TestSettings {}, 1, sink.id, process);
+        let init_state = source.initial_event().into_iter().collect();
+        let (_send, recv) = incoming_channel();
+        let (send, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        let result = process_event_queue(
+            vec![&mut source as &mut dyn Agent, &mut sink as &mut dyn Agent],
+            init_state,
+            recv,
+            &mut |_| {},
+            &mut |_| async {},
+            MaxIterSettings(max_iter),
+            send,
+            None,
+            None,
+            &mut [],
+        )
+        .await;
+        assert!(result.is_ok());
+        sink.orders
+    }
+
+    #[tokio::test]
+    pub async fn poisson_source_is_reproducible() {
+        let process = ArrivalProcess::Poisson {
+            rate: 0.5,
+            product_mix: vec![("A".to_string(), 3.0), ("B".to_string(), 1.0)],
+            due_date_allowance: 10,
+        };
+        let first = collect_orders(process.clone(), 2000).await;
+        let second = collect_orders(process, 2000).await;
+        assert_eq!(first, second);
+
+        let last = first.last().unwrap();
+        let rate = first.len() as f64 / last.created_at as f64;
+        assert!((rate - 0.5).abs() < 0.1, "rate was {}", rate);
+        let a_count = first.iter().filter(|order| order.product == "A").count();
+        let a_share = a_count as f64 / first.len() as f64;
+        assert!((a_share - 0.75).abs() < 0.1, "share was {}", a_share);
+        assert!(first
+            .iter()
+            .all(|order| order.due_date == order.created_at + 10));
+        assert!(first
+            .windows(2)
+            .all(|pair| pair[0].created_at <= pair[1].created_at));
+    }
+
+    #[tokio::test]
+    pub async fn schedule_source_repeats() {
+        let process = ArrivalProcess::Schedule {
+            orders: vec![
+                ScheduledOrder {
+                    time: 2,
+                    product: "A".to_string(),
+                    due_date: 5,
+                },
+                ScheduledOrder {
+                    time: 2,
+                    product: "B".to_string(),
+                    due_date: 6,
+                },
+                ScheduledOrder {
+                    time: 7,
+                    product: "C".to_string(),
+                    due_date: 1,
+                },
+            ],
+            repeat_every: Some(10),
+        };
+        let orders = collect_orders(process, 12).await;
+        let arrivals: Vec<(u64, &str, u64)> = orders
+            .iter()
+            .map(|order| (order.created_at, order.product.as_str(), order.due_date))
+            .collect();
+        assert_eq!(
+            arrivals[..6],
+            [
+                (2, "A", 7),
+                (2, "B", 8),
+                (7, "C", 8),
+                (12, "A", 17),
+                (12, "B", 18),
+                (17, "C", 18)
+            ]
+        );
+        assert!(orders.windows(2).all(|pair| pair[0].id < pair[1].id));
+    }
+
+    #[tokio::test]
+    pub async fn short_schedule_cycles_never_go_back_in_time() {
+        let process = ArrivalProcess::Schedule {
+            orders: vec![
+                ScheduledOrder {
+                    time: 0,
+                    product: "A".to_string(),
+                    due_date: 1,
+                },
+                ScheduledOrder {
+                    time: 8,
+                    product: "B".to_string(),
+                    due_date: 1,
+                },
+            ],
+            repeat_every: Some(5),
+        };
+        let orders = collect_orders(process, 12).await;
+        let arrivals: Vec<(u64, &str)> = orders
+            .iter()
+            .map(|order| (order.created_at, order.product.as_str()))
+            .collect();
+        assert_eq!(arrivals[..4], [(0, "A"), (8, "B"), (8, "A"), (13, "B")]);
+        assert!(orders
+            .windows(2)
+            .all(|pair| pair[0].created_at <= pair[1].created_at));
+    }
+
+    #[tokio::test]
+    pub async fn trace_source_replays_csv() {
+        let calendar = SimulationCalendar::new(
+            NaiveDate::from_ymd_opt(2022, 8, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            Duration::from_secs(60),
+        );
+        let csv = "product,time,due_date\n\
+                   \"B, large\",2022-08-01 01:00,2022-08-01T03:00:00\n\
+                   A,5,100\n";
+        let process = ArrivalProcess::trace_from_csv(csv, Some(&calendar)).unwrap();
+        assert_eq!(
+            process,
+            ArrivalProcess::Trace(vec![
+                ScheduledOrder {
+                    time: 5,
+                    product: "A".to_string(),
+                    due_date: 100
+                },
+                ScheduledOrder {
+                    time: 60,
+                    product: "B, large".to_string(),
+                    due_date: 180
+                },
+            ])
+        );
+        let orders = collect_orders(process, 100).await;
+        assert_eq!(orders.len(), 2);
+        assert_eq!(orders[1].created_at, 60);
+    }
+
+    #[test]
+    fn trace_errors_point_to_line_and_column() {
+        assert_eq!(
+            ArrivalProcess::trace_from_csv("time,product\n1,A", None),
+            Err(OrderTraceError::MissingColumn("due_date"))
+        );
+        assert_eq!(
+            ArrivalProcess::trace_from_csv("time,product,due_date\n1,A,2\nsoon,B,3", None),
+            Err(OrderTraceError::InvalidValue {
+                line: 3,
+                column: "time",
+                value: "soon".to_string()
+            })
+        );
+        assert_eq!(
+            ArrivalProcess::trace_from_csv("time,product,due_date\n2022-08-01 01:00,A,2", None),
+            Err(OrderTraceError::DateTimeWithoutCalendar { line: 2 })
+        );
+    }
+
+    struct SeedSettings(u64);
+
+    impl EnvironmentSettings for SeedSettings {
+        fn get_seed(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn sources_with_the_same_seed_have_the_same_ids() {
+        let id = |seed, stream| {
+            let process = ArrivalProcess::Trace(vec![]);
+            OrderSource::from_settings(&SeedSettings(seed), stream, Uuid::nil(), process).id
+        };
+        assert_eq!(id(DEFAULT_SEED, 0), id(DEFAULT_SEED, 0));
+        assert_ne!(id(DEFAULT_SEED, 0), id(DEFAULT_SEED, 1));
+        assert_ne!(id(DEFAULT_SEED, 0), id(7, 0));
+    }
+
+    #[test]
+    fn sources_with_different_seeds_differ() {
+        let process = ArrivalProcess::Poisson {
+            rate: 0.1,
+            product_mix: vec![("A".to_string(), 1.0)],
+            due_date_allowance: 0,
+        };
+        let arrivals = |seed| {
+            let mut source =
+                OrderSource::from_settings(&SeedSettings(seed), 0, Uuid::nil(), process.clone());
+            (0..10)
+                .filter_map(|_| source.next_arrival_time(0))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(arrivals(DEFAULT_SEED), arrivals(DEFAULT_SEED));
+        assert_ne!(arrivals(DEFAULT_SEED), arrivals(7));
+    }
+}
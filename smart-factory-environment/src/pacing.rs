@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use web_time::Instant;
+
+/// How the engine relates simulated time to real time.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Pacing {
+    /// Sleeps `get_sleep_ms` every `get_iter_count` events.
+    #[default]
+    EventCount,
+    /// Never sleeps.
+    Unthrottled,
+    /// Dispatches an event only once its simulated time is due on the wall clock.
+    /// `scale` is the number of real seconds per simulated second, ticks are converted with
+    /// `EnvironmentSettings::get_tick`.
+    WallClockScaled { scale: f64 },
+    /// Processes events for at most `budget_ms` of real time, then hands control back to the
+    /// executor with a zero-length sleep. In the browser this lets the page render in between.
+    FrameBudget { budget_ms: u64 },
+    /// Hands control back every `every` events with a zero-length sleep. In the browser the sleep
+    /// is a `setTimeout(0)`, so the page renders in between. Environments without a sleep
+    /// function only yield to the executor.
+    Yield { every: u64 },
+}
+
+/// What the engine does between two events.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pause {
+    Sleep(Duration),
+    /// Zero-length sleep of `Pacing::Yield`.
+    Yield,
+}
+
+/// Future that is pending exactly once, giving other tasks of the executor a chance to run.
+/// Works with any executor since it only wakes itself.
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// Sleep function of environments that never wait for real time, see
+/// [`AgentEnvironment::without_sleep`](crate::environment::AgentEnvironment::without_sleep).
+pub type NoSleepFunction = fn(Duration) -> YieldNow;
+
+/// Yields instead of sleeping.
+pub fn no_sleep(_duration: Duration) -> YieldNow {
+    yield_now()
+}
+
+pub(crate) struct Pacer {
+    pacing: Pacing,
+    /// Simulated length of one tick.
+    tick: Duration,
+    /// Real and simulated time the wall clock mode started counting from.
+    anchor: Option<(Instant, u64)>,
+    frame_start: Instant,
+}
+
+impl Pacer {
+    pub(crate) fn new(pacing: Pacing, tick: Duration) -> Pacer {
+        Pacer {
+            pacing,
+            tick,
+            anchor: None,
+            frame_start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn set_pacing(&mut self, pacing: Pacing) {
+        self.pacing = pacing;
+        self.resume();
+    }
+
+    /// Has to be called after the engine waited for something else than the pacer, so that the
+    /// wait is not counted as time spent on events.
+    pub(crate) fn resume(&mut self) {
+        self.anchor = None;
+        self.frame_start = Instant::now();
+    }
+
+    /// Real time to wait before dispatching an event scheduled at `time`. The event is due once
+    /// this returns `None`.
+    pub(crate) fn delay_before(&mut self, time: u64) -> Option<Duration> {
+        if let Pacing::WallClockScaled { scale } = self.pacing {
+            let (start, start_time) = *self.anchor.get_or_insert_with(|| (Instant::now(), time));
+            let simulated = time.saturating_sub(start_time) as f64 * self.tick.as_secs_f64();
+            let due =
+                Duration::try_from_secs_f64(simulated * scale.max(0.0)).unwrap_or(Duration::MAX);
+            let elapsed = start.elapsed();
+            if due > elapsed {
+                return Some(due - elapsed);
+            }
+        }
+        None
+    }
+
+    /// Pause after `processed` events were dispatched.
+    pub(crate) fn pause_after(
+        &mut self,
+        processed: u64,
+        iter_count: u64,
+        sleep_duration: Duration,
+    ) -> Option<Pause> {
+        match self.pacing {
+            Pacing::EventCount => processed
+                .is_multiple_of(iter_count)
+                .then_some(Pause::Sleep(sleep_duration)),
+            Pacing::FrameBudget { budget_ms } => (self.frame_start.elapsed()
+                >= Duration::from_millis(budget_ms))
+            .then_some(Pause::Sleep(Duration::ZERO)),
+            Pacing::Yield { every } => processed.is_multiple_of(every).then_some(Pause::Yield),
+            Pacing::Unthrottled | Pacing::WallClockScaled { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    #[test]
+    fn yield_now_is_pending_once() {
+        let mut future = yield_now();
+        let mut context = Context::from_waker(noop_waker_ref());
+        assert_eq!(Pin::new(&mut future).poll(&mut context), Poll::Pending);
+        assert_eq!(Pin::new(&mut future).poll(&mut context), Poll::Ready(()));
+    }
+}
@@ -0,0 +1,805 @@
+use crate::channel::DEFAULT_OUTGOING_CAPACITY;
+use crate::doe::{DesignPoint, ParameterValue};
+use crate::empty_environment::EmptyEnvironmentSettings;
+use crate::environment::{
+    EnvironmentSettings, DEFAULT_ITER_COUNT_SLEEP, DEFAULT_MAX_ITER, DEFAULT_SLEEP_DURATION_MS,
+    DEFAULT_TICK,
+};
+use crate::error::ErrorPolicy;
+use crate::message::ProgressRate;
+use crate::pacing::Pacing;
+use crate::rng::DEFAULT_SEED;
+use crate::statistics::WarmUp;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScenarioFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ScenarioFormat {
+    pub fn from_extension(extension: &str) -> Option<ScenarioFormat> {
+        match extension.to_ascii_lowercase().as_str() {
+            "toml" => Some(ScenarioFormat::Toml),
+            "json" => Some(ScenarioFormat::Json),
+            "yaml" | "yml" => Some(ScenarioFormat::Yaml),
+            _ => None,
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<ScenarioFormat> {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(ScenarioFormat::from_extension)
+    }
+}
+
+#[derive(Debug)]
+pub enum ScenarioError {
+    Io(std::io::Error),
+    UnknownFormat(String),
+    /// The document could not be deserialized. `path` points at the offending field.
+    Parse {
+        path: String,
+        message: String,
+    },
+    Invalid(Vec<ValidationError>),
+    /// The scenario could not be written in the requested format.
+    Write(String),
+}
+
+impl Display for ScenarioError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScenarioError::Io(error) => write!(f, "could not read scenario: {}", error),
+            ScenarioError::UnknownFormat(path) => {
+                write!(
+                    f,
+                    "unknown scenario format of '{}', expected toml, json or yaml",
+                    path
+                )
+            }
+            ScenarioError::Parse { path, message } => write!(f, "{}: {}", path, message),
+            ScenarioError::Invalid(errors) => {
+                let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+                write!(f, "{}", errors.join("\n"))
+            }
+            ScenarioError::Write(message) => write!(f, "could not write scenario: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ScenarioError {}
+
+impl From<std::io::Error> for ScenarioError {
+    fn from(error: std::io::Error) -> Self {
+        ScenarioError::Io(error)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationError {
+    /// Dotted path of the field, e.g. `model.routes[0].steps[1].machine`.
+    pub path: String,
+    pub message: String,
+}
+
+impl ValidationError {
+    pub(crate) fn new(path: String, message: &str) -> ValidationError {
+        ValidationError {
+            path,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+pub(crate) fn validate_pacing(pacing: &Pacing, path: &str, errors: &mut Vec<ValidationError>) {
+    match pacing {
+        Pacing::WallClockScaled { scale } if !(*scale >= 0.0 && scale.is_finite()) => errors.push(
+            ValidationError::new(format!("{}.scale", path), "must be a non-negative number"),
+        ),
+        Pacing::FrameBudget { budget_ms: 0 } => errors.push(ValidationError::new(
+            format!("{}.budget_ms", path),
+            "must be at least 1",
+        )),
+        Pacing::Yield { every: 0 } => errors.push(ValidationError::new(
+            format!("{}.every", path),
+            "must be at least 1",
+        )),
+        _ => {}
+    }
+}
+
+pub(crate) fn validate_progress(
+    progress: ProgressRate,
+    path: &str,
+    errors: &mut Vec<ValidationError>,
+) {
+    match progress {
+        ProgressRate::Events(0) => errors.push(ValidationError::new(
+            format!("{}.events", path),
+            "must be at least 1",
+        )),
+        ProgressRate::Millis(0) => errors.push(ValidationError::new(
+            format!("{}.millis", path),
+            "must be at least 1",
+        )),
+        _ => {}
+    }
+}
+
+pub(crate) fn validate_warm_up(warm_up: &WarmUp, path: &str, errors: &mut Vec<ValidationError>) {
+    if let WarmUp::Mser5(name) = warm_up {
+        if name.is_empty() {
+            errors.push(ValidationError::new(
+                format!("{}.mser5", path),
+                "tally name must not be empty",
+            ));
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum EnvironmentKind {
+    /// Agents that only reschedule themselves, used to measure the engine.
+    Empty { agent_count: usize },
+    /// Agents built from the model definition. The model is validated, but no front-end runs it
+    /// yet, `empty_environment_settings` is `None` for it.
+    Factory,
+}
+
+impl Default for EnvironmentKind {
+    fn default() -> Self {
+        EnvironmentKind::Empty { agent_count: 1 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineSettings {
+    pub pacing: Pacing,
+    /// Sleep of the event count pacing.
+    pub sleep_ms: u64,
+    /// Events processed between two sleeps of the event count pacing and between progress messages.
+    pub iter_count: u64,
+    /// Rate of the detailed progress messages for dashboards.
+    pub progress: ProgressRate,
+    /// What happens to events that have no agent or whose handler fails.
+    pub error_policy: ErrorPolicy,
+    /// Simulated milliseconds of one tick.
+    pub tick_ms: u64,
+    /// Queued outgoing messages before progress messages are coalesced.
+    pub outgoing_capacity: usize,
+}
+
+impl Default for EngineSettings {
+    fn default() -> Self {
+        EngineSettings {
+            pacing: Pacing::EventCount,
+            sleep_ms: DEFAULT_SLEEP_DURATION_MS,
+            iter_count: DEFAULT_ITER_COUNT_SLEEP,
+            progress: ProgressRate::Never,
+            error_policy: ErrorPolicy::Abort,
+            tick_ms: DEFAULT_TICK.as_millis() as u64,
+            outgoing_capacity: DEFAULT_OUTGOING_CAPACITY,
+        }
+    }
+}
+
+/// A missing condition does not stop the run.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StopConditions {
+    /// Events processed before the run stops.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_iter: Option<u64>,
+    /// Simulated time after which no event is dispatched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MachineDefinition {
+    pub name: String,
+    #[serde(default = "default_machine_count")]
+    pub count: usize,
+    #[serde(default)]
+    pub setup_time: u64,
+}
+
+fn default_machine_count() -> usize {
+    1
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteStep {
+    pub machine: String,
+    pub processing_time: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteDefinition {
+    pub name: String,
+    pub steps: Vec<RouteStep>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProductDefinition {
+    pub name: String,
+    pub route: String,
+    /// Ticks between the arrival of an order and its due date.
+    #[serde(default)]
+    pub due_date_allowance: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelDefinition {
+    pub machines: Vec<MachineDefinition>,
+    pub products: Vec<ProductDefinition>,
+    pub routes: Vec<RouteDefinition>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scenario {
+    pub name: Option<String>,
+    pub environment: EnvironmentKind,
+    pub engine: EngineSettings,
+    pub stop: StopConditions,
+    #[serde(with = "large_u64")]
+    pub seed: u64,
+    pub warm_up: WarmUp,
+    pub model: ModelDefinition,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Scenario {
+            name: None,
+            environment: EnvironmentKind::default(),
+            engine: EngineSettings::default(),
+            stop: StopConditions::default(),
+            seed: DEFAULT_SEED,
+            warm_up: WarmUp::None,
+            model: ModelDefinition::default(),
+        }
+    }
+}
+
+impl Scenario {
+    /// Parses and validates a scenario.
+    pub fn parse(text: &str, format: ScenarioFormat) -> Result<Scenario, ScenarioError> {
+        let scenario: Scenario = match format {
+            ScenarioFormat::Toml => {
+                serde_path_to_error::deserialize(toml::Deserializer::new(text)).map_err(parse_error)
+            }
+            ScenarioFormat::Json => {
+                serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(text))
+                    .map_err(parse_error)
+            }
+            ScenarioFormat::Yaml => {
+                serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(text))
+                    .map_err(parse_error)
+            }
+        }?;
+        scenario.validate().map_err(ScenarioError::Invalid)?;
+        Ok(scenario)
+    }
+
+    /// Loads a scenario, the format is chosen by the file extension.
+    pub fn load(path: &Path) -> Result<Scenario, ScenarioError> {
+        let format = ScenarioFormat::from_path(path)
+            .ok_or_else(|| ScenarioError::UnknownFormat(path.display().to_string()))?;
+        Scenario::parse(&std::fs::read_to_string(path)?, format)
+    }
+
+    /// Fails for numbers the format can't hold, e.g. a `stop.max_iter` above `i64::MAX` in TOML.
+    pub fn to_string(&self, format: ScenarioFormat) -> Result<String, ScenarioError> {
+        match format {
+            ScenarioFormat::Toml => toml::to_string(self).map_err(|error| error.to_string()),
+            ScenarioFormat::Json => {
+                serde_json::to_string_pretty(self).map_err(|error| error.to_string())
+            }
+            ScenarioFormat::Yaml => serde_yaml::to_string(self).map_err(|error| error.to_string()),
+        }
+        .map_err(ScenarioError::Write)
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = vec![];
+        if let EnvironmentKind::Empty { agent_count: 0 } = self.environment {
+            errors.push(ValidationError::new(
+                "environment.agent_count".to_string(),
+                "must be at least 1",
+            ));
+        }
+        if self.engine.iter_count == 0 {
+            errors.push(ValidationError::new(
+                "engine.iter_count".to_string(),
+                "must be at least 1",
+            ));
+        }
+        validate_pacing(&self.engine.pacing, "engine.pacing", &mut errors);
+        validate_progress(self.engine.progress, "engine.progress", &mut errors);
+        if self.engine.tick_ms == 0 {
+            errors.push(ValidationError::new(
+                "engine.tick_ms".to_string(),
+                "must be at least 1",
+            ));
+        }
+        if self.stop.max_iter == Some(0) {
+            errors.push(ValidationError::new(
+                "stop.max_iter".to_string(),
+                "must be at least 1",
+            ));
+        }
+        if let (Some(stop_time), WarmUp::Fixed(warm_up)) = (self.stop.stop_time, &self.warm_up) {
+            if stop_time <= *warm_up {
+                errors.push(ValidationError::new(
+                    "stop.stop_time".to_string(),
+                    "must be after the warm-up",
+                ));
+            }
+        }
+        validate_warm_up(&self.warm_up, "warm_up", &mut errors);
+        self.model.validate(&mut errors);
+        if self.environment == EnvironmentKind::Factory && self.model.machines.is_empty() {
+            errors.push(ValidationError::new(
+                "model.machines".to_string(),
+                "factory environment needs at least one machine",
+            ));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Settings of the empty environment, `None` for other environment types.
+    pub fn empty_environment_settings(&self) -> Option<EmptyEnvironmentSettings> {
+        match self.environment {
+            EnvironmentKind::Empty { agent_count } => {
+                let settings = EmptyEnvironmentSettings::new(
+                    agent_count,
+                    self.engine.sleep_ms,
+                    self.engine.iter_count,
+                    self.get_max_iter(),
+                )
+                .with_seed(self.seed)
+                .with_warm_up(self.warm_up.clone())
+                .with_pacing(self.engine.pacing.clone())
+                .with_progress(self.engine.progress)
+                .with_error_policy(self.engine.error_policy)
+                .with_tick(self.get_tick())
+                .with_outgoing_capacity(self.engine.outgoing_capacity);
+                Some(match self.stop.stop_time {
+                    Some(stop_time) => settings.with_stop_time(stop_time),
+                    None => settings,
+                })
+            }
+            EnvironmentKind::Factory => None,
+        }
+    }
+
+    /// Copy of the scenario with the parameters of a design point applied. Parameter names are
+    /// dotted paths into the scenario, e.g. `environment.agent_count` or `stop.max_iter`.
+    pub fn with_parameters(&self, point: &DesignPoint) -> Result<Scenario, ScenarioError> {
+        let mut document = serde_json::to_value(self).expect("Scenario is always valid JSON");
+        for (name, value) in &point.values {
+            let mut field = &mut document;
+            for key in name.split('.') {
+                field = match field {
+                    serde_json::Value::Object(fields) => {
+                        fields.entry(key).or_insert(serde_json::Value::Null)
+                    }
+                    _ => {
+                        return Err(ScenarioError::Parse {
+                            path: name.clone(),
+                            message: format!("'{}' is not a section", key),
+                        })
+                    }
+                };
+            }
+            *field = match value {
+                ParameterValue::Integer(value) => serde_json::Value::from(*value),
+                ParameterValue::Real(value) => serde_json::Value::from(*value),
+                ParameterValue::Text(value) => serde_json::Value::from(value.as_str()),
+            };
+        }
+        let scenario: Scenario = serde_path_to_error::deserialize(document).map_err(parse_error)?;
+        scenario.validate().map_err(ScenarioError::Invalid)?;
+        Ok(scenario)
+    }
+}
+
+impl EnvironmentSettings for Scenario {
+    fn get_iter_count(&self) -> u64 {
+        self.engine.iter_count
+    }
+
+    fn get_sleep_ms(&self) -> u64 {
+        self.engine.sleep_ms
+    }
+
+    fn get_max_iter(&self) -> u64 {
+        self.stop.max_iter.unwrap_or(DEFAULT_MAX_ITER)
+    }
+
+    fn get_stop_time(&self) -> Option<u64> {
+        self.stop.stop_time
+    }
+
+    fn get_seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn get_warm_up(&self) -> WarmUp {
+        self.warm_up.clone()
+    }
+
+    fn get_pacing(&self) -> Pacing {
+        self.engine.pacing.clone()
+    }
+
+    fn get_progress_rate(&self) -> ProgressRate {
+        self.engine.progress
+    }
+
+    fn get_error_policy(&self) -> ErrorPolicy {
+        self.engine.error_policy
+    }
+
+    fn get_tick(&self) -> Duration {
+        Duration::from_millis(self.engine.tick_ms)
+    }
+
+    fn get_outgoing_capacity(&self) -> usize {
+        self.engine.outgoing_capacity
+    }
+}
+
+/// TOML integers end at `i64::MAX`, larger numbers are written as strings. Both are read.
+mod large_u64 {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        if i64::try_from(*value).is_ok() {
+            serializer.serialize_u64(*value)
+        } else {
+            serializer.serialize_str(&value.to_string())
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Number {
+        Integer(u64),
+        Text(String),
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        match Number::deserialize(deserializer)? {
+            Number::Integer(value) => Ok(value),
+            Number::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl ModelDefinition {
+    fn validate(&self, errors: &mut Vec<ValidationError>) {
+        let machines = unique_names(
+            self.machines.iter().map(|machine| machine.name.as_str()),
+            "model.machines",
+            errors,
+        );
+        let routes = unique_names(
+            self.routes.iter().map(|route| route.name.as_str()),
+            "model.routes",
+            errors,
+        );
+        unique_names(
+            self.products.iter().map(|product| product.name.as_str()),
+            "model.products",
+            errors,
+        );
+
+        for (index, machine) in self.machines.iter().enumerate() {
+            if machine.count == 0 {
+                errors.push(ValidationError::new(
+                    format!("model.machines[{}].count", index),
+                    "must be at least 1",
+                ));
+            }
+        }
+        for (index, route) in self.routes.iter().enumerate() {
+            if route.steps.is_empty() {
+                errors.push(ValidationError::new(
+                    format!("model.routes[{}].steps", index),
+                    "route needs at least one step",
+                ));
+            }
+            for (step_index, step) in route.steps.iter().enumerate() {
+                if !machines.contains(step.machine.as_str()) {
+                    errors.push(ValidationError::new(
+                        format!("model.routes[{}].steps[{}].machine", index, step_index),
+                        &format!("unknown machine '{}'", step.machine),
+                    ));
+                }
+                if step.processing_time == 0 {
+                    errors.push(ValidationError::new(
+                        format!(
+                            "model.routes[{}].steps[{}].processing_time",
+                            index, step_index
+                        ),
+                        "must be at least 1",
+                    ));
+                }
+            }
+        }
+        for (index, product) in self.products.iter().enumerate() {
+            if !routes.contains(product.route.as_str()) {
+                errors.push(ValidationError::new(
+                    format!("model.products[{}].route", index),
+                    &format!("unknown route '{}'", product.route),
+                ));
+            }
+        }
+    }
+}
+
+fn unique_names<'a>(
+    names: impl Iterator<Item = &'a str>,
+    path: &str,
+    errors: &mut Vec<ValidationError>,
+) -> BTreeSet<&'a str> {
+    let mut seen = BTreeSet::new();
+    for (index, name) in names.enumerate() {
+        if name.is_empty() {
+            errors.push(ValidationError::new(
+                format!("{}[{}].name", path, index),
+                "must not be empty",
+            ));
+        } else if !seen.insert(name) {
+            errors.push(ValidationError::new(
+                format!("{}[{}].name", path, index),
+                &format!("duplicate name '{}'", name),
+            ));
+        }
+    }
+    seen
+}
+
+fn parse_error<E: Display>(error: serde_path_to_error::Error<E>) -> ScenarioError {
+    ScenarioError::Parse {
+        path: error.path().to_string(),
+        message: error.inner().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML_SCENARIO: &str = r#"
+name = "two machines"
+seed = 7
+
+[environment]
+type = "factory"
+
+[engine]
+sleep_ms = 0
+iter_count = 1000
+pacing = { mode = "frame_budget", budget_ms = 16 }
+progress = { millis = 250 }
+error_policy = "retire_agent"
+tick_ms = 60000
+
+[stop]
+max_iter = 5000
+stop_time = 480
+
+[[model.machines]]
+name = "lathe"
+count = 2
+
+[[model.machines]]
+name = "mill"
+setup_time = 5
+
+[[model.routes]]
+name = "shaft"
+steps = [
+    { machine = "lathe", processing_time = 10 },
+    { machine = "mill", processing_time = 4 },
+]
+
+[[model.products]]
+name = "shaft"
+route = "shaft"
+due_date_allowance = 100
+"#;
+
+    #[test]
+    fn it_loads_every_format() {
+        let scenario = Scenario::parse(TOML_SCENARIO, ScenarioFormat::Toml).unwrap();
+        assert_eq!(scenario.name.as_deref(), Some("two machines"));
+        assert_eq!(scenario.environment, EnvironmentKind::Factory);
+        assert_eq!(scenario.get_seed(), 7);
+        assert_eq!(scenario.get_max_iter(), 5000);
+        assert_eq!(scenario.get_stop_time(), Some(480));
+        assert_eq!(scenario.get_tick(), Duration::from_secs(60));
+        assert_eq!(scenario.get_pacing(), Pacing::FrameBudget { budget_ms: 16 });
+        assert_eq!(scenario.get_progress_rate(), ProgressRate::Millis(250));
+        assert_eq!(scenario.get_error_policy(), ErrorPolicy::RetireAgent);
+        assert_eq!(scenario.model.machines[0].count, 2);
+        assert_eq!(scenario.model.machines[1].count, 1);
+        assert_eq!(scenario.model.routes[0].steps[1].machine, "mill");
+
+        for format in [
+            ScenarioFormat::Json,
+            ScenarioFormat::Yaml,
+            ScenarioFormat::Toml,
+        ] {
+            let text = scenario.to_string(format).unwrap();
+            assert_eq!(Scenario::parse(&text, format).unwrap(), scenario);
+        }
+    }
+
+    #[test]
+    fn defaults_and_large_seeds_round_trip() {
+        let large_seed = Scenario {
+            seed: u64::MAX - 1,
+            ..Scenario::default()
+        };
+        for scenario in [Scenario::default(), large_seed] {
+            for format in [
+                ScenarioFormat::Json,
+                ScenarioFormat::Yaml,
+                ScenarioFormat::Toml,
+            ] {
+                let text = scenario.to_string(format).unwrap();
+                assert_eq!(Scenario::parse(&text, format).unwrap(), scenario);
+            }
+        }
+        let scenario = Scenario::parse("seed = \"18446744073709551614\"", ScenarioFormat::Toml);
+        assert_eq!(scenario.unwrap().get_seed(), u64::MAX - 1);
+
+        let unlimited = Scenario {
+            stop: StopConditions {
+                max_iter: Some(u64::MAX),
+                stop_time: None,
+            },
+            ..Scenario::default()
+        };
+        assert!(matches!(
+            unlimited.to_string(ScenarioFormat::Toml),
+            Err(ScenarioError::Write(_))
+        ));
+    }
+
+    #[test]
+    fn missing_sections_use_defaults() {
+        let scenario = Scenario::parse(
+            r#"{"environment": {"type": "empty", "agent_count": 3}, "warm_up": {"fixed": 100}}"#,
+            ScenarioFormat::Json,
+        )
+        .unwrap();
+        assert_eq!(scenario.get_iter_count(), DEFAULT_ITER_COUNT_SLEEP);
+        assert_eq!(scenario.get_sleep_ms(), DEFAULT_SLEEP_DURATION_MS);
+        assert_eq!(scenario.get_max_iter(), DEFAULT_MAX_ITER);
+        assert_eq!(scenario.get_seed(), DEFAULT_SEED);
+        assert_eq!(scenario.get_warm_up(), WarmUp::Fixed(100));
+        assert!(scenario.empty_environment_settings().is_some());
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_field() {
+        let error = Scenario::parse(
+            "model:\n  machines:\n    - name: lathe\n      count: many\n",
+            ScenarioFormat::Yaml,
+        )
+        .unwrap_err();
+        match error {
+            ScenarioError::Parse { path, .. } => assert_eq!(path, "model.machines[0].count"),
+            other => panic!("Unexpected error {:?}", other),
+        }
+
+        let error = Scenario::parse("[engine]\nsleep = 10\n", ScenarioFormat::Toml).unwrap_err();
+        match error {
+            ScenarioError::Parse { path, message } => {
+                assert_eq!(path, "engine.sleep");
+                assert!(message.contains("sleep"));
+            }
+            other => panic!("Unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn validation_errors_point_at_the_field() {
+        let text = TOML_SCENARIO
+            .replace(
+                "machine = \"mill\", processing_time = 4",
+                "machine = \"drill\", processing_time = 0",
+            )
+            .replace("route = \"shaft\"", "route = \"gear\"")
+            .replace("millis = 250", "events = 0")
+            .replace("tick_ms = 60000", "tick_ms = 0")
+            .replace("seed = 7", "seed = 7\nwarm_up = { fixed = 480 }");
+        let error = Scenario::parse(&text, ScenarioFormat::Toml).unwrap_err();
+        let paths: Vec<String> = match error {
+            ScenarioError::Invalid(errors) => errors.into_iter().map(|error| error.path).collect(),
+            other => panic!("Unexpected error {:?}", other),
+        };
+        assert_eq!(
+            paths,
+            vec![
+                "engine.progress.events",
+                "engine.tick_ms",
+                "stop.stop_time",
+                "model.routes[0].steps[1].machine",
+                "model.routes[0].steps[1].processing_time",
+                "model.products[0].route",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_applies_design_points() {
+        let scenario = Scenario::parse(TOML_SCENARIO, ScenarioFormat::Toml).unwrap();
+        let mut point = DesignPoint {
+            index: 0,
+            values: Default::default(),
+        };
+        point
+            .values
+            .insert("stop.max_iter".to_string(), ParameterValue::Integer(10));
+        point
+            .values
+            .insert("model.machines".to_string(), ParameterValue::Integer(1));
+        match scenario.with_parameters(&point).unwrap_err() {
+            ScenarioError::Parse { path, .. } => assert_eq!(path, "model.machines"),
+            other => panic!("Unexpected error {:?}", other),
+        }
+
+        point.values.remove("model.machines");
+        point
+            .values
+            .insert("seed".to_string(), ParameterValue::Integer(3));
+        let changed = scenario.with_parameters(&point).unwrap();
+        assert_eq!(changed.stop.max_iter, Some(10));
+        assert_eq!(changed.seed, 3);
+        assert_eq!(changed.model, scenario.model);
+    }
+
+    #[test]
+    fn it_detects_the_format_from_the_extension() {
+        assert_eq!(
+            ScenarioFormat::from_path(Path::new("line.YML")),
+            Some(ScenarioFormat::Yaml)
+        );
+        assert!(matches!(
+            Scenario::load(Path::new("scenario.ini")),
+            Err(ScenarioError::UnknownFormat(_))
+        ));
+    }
+}
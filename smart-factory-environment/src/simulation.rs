@@ -0,0 +1,677 @@
+use crate::agent::{Agent, AsyncAgent, BoxedAgent};
+use crate::channel::{
+    incoming_channel, outgoing_channel, IncomingReceiver, IncomingSender, OutgoingSender,
+    OutgoingStream, DEFAULT_OUTGOING_CAPACITY,
+};
+use crate::environment::{
+    EnvironmentSettings, DEFAULT_ITER_COUNT_SLEEP, DEFAULT_MAX_ITER, DEFAULT_SLEEP_DURATION_MS,
+    DEFAULT_TICK,
+};
+use crate::error::{ErrorPolicy, EventEngineError};
+use crate::event::{Event, InjectedEventArg};
+use crate::introspection::{AgentQuery, AgentSnapshot, Introspect};
+use crate::message::{IncomingQueueMessage, InjectAt, ProgressRate};
+use crate::observer::Observer;
+use crate::pacing::{no_sleep, NoSleepFunction, Pacing};
+use crate::rng::DEFAULT_SEED;
+use crate::scenario::{validate_pacing, validate_progress, validate_warm_up, ValidationError};
+use crate::statistics::{Statistics, WarmUp};
+use crate::trace::{trace_sink, TraceRecorder, TraceReplay};
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Engine settings of a `Simulation`, set through `SimulationBuilder`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulationSettings {
+    pub seed: u64,
+    pub pacing: Pacing,
+    /// Simulated length of one tick.
+    pub tick: Duration,
+    /// Events between two sleeps of `Pacing::EventCount`.
+    pub iter_count: u64,
+    pub sleep_ms: u64,
+    /// Events dispatched before the run stops.
+    pub max_events: u64,
+    /// Simulated time after which the run stops.
+    pub stop_time: Option<u64>,
+    pub warm_up: WarmUp,
+    pub progress: ProgressRate,
+    pub error_policy: ErrorPolicy,
+    pub outgoing_capacity: usize,
+}
+
+impl Default for SimulationSettings {
+    fn default() -> Self {
+        SimulationSettings {
+            seed: DEFAULT_SEED,
+            pacing: Pacing::EventCount,
+            tick: DEFAULT_TICK,
+            iter_count: DEFAULT_ITER_COUNT_SLEEP,
+            sleep_ms: DEFAULT_SLEEP_DURATION_MS,
+            max_events: DEFAULT_MAX_ITER,
+            stop_time: None,
+            warm_up: WarmUp::None,
+            progress: ProgressRate::Never,
+            error_policy: ErrorPolicy::Abort,
+            outgoing_capacity: DEFAULT_OUTGOING_CAPACITY,
+        }
+    }
+}
+
+impl SimulationSettings {
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = vec![];
+        if self.iter_count == 0 {
+            errors.push(ValidationError::new(
+                "iter_count".to_string(),
+                "must be at least 1",
+            ));
+        }
+        validate_pacing(&self.pacing, "pacing", &mut errors);
+        if self.tick.is_zero() {
+            errors.push(ValidationError::new("tick".to_string(), "must be positive"));
+        }
+        validate_progress(self.progress, "progress", &mut errors);
+        validate_warm_up(&self.warm_up, "warm_up", &mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl EnvironmentSettings for SimulationSettings {
+    fn get_iter_count(&self) -> u64 {
+        self.iter_count
+    }
+
+    fn get_sleep_ms(&self) -> u64 {
+        self.sleep_ms
+    }
+
+    fn get_max_iter(&self) -> u64 {
+        self.max_events
+    }
+
+    fn get_stop_time(&self) -> Option<u64> {
+        self.stop_time
+    }
+
+    fn get_seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn get_warm_up(&self) -> WarmUp {
+        self.warm_up.clone()
+    }
+
+    fn get_pacing(&self) -> Pacing {
+        self.pacing.clone()
+    }
+
+    fn get_tick(&self) -> Duration {
+        self.tick
+    }
+
+    fn get_progress_rate(&self) -> ProgressRate {
+        self.progress
+    }
+
+    fn get_error_policy(&self) -> ErrorPolicy {
+        self.error_policy
+    }
+
+    fn get_outgoing_capacity(&self) -> usize {
+        self.outgoing_capacity
+    }
+}
+
+/// Log function of simulations that do not log.
+pub type NoLogFunction = fn(&str);
+
+fn no_log(_message: &str) {}
+
+/// Assembles a `Simulation`. Without `with_sleep` the simulation never waits for real time.
+pub struct SimulationBuilder<LogFunction = NoLogFunction, SleepFunction = NoSleepFunction> {
+    agents: Vec<BoxedAgent>,
+    events: Vec<(Event, u64)>,
+    observers: Vec<Box<dyn Observer>>,
+    settings: SimulationSettings,
+    trace: Option<TraceRecorder>,
+    replay: Option<TraceReplay>,
+    statistics: Statistics,
+    log: LogFunction,
+    sleep: SleepFunction,
+}
+
+impl SimulationBuilder {
+    pub fn new() -> SimulationBuilder {
+        SimulationBuilder {
+            agents: vec![],
+            events: vec![],
+            observers: vec![],
+            settings: SimulationSettings::default(),
+            trace: None,
+            replay: None,
+            statistics: Statistics::new(),
+            log: no_log,
+            sleep: no_sleep,
+        }
+    }
+}
+
+impl Default for SimulationBuilder {
+    fn default() -> Self {
+        SimulationBuilder::new()
+    }
+}
+
+impl<LogFunction, SleepFunction> SimulationBuilder<LogFunction, SleepFunction> {
+    pub fn with_agent(mut self, agent: impl Agent + 'static) -> Self {
+        self.agents.push(BoxedAgent::Sync(Box::new(agent)));
+        self
+    }
+
+    pub fn with_async_agent(mut self, agent: impl AsyncAgent + 'static) -> Self {
+        self.agents.push(BoxedAgent::Async(Box::new(agent)));
+        self
+    }
+
+    /// Agents of different types, e.g. `Box<dyn Agent>` or `BoxedAgent`.
+    pub fn with_agents<Agents>(mut self, agents: Agents) -> Self
+    where
+        Agents: IntoIterator,
+        Agents::Item: Into<BoxedAgent>,
+    {
+        self.agents.extend(agents.into_iter().map(Into::into));
+        self
+    }
+
+    /// Initial event dispatched at `time`.
+    pub fn with_event(mut self, event: Event, time: u64) -> Self {
+        self.events.push((event, time));
+        self
+    }
+
+    pub fn with_events(mut self, events: impl IntoIterator<Item = (Event, u64)>) -> Self {
+        self.events.extend(events);
+        self
+    }
+
+    pub fn with_observer(mut self, observer: Box<dyn Observer>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    pub fn with_settings(mut self, settings: SimulationSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.settings.seed = seed;
+        self
+    }
+
+    pub fn with_pacing(mut self, pacing: Pacing) -> Self {
+        self.settings.pacing = pacing;
+        self
+    }
+
+    /// Simulated length of one tick, e.g. `SimulationCalendar::get_tick`.
+    pub fn with_tick(mut self, tick: Duration) -> Self {
+        self.settings.tick = tick;
+        self
+    }
+
+    /// Sleep of `Pacing::EventCount`.
+    pub fn with_sleep_every(mut self, iter_count: u64, sleep_ms: u64) -> Self {
+        self.settings.iter_count = iter_count;
+        self.settings.sleep_ms = sleep_ms;
+        self
+    }
+
+    pub fn with_max_events(mut self, max_events: u64) -> Self {
+        self.settings.max_events = max_events;
+        self
+    }
+
+    /// Events after `stop_time` are not dispatched.
+    pub fn with_stop_time(mut self, stop_time: u64) -> Self {
+        self.settings.stop_time = Some(stop_time);
+        self
+    }
+
+    pub fn with_warm_up(mut self, warm_up: WarmUp) -> Self {
+        self.settings.warm_up = warm_up;
+        self
+    }
+
+    pub fn with_progress(mut self, progress: ProgressRate) -> Self {
+        self.settings.progress = progress;
+        self
+    }
+
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.settings.error_policy = error_policy;
+        self
+    }
+
+    pub fn with_outgoing_capacity(mut self, outgoing_capacity: usize) -> Self {
+        self.settings.outgoing_capacity = outgoing_capacity;
+        self
+    }
+
+    /// The run writes its dispatched events to `recorder`, takes precedence over a replay.
+    pub fn with_trace_recorder(mut self, recorder: TraceRecorder) -> Self {
+        self.trace = Some(recorder);
+        self
+    }
+
+    /// The run is checked against `replay` and stops at the first divergence.
+    pub fn with_trace_replay(mut self, replay: TraceReplay) -> Self {
+        self.replay = Some(replay);
+        self
+    }
+
+    /// Collectors the run reports to, e.g. a handle the agents already registered with.
+    pub fn with_statistics(mut self, statistics: Statistics) -> Self {
+        self.statistics = statistics;
+        self
+    }
+
+    pub fn agents(&self) -> &[BoxedAgent] {
+        &self.agents
+    }
+
+    pub(crate) fn take_trace_recorder(&mut self) -> Option<TraceRecorder> {
+        self.trace.take()
+    }
+
+    pub(crate) fn take_trace_replay(&mut self) -> Option<TraceReplay> {
+        self.replay.take()
+    }
+
+    /// Receives the errors of `ErrorPolicy::LogAndContinue`, everything else goes through
+    /// `tracing`.
+    pub fn with_log<Log>(self, log: Log) -> SimulationBuilder<Log, SleepFunction>
+    where
+        Log: FnMut(&str),
+    {
+        SimulationBuilder {
+            agents: self.agents,
+            events: self.events,
+            observers: self.observers,
+            settings: self.settings,
+            trace: self.trace,
+            replay: self.replay,
+            statistics: self.statistics,
+            log,
+            sleep: self.sleep,
+        }
+    }
+
+    pub fn with_sleep<Sleep, SleepFuture>(
+        self,
+        sleep: Sleep,
+    ) -> SimulationBuilder<LogFunction, Sleep>
+    where
+        Sleep: Fn(Duration) -> SleepFuture,
+        SleepFuture: Future<Output = ()>,
+    {
+        SimulationBuilder {
+            agents: self.agents,
+            events: self.events,
+            observers: self.observers,
+            settings: self.settings,
+            trace: self.trace,
+            replay: self.replay,
+            statistics: self.statistics,
+            log: self.log,
+            sleep,
+        }
+    }
+
+    /// Checks the settings, that agent ids are unique and that every initial event targets an
+    /// agent. The errors name the offending fields, e.g. `events[2].agent`.
+    pub fn build(self) -> Result<Simulation<LogFunction, SleepFunction>, Vec<ValidationError>> {
+        self.validate()?;
+        Ok(self.build_validated())
+    }
+
+    /// The checks of `build`, the builder is kept either way.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = self.settings.validate().err().unwrap_or_default();
+        let mut ids: HashMap<Uuid, usize> = HashMap::new();
+        for (index, agent) in self.agents.iter().enumerate() {
+            if let Some(first) = ids.insert(agent.get_id(), index) {
+                errors.push(ValidationError::new(
+                    format!("agents[{}]", index),
+                    &format!("has the same id as agents[{}]", first),
+                ));
+            }
+        }
+        for (index, (event, _)) in self.events.iter().enumerate() {
+            if !ids.contains_key(&event.agent) {
+                errors.push(ValidationError::new(
+                    format!("events[{}].agent", index),
+                    "is not an agent of the simulation",
+                ));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Builds a simulation that already passed `validate`.
+    pub(crate) fn build_validated(self) -> Simulation<LogFunction, SleepFunction> {
+        let (control, receiver) = incoming_channel();
+        let (sender, outgoing) = outgoing_channel(self.settings.outgoing_capacity);
+        Simulation {
+            agents: self.agents,
+            events: self.events,
+            observers: self.observers,
+            settings: self.settings,
+            trace: self.trace,
+            replay: self.replay,
+            log: self.log,
+            sleep: self.sleep,
+            statistics: self.statistics,
+            control,
+            receiver: Some(receiver),
+            sender: Some(sender),
+            outgoing: Some(outgoing),
+        }
+    }
+}
+
+/// Validated simulation, runs once. Use `controller` and `take_outgoing` before the run to
+/// control and watch it while it runs.
+pub struct Simulation<LogFunction, SleepFunction> {
+    agents: Vec<BoxedAgent>,
+    events: Vec<(Event, u64)>,
+    observers: Vec<Box<dyn Observer>>,
+    settings: SimulationSettings,
+    trace: Option<TraceRecorder>,
+    replay: Option<TraceReplay>,
+    log: LogFunction,
+    sleep: SleepFunction,
+    statistics: Statistics,
+    control: IncomingSender,
+    receiver: Option<IncomingReceiver>,
+    sender: Option<OutgoingSender>,
+    outgoing: Option<OutgoingStream>,
+}
+
+impl<LogFunction, SleepFunction> Simulation<LogFunction, SleepFunction> {
+    pub fn controller(&self) -> SimulationController {
+        SimulationController {
+            sender: self.control.clone(),
+        }
+    }
+
+    /// Messages of the run. They are kept until the stream is taken, the run fails if the taken
+    /// stream is dropped while it still sends.
+    pub fn take_outgoing(&mut self) -> Option<OutgoingStream> {
+        self.outgoing.take()
+    }
+
+    pub fn get_settings(&self) -> &SimulationSettings {
+        &self.settings
+    }
+
+    pub fn get_statistics(&self) -> Statistics {
+        self.statistics.clone()
+    }
+
+    pub fn agents(&self) -> &[BoxedAgent] {
+        &self.agents
+    }
+
+    /// Matching agents, use `SimulationController::query_agents` while the simulation runs.
+    pub fn snapshot_agents(&self, query: &AgentQuery) -> Vec<AgentSnapshot> {
+        snapshot_agents(&self.agents, query)
+    }
+
+    pub fn take_trace_recorder(&mut self) -> Option<TraceRecorder> {
+        self.trace.take()
+    }
+
+    pub fn take_trace_replay(&mut self) -> Option<TraceReplay> {
+        self.replay.take()
+    }
+
+    pub(crate) fn log(&mut self, message: &str)
+    where
+        LogFunction: FnMut(&str),
+    {
+        (self.log)(message)
+    }
+
+    /// Builder holding the agents, observers, traces and statistics of this simulation, together
+    /// with the initial events it did not run yet.
+    pub(crate) fn into_builder(self) -> SimulationBuilder<LogFunction, SleepFunction> {
+        SimulationBuilder {
+            agents: self.agents,
+            events: self.events,
+            observers: self.observers,
+            settings: self.settings,
+            trace: self.trace,
+            replay: self.replay,
+            statistics: self.statistics,
+            log: self.log,
+            sleep: self.sleep,
+        }
+    }
+
+    pub async fn run<SleepFuture>(&mut self) -> Result<(), EventEngineError>
+    where
+        LogFunction: FnMut(&str),
+        SleepFunction: Fn(Duration) -> SleepFuture,
+        SleepFuture: Future<Output = ()>,
+    {
+        let (receiver, sender) = match (self.receiver.take(), self.sender.take()) {
+            (Some(receiver), Some(sender)) => (receiver, sender),
+            _ => return Err(EventEngineError::AlreadyRun),
+        };
+        let trace = trace_sink(self.trace.as_mut(), self.replay.as_mut());
+        crate::event_queue::process_event_queue(
+            self.agents.iter_mut().map(BoxedAgent::slot),
+            std::mem::take(&mut self.events),
+            receiver,
+            &mut self.log,
+            &mut self.sleep,
+            self.settings.clone(),
+            sender,
+            Some(&self.statistics),
+            trace,
+            &mut self.observers,
+        )
+        .await
+    }
+
+    /// Runs on the current thread until the run stops.
+    pub fn run_to_completion<SleepFuture>(&mut self) -> Result<(), EventEngineError>
+    where
+        LogFunction: FnMut(&str),
+        SleepFunction: Fn(Duration) -> SleepFuture,
+        SleepFuture: Future<Output = ()>,
+    {
+        futures::executor::block_on(self.run())
+    }
+}
+
+/// Matching agents of `agents`, sorted by id.
+pub(crate) fn snapshot_agents(agents: &[BoxedAgent], query: &AgentQuery) -> Vec<AgentSnapshot> {
+    query.select(agents.iter().filter_map(|agent| {
+        agent
+            .introspect()
+            .map(|introspect| (agent.get_id(), introspect as &dyn Introspect))
+    }))
+}
+
+/// Controls a running simulation. Every method returns the message back once the run is over.
+#[derive(Clone)]
+pub struct SimulationController {
+    sender: IncomingSender,
+}
+
+impl SimulationController {
+    pub fn send(&self, message: IncomingQueueMessage) -> Result<(), IncomingQueueMessage> {
+        self.sender
+            .unbounded_send(message)
+            .map_err(|error| error.into_inner())
+    }
+
+    pub fn halt(&self) -> Result<(), IncomingQueueMessage> {
+        self.send(IncomingQueueMessage::Halt)
+    }
+
+    pub fn change_pacing(&self, pacing: Pacing) -> Result<(), IncomingQueueMessage> {
+        self.send(IncomingQueueMessage::ChangePacing(pacing))
+    }
+
+    pub fn change_max_events(&self, max_events: u64) -> Result<(), IncomingQueueMessage> {
+        self.send(IncomingQueueMessage::ChangeMaxIter(max_events))
+    }
+
+    /// Answered with `OutgoingQueueMessage::Agents`.
+    pub fn query_agents(&self, query: AgentQuery) -> Result<(), IncomingQueueMessage> {
+        self.send(IncomingQueueMessage::QueryAgents(query))
+    }
+
+    pub fn inject_event(
+        &self,
+        target: Uuid,
+        at: InjectAt,
+        args: InjectedEventArg,
+    ) -> Result<(), IncomingQueueMessage> {
+        self.send(IncomingQueueMessage::InjectEvent { target, at, args })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::NewEventsVec;
+    use crate::event::EventArg;
+    use crate::message::OutgoingQueueMessage;
+    use crate::observer::FinishReason;
+    use futures::StreamExt;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const TICKER: Uuid = Uuid::from_u128(1);
+    const SINK: Uuid = Uuid::from_u128(2);
+
+    /// Sends an event to the sink every tick.
+    struct Ticker;
+
+    impl Agent for Ticker {
+        fn handle(&mut self, time: u64, _args: EventArg) -> NewEventsVec {
+            vec![(Event::new(TICKER), time + 1), (Event::new(SINK), time)]
+        }
+
+        fn get_id(&self) -> Uuid {
+            TICKER
+        }
+    }
+
+    struct Sink {
+        received: Rc<RefCell<Vec<u64>>>,
+    }
+
+    impl Agent for Sink {
+        fn handle(&mut self, time: u64, _args: EventArg) -> NewEventsVec {
+            self.received.borrow_mut().push(time);
+            vec![]
+        }
+
+        fn get_id(&self) -> Uuid {
+            SINK
+        }
+    }
+
+    fn builder(received: &Rc<RefCell<Vec<u64>>>) -> SimulationBuilder {
+        SimulationBuilder::new()
+            .with_agent(Ticker)
+            .with_agent(Sink {
+                received: received.clone(),
+            })
+            .with_event(Event::new(TICKER), 0)
+    }
+
+    #[test]
+    fn simulations_stop_at_the_stop_time() {
+        let received = Rc::new(RefCell::new(vec![]));
+        let mut simulation = builder(&received).with_stop_time(3).build().unwrap();
+        let mut outgoing = simulation.take_outgoing().unwrap();
+        assert!(simulation.run_to_completion().is_ok());
+        assert_eq!(*received.borrow(), vec![0, 1, 2, 3]);
+        assert!(matches!(
+            outgoing.try_iter().last(),
+            Some(OutgoingQueueMessage::Finished(FinishReason::StopTime))
+        ));
+        assert!(matches!(
+            simulation.run_to_completion(),
+            Err(EventEngineError::AlreadyRun)
+        ));
+    }
+
+    #[test]
+    fn builders_report_every_invalid_field() {
+        let received = Rc::new(RefCell::new(vec![]));
+        let errors = builder(&received)
+            .with_agent(Ticker)
+            .with_event(Event::new(Uuid::from_u128(3)), 0)
+            .with_pacing(Pacing::Yield { every: 0 })
+            .with_sleep_every(0, 10)
+            .build()
+            .err()
+            .unwrap();
+        let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "iter_count: must be at least 1",
+                "pacing.every: must be at least 1",
+                "agents[2]: has the same id as agents[0]",
+                "events[1].agent: is not an agent of the simulation",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn controllers_halt_running_simulations() {
+        let received = Rc::new(RefCell::new(vec![]));
+        let mut simulation = builder(&received)
+            .with_pacing(Pacing::Yield { every: 1 })
+            .build()
+            .unwrap();
+        let controller = simulation.controller();
+        let mut outgoing = simulation.take_outgoing().unwrap();
+        let watch = async {
+            let mut messages = 0;
+            while let Some(message) = outgoing.next().await {
+                messages += 1;
+                if messages == 1 {
+                    assert!(controller.halt().is_ok());
+                }
+                if let OutgoingQueueMessage::Finished(reason) = message {
+                    return reason;
+                }
+            }
+            panic!("Stream ended without a finished message");
+        };
+        let (result, reason) = futures::join!(simulation.run(), watch);
+        assert!(result.is_ok());
+        assert_eq!(reason, FinishReason::Halted);
+        assert!(controller.halt().is_err());
+    }
+}
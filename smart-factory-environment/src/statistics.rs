@@ -0,0 +1,968 @@
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+pub const DEFAULT_QUANTILES: [f64; 3] = [0.5, 0.9, 0.95];
+
+/// Updates kept for `WarmUp::Mser5` before its truncation point is fixed.
+pub const MSER5_JOURNAL_LIMIT: usize = 100_000;
+
+/// Streaming estimate of a single quantile (P² algorithm by Jain and Chlamtac).
+/// Uses constant memory no matter how many observations were recorded.
+#[derive(Clone, Debug)]
+struct P2Quantile {
+    p: f64,
+    heights: [f64; 5],
+    positions: [f64; 5],
+    desired: [f64; 5],
+    increments: [f64; 5],
+    count: usize,
+}
+
+impl P2Quantile {
+    fn new(p: f64) -> P2Quantile {
+        P2Quantile {
+            p,
+            heights: [0.0; 5],
+            positions: [1.0, 2.0, 3.0, 4.0, 5.0],
+            desired: [1.0, 1.0 + 2.0 * p, 1.0 + 4.0 * p, 3.0 + 2.0 * p, 5.0],
+            increments: [0.0, p / 2.0, p, (1.0 + p) / 2.0, 1.0],
+            count: 0,
+        }
+    }
+
+    fn record(&mut self, value: f64) {
+        if self.count < 5 {
+            self.heights[self.count] = value;
+            self.count += 1;
+            if self.count == 5 {
+                self.heights.sort_by(f64::total_cmp);
+            }
+            return;
+        }
+        self.count += 1;
+
+        let cell = if value < self.heights[0] {
+            self.heights[0] = value;
+            0
+        } else if value >= self.heights[4] {
+            self.heights[4] = value;
+            3
+        } else {
+            (0..4).find(|&i| value < self.heights[i + 1]).unwrap_or(3)
+        };
+        for position in &mut self.positions[cell + 1..] {
+            *position += 1.0;
+        }
+        for (desired, increment) in self.desired.iter_mut().zip(self.increments) {
+            *desired += increment;
+        }
+
+        for i in 1..4 {
+            let delta = self.desired[i] - self.positions[i];
+            if (delta >= 1.0 && self.positions[i + 1] - self.positions[i] > 1.0)
+                || (delta <= -1.0 && self.positions[i - 1] - self.positions[i] < -1.0)
+            {
+                let sign = delta.signum();
+                let parabolic = self.parabolic(i, sign);
+                self.heights[i] =
+                    if self.heights[i - 1] < parabolic && parabolic < self.heights[i + 1] {
+                        parabolic
+                    } else {
+                        self.linear(i, sign)
+                    };
+                self.positions[i] += sign;
+            }
+        }
+    }
+
+    fn parabolic(&self, i: usize, sign: f64) -> f64 {
+        let (q, n) = (&self.heights, &self.positions);
+        q[i] + sign / (n[i + 1] - n[i - 1])
+            * ((n[i] - n[i - 1] + sign) * (q[i + 1] - q[i]) / (n[i + 1] - n[i])
+                + (n[i + 1] - n[i] - sign) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]))
+    }
+
+    fn linear(&self, i: usize, sign: f64) -> f64 {
+        let other = if sign > 0.0 { i + 1 } else { i - 1 };
+        self.heights[i]
+            + sign * (self.heights[other] - self.heights[i])
+                / (self.positions[other] - self.positions[i])
+    }
+
+    fn estimate(&self) -> f64 {
+        if self.count >= 5 {
+            return self.heights[2];
+        }
+        if self.count == 0 {
+            return 0.0;
+        }
+        let mut seen = self.heights[..self.count].to_vec();
+        seen.sort_by(f64::total_cmp);
+        seen[((self.count - 1) as f64 * self.p).round() as usize]
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TallySummary {
+    pub count: u64,
+    pub mean: f64,
+    pub variance: f64,
+    pub min: f64,
+    pub max: f64,
+    /// Pairs of (quantile, estimated value).
+    pub quantiles: Vec<(f64, f64)>,
+}
+
+/// Collects independent observations, e.g. waiting times of parts.
+#[derive(Clone, Debug)]
+pub struct Tally {
+    count: u64,
+    mean: f64,
+    squared_deviations: f64,
+    min: f64,
+    max: f64,
+    quantiles: Vec<P2Quantile>,
+}
+
+impl Default for Tally {
+    fn default() -> Self {
+        Tally::new(&DEFAULT_QUANTILES)
+    }
+}
+
+impl Tally {
+    pub fn new(quantiles: &[f64]) -> Tally {
+        Tally {
+            count: 0,
+            mean: 0.0,
+            squared_deviations: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            quantiles: quantiles.iter().map(|p| P2Quantile::new(*p)).collect(),
+        }
+    }
+
+    pub fn record(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.squared_deviations += delta * (value - self.mean);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        for quantile in &mut self.quantiles {
+            quantile.record(value);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        let quantiles: Vec<f64> = self.quantiles.iter().map(|quantile| quantile.p).collect();
+        *self = Tally::new(&quantiles);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn summary(&self) -> TallySummary {
+        if self.count == 0 {
+            return TallySummary {
+                quantiles: self
+                    .quantiles
+                    .iter()
+                    .map(|quantile| (quantile.p, 0.0))
+                    .collect(),
+                ..TallySummary::default()
+            };
+        }
+        TallySummary {
+            count: self.count,
+            mean: self.mean,
+            variance: if self.count > 1 {
+                self.squared_deviations / (self.count - 1) as f64
+            } else {
+                0.0
+            },
+            min: self.min,
+            max: self.max,
+            quantiles: self
+                .quantiles
+                .iter()
+                .map(|quantile| (quantile.p, quantile.estimate()))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TimeWeightedSummary {
+    pub duration: u64,
+    pub mean: f64,
+    pub variance: f64,
+    pub min: f64,
+    pub max: f64,
+    pub current: f64,
+}
+
+/// Tracks a piecewise constant value over simulated time, e.g. a queue length or machine occupancy.
+#[derive(Clone, Debug)]
+pub struct TimeWeighted {
+    start: u64,
+    last_change: u64,
+    value: f64,
+    area: f64,
+    squared_area: f64,
+    min: f64,
+    max: f64,
+}
+
+impl TimeWeighted {
+    pub fn new(start: u64, initial: f64) -> TimeWeighted {
+        TimeWeighted {
+            start,
+            last_change: start,
+            value: initial,
+            area: 0.0,
+            squared_area: 0.0,
+            min: initial,
+            max: initial,
+        }
+    }
+
+    pub fn set(&mut self, time: u64, value: f64) {
+        self.advance(time);
+        self.value = value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn add(&mut self, time: u64, delta: f64) {
+        self.set(time, self.value + delta);
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    /// Forgets the history, keeping the current value as the new starting point.
+    pub fn reset(&mut self, time: u64) {
+        *self = TimeWeighted::new(time.max(self.last_change), self.value);
+    }
+
+    pub fn summary(&self, now: u64) -> TimeWeightedSummary {
+        let elapsed = now.saturating_sub(self.last_change) as f64;
+        let duration = now.max(self.last_change) - self.start;
+        let area = self.area + self.value * elapsed;
+        let squared_area = self.squared_area + self.value * self.value * elapsed;
+        let (mean, variance) = if duration == 0 {
+            (self.value, 0.0)
+        } else {
+            let mean = area / duration as f64;
+            (
+                mean,
+                (squared_area / duration as f64 - mean * mean).max(0.0),
+            )
+        };
+        TimeWeightedSummary {
+            duration,
+            mean,
+            variance,
+            min: self.min,
+            max: self.max,
+            current: self.value,
+        }
+    }
+
+    fn advance(&mut self, time: u64) {
+        let elapsed = time.saturating_sub(self.last_change) as f64;
+        self.area += self.value * elapsed;
+        self.squared_area += self.value * self.value * elapsed;
+        self.last_change = self.last_change.max(time);
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StatisticsReport {
+    pub time: u64,
+    /// Time from which the collected values are reported, if the warm-up period was discarded.
+    pub truncation_point: Option<u64>,
+    pub tallies: BTreeMap<String, TallySummary>,
+    pub time_weighted: BTreeMap<String, TimeWeightedSummary>,
+}
+
+impl StatisticsReport {
+    /// Single-level view of the report (`"<name>.<measure>" -> value`), handy for comparing runs.
+    /// Quantiles are named by their percentage with at most one decimal, e.g. `p95` or `p99.5`.
+    /// Keys are unique because `Statistics` never uses a name for collectors of both kinds.
+    pub fn flatten(&self) -> BTreeMap<String, f64> {
+        let mut values = BTreeMap::new();
+        for (name, tally) in &self.tallies {
+            values.insert(format!("{}.count", name), tally.count as f64);
+            values.insert(format!("{}.mean", name), tally.mean);
+            values.insert(format!("{}.variance", name), tally.variance);
+            values.insert(format!("{}.min", name), tally.min);
+            values.insert(format!("{}.max", name), tally.max);
+            for (p, value) in &tally.quantiles {
+                values.insert(format!("{}.p{}", name, (p * 1000.0).round() / 10.0), *value);
+            }
+        }
+        for (name, collector) in &self.time_weighted {
+            values.insert(format!("{}.mean", name), collector.mean);
+            values.insert(format!("{}.variance", name), collector.variance);
+            values.insert(format!("{}.min", name), collector.min);
+            values.insert(format!("{}.max", name), collector.max);
+        }
+        values
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarmUp {
+    #[default]
+    None,
+    /// All collectors are reset once simulated time reaches the given tick.
+    Fixed(u64),
+    /// Truncation point is found by MSER-5 over the observations of the named tally. Updates are
+    /// kept in memory and replayed from that point for reports, until the run ends or
+    /// `MSER5_JOURNAL_LIMIT` updates were made. Then the truncation point is fixed and all
+    /// collectors, including reset listeners, are reset at it.
+    Mser5(String),
+}
+
+enum Update {
+    RegisterTally(Vec<f64>),
+    RegisterTimeWeighted(f64),
+    Record(f64),
+    SetLevel(f64),
+    AddLevel(f64),
+}
+
+#[derive(Default)]
+struct Collectors {
+    tallies: BTreeMap<String, Tally>,
+    time_weighted: BTreeMap<String, TimeWeighted>,
+}
+
+impl Collectors {
+    // Flattened reports would mix up the measures of a tally and a time-weighted value of the
+    // same name
+    fn clashes(&self, name: &str, update: &Update) -> bool {
+        match update {
+            Update::RegisterTally(_) | Update::Record(_) => self.time_weighted.contains_key(name),
+            Update::RegisterTimeWeighted(_) | Update::SetLevel(_) | Update::AddLevel(_) => {
+                self.tallies.contains_key(name)
+            }
+        }
+    }
+
+    fn apply(&mut self, name: &str, time: u64, update: &Update) {
+        match update {
+            Update::RegisterTally(quantiles) => {
+                self.tallies.insert(name.to_string(), Tally::new(quantiles));
+            }
+            Update::RegisterTimeWeighted(initial) => {
+                self.time_weighted
+                    .insert(name.to_string(), TimeWeighted::new(time, *initial));
+            }
+            Update::Record(value) => self
+                .tallies
+                .entry(name.to_string())
+                .or_default()
+                .record(*value),
+            Update::SetLevel(value) => self.time_weighted(name, time).set(time, *value),
+            Update::AddLevel(delta) => self.time_weighted(name, time).add(time, *delta),
+        }
+    }
+
+    fn time_weighted(&mut self, name: &str, time: u64) -> &mut TimeWeighted {
+        self.time_weighted
+            .entry(name.to_string())
+            .or_insert_with(|| TimeWeighted::new(time, 0.0))
+    }
+
+    fn reset(&mut self, time: u64) {
+        self.tallies.values_mut().for_each(Tally::reset);
+        self.time_weighted
+            .values_mut()
+            .for_each(|collector| collector.reset(time));
+    }
+
+    // Registration updates recreating the collectors in their current state
+    fn snapshot(&self) -> Vec<(String, u64, Update)> {
+        let tallies = self.tallies.iter().map(|(name, tally)| {
+            let quantiles = tally.quantiles.iter().map(|quantile| quantile.p).collect();
+            (name.clone(), 0, Update::RegisterTally(quantiles))
+        });
+        let time_weighted = self.time_weighted.iter().map(|(name, collector)| {
+            (
+                name.clone(),
+                collector.last_change,
+                Update::RegisterTimeWeighted(collector.value),
+            )
+        });
+        tallies.chain(time_weighted).collect()
+    }
+
+    fn report(&self, now: u64, truncation_point: Option<u64>) -> StatisticsReport {
+        StatisticsReport {
+            time: now,
+            truncation_point,
+            tallies: self
+                .tallies
+                .iter()
+                .map(|(name, tally)| (name.clone(), tally.summary()))
+                .collect(),
+            time_weighted: self
+                .time_weighted
+                .iter()
+                .map(|(name, collector)| (name.clone(), collector.summary(now)))
+                .collect(),
+        }
+    }
+}
+
+/// Number of leading observations MSER-5 suggests to delete.
+pub fn mser5_truncation(observations: &[f64]) -> usize {
+    let batches: Vec<f64> = observations
+        .chunks_exact(5)
+        .map(|batch| batch.iter().sum::<f64>() / 5.0)
+        .collect();
+    let mut best = (f64::INFINITY, 0);
+    for deleted in 0..=batches.len() / 2 {
+        let kept = &batches[deleted..];
+        if kept.is_empty() {
+            break;
+        }
+        let count = kept.len() as f64;
+        let mean = kept.iter().sum::<f64>() / count;
+        let squared_errors: f64 = kept.iter().map(|batch| (batch - mean).powi(2)).sum();
+        let statistic = squared_errors / (count * count);
+        if statistic < best.0 {
+            best = (statistic, deleted);
+        }
+    }
+    best.1 * 5
+}
+
+type WarmUpListener = Box<dyn FnMut(&WarmUp)>;
+
+#[derive(Default)]
+struct StatisticsRegistry {
+    collectors: Collectors,
+    time: u64,
+    warm_up: WarmUp,
+    truncation_point: Option<u64>,
+    journal: Vec<(String, u64, Update)>,
+    // MSER-5 truncation point is fixed, updates are not journaled anymore
+    settled: bool,
+    reset_listeners: Vec<Box<dyn FnMut(u64)>>,
+    warm_up_listeners: Vec<WarmUpListener>,
+    settle_listeners: Vec<Box<dyn FnMut()>>,
+}
+
+impl StatisticsRegistry {
+    fn update(&mut self, name: &str, time: u64, update: Update) {
+        if self.collectors.clashes(name, &update) {
+            tracing::warn!(
+                name,
+                "Statistic is already used by another kind of collector, ignored"
+            );
+            return;
+        }
+        self.collectors.apply(name, time, &update);
+        if self.journaling() {
+            self.journal.push((name.to_string(), time, update));
+            if self.journal.len() >= MSER5_JOURNAL_LIMIT {
+                self.settle_mser5();
+            }
+        }
+    }
+
+    fn journaling(&self) -> bool {
+        matches!(self.warm_up, WarmUp::Mser5(_)) && !self.settled
+    }
+
+    fn reset(&mut self, time: u64) {
+        self.collectors.reset(time);
+        self.truncation_point = Some(time);
+        if self.journaling() {
+            self.journal = self.collectors.snapshot();
+        }
+        self.reset_listeners
+            .iter_mut()
+            .for_each(|listener| listener(time));
+    }
+
+    // Resets everything at the MSER-5 truncation point found so far and stops journaling
+    fn settle_mser5(&mut self) {
+        self.settled = true;
+        if let Some((truncation_point, collectors)) = self.mser5_replay() {
+            self.collectors = collectors;
+            self.truncation_point = Some(truncation_point);
+            self.reset_listeners
+                .iter_mut()
+                .for_each(|listener| listener(truncation_point));
+        }
+        self.journal = vec![];
+        self.settle_listeners
+            .iter_mut()
+            .for_each(|listener| listener());
+    }
+
+    fn report(&self, now: u64) -> StatisticsReport {
+        if self.journaling() {
+            if let Some((truncation_point, collectors)) = self.mser5_replay() {
+                return collectors.report(now, Some(truncation_point));
+            }
+        }
+        self.collectors.report(now, self.truncation_point)
+    }
+
+    // Collectors restarted at the MSER-5 truncation point, none if nothing is truncated
+    fn mser5_replay(&self) -> Option<(u64, Collectors)> {
+        let WarmUp::Mser5(metric) = &self.warm_up else {
+            return None;
+        };
+        let observations: Vec<(u64, f64)> = self
+            .journal
+            .iter()
+            .filter_map(|(name, time, update)| match update {
+                Update::Record(value) if name == metric => Some((*time, *value)),
+                _ => None,
+            })
+            .collect();
+        let values: Vec<f64> = observations.iter().map(|(_, value)| *value).collect();
+        let deleted = mser5_truncation(&values);
+        if deleted == 0 {
+            return None;
+        }
+
+        let truncation_point = observations[deleted].0;
+        let mut replayed = Collectors::default();
+        let mut was_reset = false;
+        for (name, time, update) in &self.journal {
+            if !was_reset && *time >= truncation_point {
+                replayed.reset(truncation_point);
+                was_reset = true;
+            }
+            replayed.apply(name, *time, update);
+        }
+        Some((truncation_point, replayed))
+    }
+}
+
+/// Named collectors shared by all agents of a run. Cloning gives another handle to the same registry.
+#[derive(Clone, Default)]
+pub struct Statistics {
+    registry: Rc<RefCell<StatisticsRegistry>>,
+}
+
+impl Statistics {
+    pub fn new() -> Statistics {
+        Statistics::default()
+    }
+
+    pub fn register_tally(&self, name: &str, quantiles: &[f64]) {
+        let mut registry = self.registry.borrow_mut();
+        let time = registry.time;
+        registry.update(name, time, Update::RegisterTally(quantiles.to_vec()));
+    }
+
+    pub fn register_time_weighted(&self, name: &str, start: u64, initial: f64) {
+        self.registry
+            .borrow_mut()
+            .update(name, start, Update::RegisterTimeWeighted(initial));
+    }
+
+    /// Records an observation, registering a tally with the default quantiles if needed.
+    pub fn record(&self, name: &str, value: f64) {
+        let mut registry = self.registry.borrow_mut();
+        let time = registry.time;
+        registry.update(name, time, Update::Record(value));
+    }
+
+    /// Changes a time-weighted value, registering it (starting at zero from `time`) if needed.
+    pub fn set_level(&self, name: &str, time: u64, value: f64) {
+        self.registry
+            .borrow_mut()
+            .update(name, time, Update::SetLevel(value));
+    }
+
+    pub fn add_level(&self, name: &str, time: u64, delta: f64) {
+        self.registry
+            .borrow_mut()
+            .update(name, time, Update::AddLevel(delta));
+    }
+
+    /// Called by the engine at the start of a run.
+    pub fn configure_warm_up(&self, warm_up: WarmUp) {
+        let mut registry = self.registry.borrow_mut();
+        registry.journal = match warm_up {
+            WarmUp::Mser5(_) => registry.collectors.snapshot(),
+            _ => vec![],
+        };
+        registry
+            .warm_up_listeners
+            .iter_mut()
+            .for_each(|listener| listener(&warm_up));
+        registry.warm_up = warm_up;
+        registry.truncation_point = None;
+        registry.settled = false;
+    }
+
+    /// Called by the engine at the end of a run, fixes the MSER-5 truncation point so that reset
+    /// listeners discard the warm-up period as well.
+    pub fn finish_warm_up(&self) {
+        let mut registry = self.registry.borrow_mut();
+        if registry.journaling() {
+            registry.settle_mser5();
+        }
+    }
+
+    /// Called by the engine before each dispatched event, resets the collectors when the warm-up is over.
+    pub fn advance(&self, time: u64) {
+        let mut registry = self.registry.borrow_mut();
+        registry.time = time;
+        if let WarmUp::Fixed(warm_up) = registry.warm_up {
+            if time >= warm_up && registry.truncation_point.is_none() {
+                registry.reset(warm_up);
+            }
+        }
+    }
+
+    /// Simulated time of the last dispatched event.
+    pub fn time(&self) -> u64 {
+        self.registry.borrow().time
+    }
+
+    /// `listener` is called with the reset time, so that collectors living outside of the registry
+    /// (e.g. production KPIs) can discard the warm-up period as well. Under `WarmUp::Mser5` the
+    /// reset time lies in the past.
+    pub fn add_reset_listener(&self, listener: impl FnMut(u64) + 'static) {
+        self.registry
+            .borrow_mut()
+            .reset_listeners
+            .push(Box::new(listener));
+    }
+
+    /// `listener` is called with the warm-up of every run before it starts, e.g. to keep the
+    /// updates an MSER-5 reset has to go back through.
+    pub fn add_warm_up_listener(&self, listener: impl FnMut(&WarmUp) + 'static) {
+        self.registry
+            .borrow_mut()
+            .warm_up_listeners
+            .push(Box::new(listener));
+    }
+
+    /// `listener` is called once the MSER-5 truncation point is fixed, after the reset listeners
+    /// if anything was truncated, e.g. to stop keeping updates.
+    pub fn add_settle_listener(&self, listener: impl FnMut() + 'static) {
+        self.registry
+            .borrow_mut()
+            .settle_listeners
+            .push(Box::new(listener));
+    }
+
+    pub fn reset(&self, time: u64) {
+        self.registry.borrow_mut().reset(time);
+    }
+
+    pub fn report(&self, now: u64) -> StatisticsReport {
+        self.registry.borrow().report(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, NewEventsVec};
+    use crate::channel::{incoming_channel, outgoing_channel, DEFAULT_OUTGOING_CAPACITY};
+    use crate::environment::EnvironmentSettings;
+    use crate::event::{Event, EventArg};
+    use crate::event_queue::process_event_queue;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use uuid::Uuid;
+
+    #[test]
+    fn tally_computes_moments() {
+        let mut tally = Tally::default();
+        for value in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0] {
+            tally.record(value);
+        }
+        let summary = tally.summary();
+        assert_eq!(summary.count, 8);
+        assert_eq!(summary.mean, 5.0);
+        assert!((summary.variance - 32.0 / 7.0).abs() < 1e-12);
+        assert_eq!(summary.min, 2.0);
+        assert_eq!(summary.max, 9.0);
+    }
+
+    #[test]
+    fn tally_estimates_quantiles() {
+        let mut tally = Tally::new(&[0.5, 0.9, 0.99]);
+        // Deterministic permutation of 1..=10000
+        for i in 0..10000u64 {
+            tally.record(((i * 7919) % 10000 + 1) as f64);
+        }
+        let summary = tally.summary();
+        for (p, estimate) in summary.quantiles {
+            let exact = p * 10000.0;
+            assert!(
+                (estimate - exact).abs() < 100.0,
+                "p{} estimated as {}",
+                p,
+                estimate
+            );
+        }
+    }
+
+    #[test]
+    fn tally_with_few_values_uses_exact_quantiles() {
+        let mut tally = Tally::new(&[0.5]);
+        tally.record(3.0);
+        tally.record(1.0);
+        tally.record(2.0);
+        assert_eq!(tally.summary().quantiles, vec![(0.5, 2.0)]);
+    }
+
+    #[test]
+    fn time_weighted_averages_over_time() {
+        let mut queue = TimeWeighted::new(0, 0.0);
+        queue.set(2, 3.0);
+        queue.add(6, -2.0);
+        let summary = queue.summary(10);
+        // 0 for 2 ticks, 3 for 4 ticks, 1 for 4 ticks
+        assert_eq!(summary.mean, 1.6);
+        assert!((summary.variance - (40.0 / 10.0 - 1.6 * 1.6)).abs() < 1e-12);
+        assert_eq!(summary.max, 3.0);
+        assert_eq!(summary.current, 1.0);
+
+        queue.reset(10);
+        let summary = queue.summary(20);
+        assert_eq!(summary.mean, 1.0);
+        assert_eq!(summary.min, 1.0);
+        assert_eq!(summary.duration, 10);
+    }
+
+    #[tokio::test]
+    pub async fn agents_update_collectors_by_name() {
+        struct QueueAgent {
+            id: Uuid,
+            statistics: Statistics,
+        }
+
+        impl Agent for QueueAgent {
+            fn handle(&mut self, time: u64, _args: EventArg) -> NewEventsVec {
+                self.statistics.record("service", (time % 3) as f64);
+                self.statistics.add_level("queue", time, 1.0);
+                if time < 9 {
+                    vec![(Event::new(self.id), time + 1)]
+                } else {
+                    vec![]
+                }
+            }
+
+            fn get_id(&self) -> Uuid {
+                self.id
+            }
+        }
+
+        struct TestSettings {}
+        impl EnvironmentSettings for TestSettings {}
+
+        let statistics = Statistics::new();
+        statistics.register_time_weighted("queue", 0, 0.0);
+        let agent_id = Uuid::new_v4();
+        let mut agent = QueueAgent {
+            id: agent_id,
+            statistics: statistics.clone(),
+        };
+        let (_send, recv) = incoming_channel();
+        let (send, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        let result = process_event_queue(
+            Agent::solo_vec(&mut agent),
+            vec![(Event::new(agent_id), 0)],
+            recv,
+            &mut |_| {},
+            &mut |_| async {},
+            TestSettings {},
+            send,
+            Some(&statistics),
+            None,
+            &mut [],
+        )
+        .await;
+        assert!(result.is_ok());
+
+        let report = statistics.report(10);
+        assert_eq!(report.tallies["service"].count, 10);
+        assert_eq!(report.time_weighted["queue"].current, 10.0);
+        assert_eq!(report.time_weighted["queue"].mean, 5.5);
+        let flat = report.flatten();
+        assert_eq!(flat["service.count"], 10.0);
+        assert_eq!(flat["queue.mean"], 5.5);
+        assert!(flat.contains_key("service.p50"));
+        assert!(flat.contains_key("service.p95"));
+    }
+
+    #[test]
+    fn names_are_not_shared_between_collector_kinds() {
+        let statistics = Statistics::new();
+        statistics.register_tally("queue", &[0.995]);
+        statistics.record("queue", 1.0);
+        statistics.set_level("queue", 0, 5.0);
+        statistics.register_time_weighted("wip", 0, 2.0);
+        statistics.record("wip", 3.0);
+
+        let report = statistics.report(10);
+        assert!(!report.time_weighted.contains_key("queue"));
+        assert!(!report.tallies.contains_key("wip"));
+        let flat = report.flatten();
+        assert_eq!(flat["queue.mean"], 1.0);
+        assert_eq!(flat["queue.p99.5"], 1.0);
+        assert_eq!(flat["wip.mean"], 2.0);
+    }
+
+    struct RecordingAgent {
+        id: Uuid,
+        statistics: Statistics,
+    }
+
+    impl Agent for RecordingAgent {
+        fn handle(&mut self, time: u64, _args: EventArg) -> NewEventsVec {
+            self.statistics.record("value", time as f64);
+            self.statistics.set_level("level", time, time as f64);
+            if time < 9 {
+                vec![(Event::new(self.id), time + 1)]
+            } else {
+                vec![]
+            }
+        }
+
+        fn get_id(&self) -> Uuid {
+            self.id
+        }
+    }
+
+    #[tokio::test]
+    pub async fn fixed_warm_up_resets_collectors() {
+        struct WarmUpSettings {}
+        impl EnvironmentSettings for WarmUpSettings {
+            fn get_warm_up(&self) -> WarmUp {
+                WarmUp::Fixed(5)
+            }
+        }
+
+        let statistics = Statistics::new();
+        let reset_at = Rc::new(RefCell::new(None));
+        let listener_reset_at = reset_at.clone();
+        statistics.add_reset_listener(move |time| *listener_reset_at.borrow_mut() = Some(time));
+        let agent_id = Uuid::new_v4();
+        let mut agent = RecordingAgent {
+            id: agent_id,
+            statistics: statistics.clone(),
+        };
+        let (_send, recv) = incoming_channel();
+        let (send, _recv) = outgoing_channel(DEFAULT_OUTGOING_CAPACITY);
+        let result = process_event_queue(
+            Agent::solo_vec(&mut agent),
+            vec![(Event::new(agent_id), 0)],
+            recv,
+            &mut |_| {},
+            &mut |_| async {},
+            WarmUpSettings {},
+            send,
+            Some(&statistics),
+            None,
+            &mut [],
+        )
+        .await;
+        assert!(result.is_ok());
+
+        let report = statistics.report(10);
+        assert_eq!(report.truncation_point, Some(5));
+        assert_eq!(*reset_at.borrow(), Some(5));
+        assert_eq!(report.tallies["value"].count, 5);
+        assert_eq!(report.tallies["value"].mean, 7.0);
+        // Levels 5..=9 held for one tick each
+        assert_eq!(report.time_weighted["level"].mean, 7.0);
+        assert_eq!(report.time_weighted["level"].min, 4.0);
+    }
+
+    #[test]
+    fn mser5_deletes_initial_transient() {
+        let mut observations = vec![100.0; 50];
+        observations.extend((0..500).map(|i| if i % 2 == 0 { 10.0 } else { 12.0 }));
+        assert_eq!(mser5_truncation(&observations), 50);
+        assert_eq!(mser5_truncation(&observations[50..]), 0);
+        assert_eq!(mser5_truncation(&[1.0, 2.0, 3.0]), 0);
+    }
+
+    #[test]
+    fn mser5_warm_up_is_applied_to_all_collectors() {
+        let statistics = Statistics::new();
+        statistics.configure_warm_up(WarmUp::Mser5("lead time".to_string()));
+        for time in 0..300u64 {
+            statistics.advance(time);
+            let transient = time < 100;
+            statistics.record(
+                "lead time",
+                if transient {
+                    50.0
+                } else {
+                    5.0 + (time % 2) as f64
+                },
+            );
+            statistics.record("other", if transient { 1.0 } else { 2.0 });
+            statistics.set_level("queue", time, if transient { 10.0 } else { 1.0 });
+        }
+        let report = statistics.report(300);
+        assert_eq!(report.truncation_point, Some(100));
+        assert_eq!(report.tallies["lead time"].count, 200);
+        assert_eq!(report.tallies["lead time"].mean, 5.5);
+        assert_eq!(report.tallies["other"].mean, 2.0);
+        assert_eq!(report.time_weighted["queue"].mean, 1.0);
+        assert_eq!(report.time_weighted["queue"].duration, 200);
+    }
+
+    #[test]
+    fn mser5_truncation_is_fixed_for_reset_listeners() {
+        let statistics = Statistics::new();
+        let reset_at = Rc::new(RefCell::new(vec![]));
+        let listener_reset_at = reset_at.clone();
+        statistics.add_reset_listener(move |time| listener_reset_at.borrow_mut().push(time));
+        statistics.configure_warm_up(WarmUp::Mser5("lead time".to_string()));
+        for time in 0..300u64 {
+            statistics.advance(time);
+            statistics.record("lead time", if time < 100 { 50.0 } else { 5.0 });
+        }
+        assert!(reset_at.borrow().is_empty());
+        let replayed = statistics.report(300);
+
+        statistics.finish_warm_up();
+        assert_eq!(*reset_at.borrow(), vec![100]);
+        assert_eq!(statistics.report(300), replayed);
+        statistics.record("lead time", 5.0);
+        assert_eq!(statistics.report(300).tallies["lead time"].count, 201);
+    }
+
+    #[test]
+    fn mser5_journal_is_bounded() {
+        let statistics = Statistics::new();
+        let reset_at = Rc::new(RefCell::new(vec![]));
+        let listener_reset_at = reset_at.clone();
+        statistics.add_reset_listener(move |time| listener_reset_at.borrow_mut().push(time));
+        statistics.configure_warm_up(WarmUp::Mser5("lead time".to_string()));
+        for time in 0..MSER5_JOURNAL_LIMIT as u64 {
+            statistics.advance(time);
+            statistics.record("lead time", if time < 1000 { 50.0 } else { 5.0 });
+        }
+        assert_eq!(*reset_at.borrow(), vec![1000]);
+        assert!(statistics.registry.borrow().journal.is_empty());
+        statistics.finish_warm_up();
+        assert_eq!(reset_at.borrow().len(), 1);
+    }
+}
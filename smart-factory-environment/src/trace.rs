@@ -0,0 +1,612 @@
+use crate::error::EventEngineError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledEvent {
+    pub target: Uuid,
+    pub time: u64,
+    pub payload_type: Option<String>,
+}
+
+/// One dispatched event, the events its handler scheduled and the events injected before it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceRecord {
+    pub sequence: u64,
+    pub time: u64,
+    pub sender: Option<Uuid>,
+    pub target: Uuid,
+    pub payload_type: Option<String>,
+    /// JSON produced by `EventArgs::to_json`.
+    #[serde(default, with = "json_payload")]
+    pub payload: Option<Value>,
+    pub scheduled: Vec<ScheduledEvent>,
+    /// Events injected from outside the simulation since the previous dispatched event, see
+    /// `IncomingQueueMessage::InjectEvent`.
+    #[serde(default)]
+    pub injected: Vec<ScheduledEvent>,
+}
+
+/// Payloads are nested JSON in JSON Lines traces. Bincode can not read self-describing values, so
+/// binary traces keep them as JSON text.
+mod json_payload {
+    use super::*;
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(
+        payload: &Option<Value>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            payload.serialize(serializer)
+        } else {
+            payload.as_ref().map(Value::to_string).serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Value>, D::Error> {
+        if deserializer.is_human_readable() {
+            Option::<Value>::deserialize(deserializer)
+        } else {
+            Option::<String>::deserialize(deserializer)?
+                .map(|text| serde_json::from_str(&text).map_err(D::Error::custom))
+                .transpose()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One JSON object per line.
+    JsonLines,
+    /// Consecutive bincode records.
+    Binary,
+}
+
+impl TraceFormat {
+    /// `.jsonl` and `.json` give JSON Lines, anything else the binary format.
+    pub fn from_path(path: &Path) -> TraceFormat {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("jsonl") | Some("json") => TraceFormat::JsonLines,
+            _ => TraceFormat::Binary,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+    Io(std::io::Error),
+    Json {
+        line: usize,
+        error: serde_json::Error,
+    },
+    Binary(bincode::Error),
+}
+
+impl Display for TraceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceError::Io(error) => write!(f, "{}", error),
+            TraceError::Json { line, error } => write!(f, "line {}: {}", line, error),
+            TraceError::Binary(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TraceError::Io(error) => Some(error),
+            TraceError::Json { error, .. } => Some(error),
+            TraceError::Binary(error) => Some(error),
+        }
+    }
+}
+
+impl From<std::io::Error> for TraceError {
+    fn from(error: std::io::Error) -> Self {
+        TraceError::Io(error)
+    }
+}
+
+/// Receives every dispatched event from the engine.
+pub trait TraceSink {
+    fn record(&mut self, record: &TraceRecord) -> Result<(), EventEngineError>;
+}
+
+/// Sink of a run with an optional recorder and replay, the recorder takes precedence.
+pub(crate) fn trace_sink<'a>(
+    recorder: Option<&'a mut TraceRecorder>,
+    replay: Option<&'a mut TraceReplay>,
+) -> Option<&'a mut dyn TraceSink> {
+    match (recorder, replay) {
+        (Some(recorder), _) => Some(recorder),
+        (None, Some(replay)) => Some(replay),
+        (None, None) => None,
+    }
+}
+
+/// Writes and flushes every dispatched event as soon as it is handled, so a trace can be followed
+/// while the run is still going.
+pub struct TraceRecorder {
+    writer: Box<dyn Write>,
+    format: TraceFormat,
+}
+
+impl TraceRecorder {
+    pub fn new(writer: impl Write + 'static, format: TraceFormat) -> TraceRecorder {
+        TraceRecorder {
+            writer: Box::new(writer),
+            format,
+        }
+    }
+
+    pub fn create(path: &Path) -> std::io::Result<TraceRecorder> {
+        Ok(TraceRecorder::new(
+            BufWriter::new(File::create(path)?),
+            TraceFormat::from_path(path),
+        ))
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) -> std::io::Result<()> {
+        let written = match self.format {
+            TraceFormat::JsonLines => {
+                serde_json::to_writer(&mut self.writer, record)?;
+                self.writer.write_all(b"\n")
+            }
+            TraceFormat::Binary => {
+                bincode::serialize_into(&mut self.writer, record).map_err(|error| match *error {
+                    bincode::ErrorKind::Io(error) => error,
+                    error => std::io::Error::other(error),
+                })
+            }
+        };
+        written?;
+        self.writer.flush()
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads a trace back record by record.
+pub struct TraceReader {
+    reader: Box<dyn BufRead>,
+    format: TraceFormat,
+    line: usize,
+}
+
+impl TraceReader {
+    pub fn new(reader: impl Read + 'static, format: TraceFormat) -> TraceReader {
+        TraceReader {
+            reader: Box::new(BufReader::new(reader)),
+            format,
+            line: 0,
+        }
+    }
+
+    pub fn open(path: &Path) -> std::io::Result<TraceReader> {
+        Ok(TraceReader::new(
+            File::open(path)?,
+            TraceFormat::from_path(path),
+        ))
+    }
+
+    fn next_record(&mut self) -> Result<Option<TraceRecord>, TraceError> {
+        match self.format {
+            TraceFormat::JsonLines => loop {
+                let mut line = String::new();
+                if self.reader.read_line(&mut line)? == 0 {
+                    return Ok(None);
+                }
+                self.line += 1;
+                if !line.trim().is_empty() {
+                    return serde_json::from_str(&line).map(Some).map_err(|error| {
+                        TraceError::Json {
+                            line: self.line,
+                            error,
+                        }
+                    });
+                }
+            },
+            TraceFormat::Binary => {
+                if self.reader.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                bincode::deserialize_from(&mut self.reader)
+                    .map(Some)
+                    .map_err(TraceError::Binary)
+            }
+        }
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = Result<TraceRecord, TraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+impl TraceSink for TraceRecorder {
+    fn record(&mut self, record: &TraceRecord) -> Result<(), EventEngineError> {
+        TraceRecorder::record(self, record).map_err(EventEngineError::CouldNotRecord)
+    }
+}
+
+/// First event at which a run and its recording disagree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub sequence: u64,
+    /// `None` if the recording ended before the run.
+    pub expected: Option<TraceRecord>,
+    /// `None` if the run ended before the recording.
+    pub actual: Option<TraceRecord>,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Run diverged from the recording at event {}",
+            self.sequence
+        )?;
+        let (expected, actual) = match (&self.expected, &self.actual) {
+            (Some(expected), Some(actual)) => (expected, actual),
+            (None, _) => return write!(f, ": the recording has no more events"),
+            (_, None) => return write!(f, ": the run has no more events"),
+        };
+        let fields = [
+            ("time", expected.time.to_string(), actual.time.to_string()),
+            (
+                "sender",
+                format!("{:?}", expected.sender),
+                format!("{:?}", actual.sender),
+            ),
+            (
+                "target",
+                expected.target.to_string(),
+                actual.target.to_string(),
+            ),
+            (
+                "payload_type",
+                format!("{:?}", expected.payload_type),
+                format!("{:?}", actual.payload_type),
+            ),
+            (
+                "payload",
+                format!("{:?}", expected.payload.as_ref().map(Value::to_string)),
+                format!("{:?}", actual.payload.as_ref().map(Value::to_string)),
+            ),
+            (
+                "scheduled",
+                format!("{:?}", expected.scheduled),
+                format!("{:?}", actual.scheduled),
+            ),
+            (
+                "injected",
+                format!("{:?}", expected.injected),
+                format!("{:?}", actual.injected),
+            ),
+        ];
+        for (name, expected, actual) in fields {
+            if expected != actual {
+                write!(f, "\n  {}:\n    - {}\n    + {}", name, expected, actual)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks a run against a recorded trace instead of writing a new one. The engine stops with
+/// `EventEngineError::TraceDiverged` at the first dispatched event that differs.
+///
+/// Injected events are not injected again, so a recording of a run that had them diverges at the
+/// first event dispatched after an injection.
+pub struct TraceReplay {
+    expected: TraceReader,
+    matched: u64,
+}
+
+impl TraceReplay {
+    pub fn new(expected: TraceReader) -> TraceReplay {
+        TraceReplay {
+            expected,
+            matched: 0,
+        }
+    }
+
+    pub fn open(path: &Path) -> std::io::Result<TraceReplay> {
+        Ok(TraceReplay::new(TraceReader::open(path)?))
+    }
+
+    /// Events that matched the recording so far.
+    pub fn matched(&self) -> u64 {
+        self.matched
+    }
+
+    /// Fails if the recording has events the run did not dispatch, call it once the run ended.
+    pub fn finish(&mut self) -> Result<(), EventEngineError> {
+        match self.expected.next() {
+            None => Ok(()),
+            Some(Ok(expected)) => Err(self.diverged(Some(expected), None)),
+            Some(Err(error)) => Err(EventEngineError::CouldNotReplay(error)),
+        }
+    }
+
+    fn diverged(
+        &self,
+        expected: Option<TraceRecord>,
+        actual: Option<TraceRecord>,
+    ) -> EventEngineError {
+        EventEngineError::TraceDiverged(Box::new(Divergence {
+            sequence: self.matched,
+            expected,
+            actual,
+        }))
+    }
+}
+
+impl TraceSink for TraceReplay {
+    fn record(&mut self, actual: &TraceRecord) -> Result<(), EventEngineError> {
+        match self.expected.next() {
+            Some(Ok(expected)) if expected == *actual => {
+                self.matched += 1;
+                Ok(())
+            }
+            Some(Ok(expected)) => Err(self.diverged(Some(expected), Some(actual.clone()))),
+            Some(Err(error)) => Err(EventEngineError::CouldNotReplay(error)),
+            None => Err(self.diverged(None, Some(actual.clone()))),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::agent::{Agent, NewEventsVec};
+    use crate::environment::EnvironmentSettings;
+    use crate::event::{Event, EventArg, EventArgs};
+    use crate::event_queue::tests::run_engine;
+    use std::any::Any;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Writer whose contents stay readable after the recorder took ownership of it.
+    #[derive(Clone, Default)]
+    pub(crate) struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuffer {
+        pub(crate) fn contents(&self) -> Vec<u8> {
+            self.0.borrow().clone()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Ping {
+        count: u64,
+    }
+
+    impl EventArgs for Ping {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn to_json(&self) -> Option<Value> {
+            Some(serde_json::json!({ "count": self.count }))
+        }
+    }
+
+    struct PingAgent {
+        id: Uuid,
+        peer: Uuid,
+        delay: u64,
+    }
+
+    impl Agent for PingAgent {
+        fn handle(&mut self, time: u64, args: EventArg) -> NewEventsVec {
+            let count = args
+                .and_then(|args| args.as_any().downcast_ref::<Ping>().map(|ping| ping.count))
+                .unwrap_or(0);
+            vec![(
+                Event::new_with_args(self.peer, Box::new(Ping { count: count + 1 })),
+                time + self.delay,
+            )]
+        }
+
+        fn get_id(&self) -> Uuid {
+            self.id
+        }
+    }
+
+    struct ShortRun {
+        max_iter: u64,
+    }
+
+    impl EnvironmentSettings for ShortRun {
+        fn get_max_iter(&self) -> u64 {
+            self.max_iter
+        }
+    }
+
+    const FIRST: Uuid = Uuid::from_u128(1);
+    const SECOND: Uuid = Uuid::from_u128(2);
+
+    fn ping_pong(
+        delay: u64,
+        max_iter: u64,
+        trace: &mut dyn TraceSink,
+    ) -> Result<(), EventEngineError> {
+        let mut first = PingAgent {
+            id: FIRST,
+            peer: SECOND,
+            delay,
+        };
+        let mut second = PingAgent {
+            id: SECOND,
+            peer: FIRST,
+            delay: 2,
+        };
+        run_engine(
+            vec![&mut first as &mut dyn Agent, &mut second as &mut dyn Agent],
+            vec![(Event::new(FIRST), 1)],
+            ShortRun { max_iter },
+            Some(trace),
+            &mut [],
+        )
+        .0
+    }
+
+    fn record_ping_pong(format: TraceFormat) -> (Vec<u8>, Uuid, Uuid) {
+        let buffer = SharedBuffer::default();
+        let mut recorder = TraceRecorder::new(buffer.clone(), format);
+        assert!(ping_pong(2, 3, &mut recorder).is_ok());
+        let bytes = buffer.contents();
+        (bytes, FIRST, SECOND)
+    }
+
+    fn replay(bytes: Vec<u8>) -> TraceReplay {
+        TraceReplay::new(TraceReader::new(
+            std::io::Cursor::new(bytes),
+            TraceFormat::JsonLines,
+        ))
+    }
+
+    #[test]
+    fn it_records_dispatched_events_as_json_lines() {
+        let (bytes, first_id, second_id) = record_ping_pong(TraceFormat::JsonLines);
+        let text = String::from_utf8(bytes.clone()).unwrap();
+        assert_eq!(text.lines().count(), 3);
+        assert!(text.contains("\"payload\":{\"count\":2}"));
+
+        let records: Vec<TraceRecord> =
+            TraceReader::new(std::io::Cursor::new(bytes), TraceFormat::JsonLines)
+                .collect::<Result<_, _>>()
+                .unwrap();
+        assert_eq!(records[0].sequence, 0);
+        assert_eq!(records[0].time, 1);
+        assert_eq!(records[0].sender, None);
+        assert_eq!(records[0].target, first_id);
+        assert_eq!(records[0].payload, None);
+        assert_eq!(
+            records[0].scheduled,
+            vec![ScheduledEvent {
+                target: second_id,
+                time: 3,
+                payload_type: Some(std::any::type_name::<Ping>().to_string()),
+            }]
+        );
+        assert_eq!(records[2].sequence, 2);
+        assert_eq!(records[2].time, 5);
+        assert_eq!(records[2].sender, Some(second_id));
+        assert_eq!(records[2].target, first_id);
+        assert_eq!(records[2].payload, Some(serde_json::json!({ "count": 2 })));
+    }
+
+    #[test]
+    fn binary_traces_round_trip() {
+        let (json, ..) = record_ping_pong(TraceFormat::JsonLines);
+        let (binary, ..) = record_ping_pong(TraceFormat::Binary);
+        assert!(binary.len() < json.len());
+        let records: Vec<TraceRecord> =
+            TraceReader::new(std::io::Cursor::new(binary), TraceFormat::Binary)
+                .collect::<Result<_, _>>()
+                .unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[1].payload, Some(serde_json::json!({ "count": 1 })));
+    }
+
+    #[test]
+    fn created_traces_can_be_read_while_recording() {
+        let path = std::env::temp_dir().join(format!("trace-{}.jsonl", std::process::id()));
+        let mut recorder = TraceRecorder::create(&path).unwrap();
+        let record = TraceRecord {
+            sequence: 0,
+            time: 1,
+            sender: None,
+            target: Uuid::from_u128(1),
+            payload_type: None,
+            payload: None,
+            scheduled: vec![],
+            injected: vec![],
+        };
+        recorder.record(&record).unwrap();
+        let records: Vec<TraceRecord> = TraceReader::open(&path)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        drop(recorder);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records, vec![record]);
+    }
+
+    #[test]
+    fn replaying_the_same_model_matches() {
+        let (bytes, ..) = record_ping_pong(TraceFormat::JsonLines);
+        let mut replay = replay(bytes);
+        assert!(ping_pong(2, 3, &mut replay).is_ok());
+        assert!(replay.finish().is_ok());
+        assert_eq!(replay.matched(), 3);
+    }
+
+    #[test]
+    fn replay_stops_at_the_first_divergence() {
+        let (bytes, ..) = record_ping_pong(TraceFormat::JsonLines);
+        let mut replay = replay(bytes);
+        let result = ping_pong(3, 3, &mut replay);
+        let divergence = match result {
+            Err(EventEngineError::TraceDiverged(divergence)) => divergence,
+            other => panic!("Expected a divergence, got {:?}", other),
+        };
+        // The first event only schedules differently, the time of the second one follows from it
+        assert_eq!(divergence.sequence, 0);
+        assert_eq!(replay.matched(), 0);
+        let diff = divergence.to_string();
+        assert!(diff.contains("\n  scheduled:"));
+        assert!(diff.contains("time: 3"));
+        assert!(diff.contains("time: 4"));
+        assert!(!diff.contains("\n  time:"));
+        assert!(!diff.contains("\n  target:"));
+    }
+
+    #[test]
+    fn replay_detects_runs_of_different_length() {
+        let (bytes, ..) = record_ping_pong(TraceFormat::JsonLines);
+        let mut longer = replay(bytes.clone());
+        match ping_pong(2, 4, &mut longer) {
+            Err(EventEngineError::TraceDiverged(divergence)) => {
+                assert_eq!(divergence.sequence, 3);
+                assert!(divergence.expected.is_none());
+            }
+            other => panic!("Expected a divergence, got {:?}", other),
+        }
+
+        let mut shorter = replay(bytes);
+        assert!(ping_pong(2, 2, &mut shorter).is_ok());
+        match shorter.finish() {
+            Err(EventEngineError::TraceDiverged(divergence)) => {
+                assert_eq!(divergence.sequence, 2);
+                assert!(divergence.actual.is_none());
+            }
+            other => panic!("Expected a divergence, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use smart_factory_environment::empty_environment::{
+    EmptyEnvironmentSettings, InfiniteEmptyEnvironment,
+};
+use smart_factory_environment::environment::AgentEnvironment;
+use smart_factory_environment::golden::{assert_kpis_match_golden, assert_trace_matches_golden};
+use std::path::PathBuf;
+
+// Regenerate with `UPDATE_GOLDENS=1 cargo test --test golden` after an intended behavior change
+
+fn golden(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("goldens")
+        .join(name)
+}
+
+fn settings() -> EmptyEnvironmentSettings {
+    EmptyEnvironmentSettings::new(3, 0, 100, 12).with_seed(42)
+}
+
+#[test]
+fn empty_environment_trace_matches_golden() {
+    let mut environment = InfiniteEmptyEnvironment::without_sleep(|_: &str| {});
+    assert_trace_matches_golden(
+        &mut environment,
+        settings(),
+        golden("empty_environment.jsonl"),
+    );
+}
+
+#[test]
+fn empty_environment_kpis_match_golden() {
+    let mut environment = InfiniteEmptyEnvironment::without_sleep(|_: &str| {});
+    let run = futures::executor::block_on(environment.run(settings()));
+    assert!(run.is_ok());
+    let statistics = environment.get_statistics();
+    let mut kpis = statistics.report(statistics.time()).flatten();
+    kpis.insert("events".to_string(), environment.report() as f64);
+    assert_kpis_match_golden(&kpis, golden("empty_environment_kpis.json"));
+}
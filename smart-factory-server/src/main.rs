@@ -1,55 +1,113 @@
-use std::{env, io::Error};
+use std::env;
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::sync::Arc;
 
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use smart_factory_environment::channel::OutgoingStream;
+use smart_factory_environment::environment::EnvironmentSettings;
 use smart_factory_environment::greet_message;
+use smart_factory_environment::introspection::{AgentQuery, AgentSnapshot};
+use smart_factory_environment::message::OutgoingQueueMessage;
+use smart_factory_environment::scenario::{Scenario, ScenarioError, ScenarioFormat};
+use smart_factory_environment::simulation::SimulationController;
+use std::path::Path;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{oneshot, Semaphore};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::WebSocketStream;
+use tracing_subscriber::EnvFilter;
+
+/// Events of a client scenario before its run stops, whatever its own stop conditions say.
+const MAX_EVENTS_PER_RUN: u64 = 100_000_000;
+/// Scenario runs of all connections at the same time, each one occupies a blocking thread.
+const MAX_CONCURRENT_RUNS: usize = 4;
 
 #[tokio::main]
-async fn main() -> Result<(), Error> {
+async fn main() -> ExitCode {
+    // `RUST_LOG=smart_factory_environment=debug` shows the engine, info is the default
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
     let addr = env::args()
         .nth(1)
         .unwrap_or_else(|| "127.0.0.1:8080".to_string());
 
+    // Optional scenario file, checked on start so that mistakes are found before any client connects.
+    if let Some(path) = env::args().nth(2) {
+        match Scenario::load(Path::new(&path)) {
+            Ok(scenario) => tracing::info!(
+                scenario = %scenario.name.unwrap_or(path),
+                "Loaded scenario"
+            ),
+            Err(error) => {
+                eprintln!("Invalid scenario {}:\n{}", path, error);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
     // Create the event loop and TCP listener we'll accept connections on.
     let try_socket = TcpListener::bind(&addr).await;
     let listener = try_socket.expect("Failed to bind");
-    println!("Listening on: {}", addr);
+    tracing::info!(%addr, "Listening");
 
+    let runs = Arc::new(Semaphore::new(MAX_CONCURRENT_RUNS));
     while let Ok((stream, _)) = listener.accept().await {
-        tokio::spawn(accept_connection(stream));
+        tokio::spawn(accept_connection(stream, runs.clone()));
     }
 
-    Ok(())
+    ExitCode::SUCCESS
 }
 
-async fn accept_connection(stream: TcpStream) {
+async fn accept_connection(stream: TcpStream, runs: Arc<Semaphore>) {
     let addr = stream
         .peer_addr()
         .expect("connected streams should have a peer address");
-    println!("Peer address: {}", addr);
+    tracing::info!(%addr, "Peer connected");
 
     let ws_stream = tokio_tungstenite::accept_async(stream)
         .await
         .expect("Error during the websocket handshake occurred");
 
-    println!("New WebSocket connection: {}", addr);
+    tracing::info!(%addr, "New WebSocket connection");
 
     let (mut write, mut read) = ws_stream.split();
+    // Agents of the last scenario run on this connection, answering `{"query": ...}` messages
+    // between runs. During a run queries are answered by the simulation.
+    let mut agents: Vec<AgentSnapshot> = vec![];
 
     loop {
         let msg = read.next().await;
         match msg {
             Some(Ok(message)) => {
-                let message = message;
                 if let tungstenite::Message::Text(message) = message {
-                    let result = write
-                        .send(tungstenite::Message::Text(greet_message(&message)))
-                        .await;
-                    if result.is_err() {
-                        println!(
-                            "Error while sending a message: {:?}",
-                            result.unwrap_err()
-                        );
+                    let response = if let Ok(QueryMessage { query }) =
+                        serde_json::from_str::<QueryMessage>(&message)
+                    {
+                        answer_query(&query, &agents)
+                    } else if message.trim_start().starts_with('{') {
+                        match start_scenario(message, &runs).await {
+                            Ok(run) => match watch_run(run, &mut read, &mut write, addr).await {
+                                Some((response, snapshots)) => {
+                                    agents = snapshots;
+                                    response
+                                }
+                                None => return,
+                            },
+                            Err(response) => response,
+                        }
+                    } else {
+                        greet_message(&message)
+                    };
+                    let result = write.send(tungstenite::Message::Text(response)).await;
+                    if let Err(error) = result {
+                        tracing::warn!(%addr, error = ?error, "Error while sending a message");
                     }
                 }
             }
@@ -57,3 +115,148 @@ async fn accept_connection(stream: TcpStream) {
         }
     }
 }
+
+/// Waits for a run while answering the client, queries are forwarded to the running simulation.
+/// `None` if the client left, the run is halted then instead of going on unobserved.
+async fn watch_run(
+    mut run: RunningScenario,
+    read: &mut SplitStream<WebSocketStream<TcpStream>>,
+    write: &mut SplitSink<WebSocketStream<TcpStream>, tungstenite::Message>,
+    addr: SocketAddr,
+) -> Option<(String, Vec<AgentSnapshot>)> {
+    let mut outgoing_open = true;
+    loop {
+        tokio::select! {
+            finished = &mut run.handle => return Some(describe_run(finished)),
+            message = run.outgoing.next(), if outgoing_open => match message {
+                Some(OutgoingQueueMessage::Agents(snapshots)) => {
+                    let response = serde_json::to_string(&snapshots)
+                        .unwrap_or_else(|error| format!("Invalid query: {}", error));
+                    if let Err(error) = write.send(tungstenite::Message::Text(response)).await {
+                        tracing::warn!(%addr, error = ?error, "Error while sending a message");
+                    }
+                }
+                Some(_) => {}
+                None => outgoing_open = false,
+            },
+            message = read.next() => match message {
+                Some(Ok(tungstenite::Message::Text(message))) => {
+                    // Answered on `run.outgoing`, queries the run ends before stay unanswered
+                    let refusal = match serde_json::from_str::<QueryMessage>(&message) {
+                        Ok(QueryMessage { query }) => run
+                            .controller
+                            .query_agents(query)
+                            .err()
+                            .map(|_| "The scenario has finished".to_string()),
+                        Err(_) => Some("A scenario is already running".to_string()),
+                    };
+                    if let Some(refusal) = refusal {
+                        if let Err(error) = write.send(tungstenite::Message::Text(refusal)).await {
+                            tracing::warn!(%addr, error = ?error, "Error while sending a message");
+                        }
+                    }
+                }
+                Some(Ok(_)) => {}
+                _ => {
+                    tracing::info!(%addr, "Peer left during a run, halting it");
+                    let _ = run.controller.halt();
+                    let _ = run.handle.await;
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QueryMessage {
+    query: AgentQuery,
+}
+
+/// JSON array of the matching agents.
+fn answer_query(query: &AgentQuery, agents: &[AgentSnapshot]) -> String {
+    let matching: Vec<&AgentSnapshot> = agents
+        .iter()
+        .filter(|snapshot| query.matches(snapshot))
+        .collect();
+    serde_json::to_string(&matching).unwrap_or_else(|error| format!("Invalid query: {}", error))
+}
+
+/// Scenario run on a blocking thread.
+struct RunningScenario {
+    controller: SimulationController,
+    /// Answers to the queries sent through `controller`.
+    outgoing: OutgoingStream,
+    handle: JoinHandle<(Result<u64, String>, Vec<AgentSnapshot>)>,
+}
+
+/// Starts a JSON scenario sent by the client, or describes why it can't run. Runs stop after
+/// `MAX_EVENTS_PER_RUN` events, a scenario is refused while `MAX_CONCURRENT_RUNS` are running.
+async fn start_scenario(message: String, runs: &Arc<Semaphore>) -> Result<RunningScenario, String> {
+    let scenario = Scenario::parse(&message, ScenarioFormat::Json)
+        .map_err(|error| format!("Invalid scenario:\n{}", error))?;
+    let settings = scenario
+        .empty_environment_settings()
+        .ok_or_else(|| "Only empty environment scenarios can be run".to_string())?;
+    let permit = runs
+        .clone()
+        .try_acquire_owned()
+        .map_err(|_| "The server is busy, try again later".to_string())?;
+    let max_events = scenario.get_max_iter().min(MAX_EVENTS_PER_RUN);
+    // The simulation is not Send, so it is built and driven on a blocking thread.
+    // Its records reach the subscriber through `tracing`, so it has no log callback.
+    let (controller_sender, controller) = oneshot::channel();
+    let handle = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let mut simulation = match settings
+            .simulation_builder()
+            .with_max_events(max_events)
+            .with_sleep(|duration| async move { std::thread::sleep(duration) })
+            .build()
+        {
+            Ok(simulation) => simulation,
+            Err(errors) => {
+                let error = ScenarioError::Invalid(errors);
+                return (Err(format!("Invalid scenario:\n{}", error)), vec![]);
+            }
+        };
+        let outgoing = simulation
+            .take_outgoing()
+            .expect("Outgoing stream is taken once");
+        let _ = controller_sender.send((simulation.controller(), outgoing));
+        let result = simulation
+            .run_to_completion()
+            .map_err(|error| error.to_string());
+        let agents = simulation.snapshot_agents(&AgentQuery::All);
+        let events = agents
+            .iter()
+            .filter_map(|agent| {
+                agent
+                    .properties
+                    .get("counter")
+                    .and_then(|counter| counter.as_u64())
+            })
+            .sum();
+        (result.map(|_| events), agents)
+    });
+    match controller.await {
+        Ok((controller, outgoing)) => Ok(RunningScenario {
+            controller,
+            outgoing,
+            handle,
+        }),
+        Err(_) => Err(describe_run(handle.await).0),
+    }
+}
+
+/// Describes the outcome of a run, along with the final state of its agents.
+fn describe_run(
+    finished: Result<(Result<u64, String>, Vec<AgentSnapshot>), tokio::task::JoinError>,
+) -> (String, Vec<AgentSnapshot>) {
+    match finished {
+        Ok((Ok(events), agents)) => (format!("Finished after {} events", events), agents),
+        Ok((Err(error), agents)) => (format!("Simulation failed: {}", error), agents),
+        Err(error) => (format!("Simulation failed: {}", error), vec![]),
+    }
+}
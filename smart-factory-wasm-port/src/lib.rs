@@ -6,10 +6,17 @@ use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::time::Duration;
 
+use futures::StreamExt;
 use smart_factory_environment::empty_environment::{
     EmptyEnvironmentSettings, InfiniteEmptyEnvironment,
 };
 use smart_factory_environment::environment::AgentEnvironment;
+use smart_factory_environment::introspection::AgentQuery;
+use smart_factory_environment::logging::log_callback_subscriber;
+use smart_factory_environment::message::OutgoingQueueMessage;
+use smart_factory_environment::scenario::{Scenario, ScenarioError, ScenarioFormat};
+use smart_factory_environment::simulation::SimulationController;
+use tracing_subscriber::filter::LevelFilter;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
 
@@ -71,15 +78,138 @@ pub async fn sleep(duration: Duration) {
     Timeout::new(duration).await;
 }
 
-pub fn run_empty() {
+pub async fn run_empty() {
     const ITER_COUNT_SLEEP: u64 = 5000;
     const SLEEP_DURATION_MS: u64 = 100;
 
     let mut env = InfiniteEmptyEnvironment::new(log, sleep);
-    env.run(EmptyEnvironmentSettings::new(
-        1,
-        SLEEP_DURATION_MS,
-        ITER_COUNT_SLEEP,
-        u64::MAX
-    ));
+    let _result = env
+        .run(EmptyEnvironmentSettings::new(
+            1,
+            SLEEP_DURATION_MS,
+            ITER_COUNT_SLEEP,
+            u64::MAX,
+        ))
+        .await;
+}
+
+/// Sends engine records up to `max_level` (`error` to `trace`) to the browser console.
+#[wasm_bindgen]
+pub fn enable_tracing(max_level: &str) -> Result<(), JsValue> {
+    let max_level: LevelFilter = max_level
+        .parse()
+        .map_err(|_| JsValue::from_str(&format!("Unknown level {}", max_level)))?;
+    tracing::subscriber::set_global_default(log_callback_subscriber(log, max_level))
+        .map_err(|error| JsValue::from_str(&error.to_string()))
+}
+
+/// Checks a JSON scenario, the error lists the offending fields.
+#[wasm_bindgen]
+pub fn validate_scenario(scenario: &str) -> Result<(), JsValue> {
+    Scenario::parse(scenario, ScenarioFormat::Json)
+        .map(|_| ())
+        .map_err(|error| JsValue::from_str(&error.to_string()))
+}
+
+/// Runs a JSON scenario, the error describes an invalid scenario or a failed run.
+#[wasm_bindgen]
+pub async fn run_scenario(scenario: String) -> Result<(), JsValue> {
+    let scenario = Scenario::parse(&scenario, ScenarioFormat::Json)
+        .map_err(|error| JsValue::from_str(&error.to_string()))?;
+    let settings = scenario
+        .empty_environment_settings()
+        .ok_or_else(|| JsValue::from_str("Only empty environment scenarios can be run"))?;
+
+    let mut env = InfiniteEmptyEnvironment::new(log, sleep);
+    env.run(settings)
+        .await
+        .map_err(|error| JsValue::from_str(&error.to_string()))
+}
+
+/// Runs a JSON scenario and answers a JSON agent query, e.g. `{"type_name": "InfiniteLoopAgent"}`,
+/// with the matching agents as a JSON array once the run is over.
+#[wasm_bindgen]
+pub async fn run_scenario_and_query(scenario: String, query: String) -> Result<String, JsValue> {
+    let query = parse_query(&query)?;
+    let scenario = Scenario::parse(&scenario, ScenarioFormat::Json)
+        .map_err(|error| JsValue::from_str(&error.to_string()))?;
+    let settings = scenario
+        .empty_environment_settings()
+        .ok_or_else(|| JsValue::from_str("Only empty environment scenarios can be run"))?;
+
+    let mut env = InfiniteEmptyEnvironment::new(log, sleep);
+    env.run(settings)
+        .await
+        .map_err(|error| JsValue::from_str(&error.to_string()))?;
+    serde_json::to_string(&env.snapshot_agents(&query))
+        .map_err(|error| JsValue::from_str(&error.to_string()))
+}
+
+/// Scenario running in the background, see `start_scenario`.
+#[wasm_bindgen]
+pub struct ScenarioRun {
+    controller: SimulationController,
+}
+
+#[wasm_bindgen]
+impl ScenarioRun {
+    /// Asks the running scenario for the agents matching a JSON query, the answer is passed to
+    /// `on_agents`. Fails once the run is over.
+    pub fn query(&self, query: &str) -> Result<(), JsValue> {
+        self.controller
+            .query_agents(parse_query(query)?)
+            .map_err(|_| JsValue::from_str("The scenario has finished"))
+    }
+
+    pub fn halt(&self) {
+        let _ = self.controller.halt();
+    }
+}
+
+/// Starts a JSON scenario without waiting for it. `on_agents` is called with a JSON array of the
+/// matching agents for every query, `on_finished` with `null` or the error once the run is over.
+#[wasm_bindgen]
+pub fn start_scenario(
+    scenario: &str,
+    on_agents: js_sys::Function,
+    on_finished: js_sys::Function,
+) -> Result<ScenarioRun, JsValue> {
+    let scenario = Scenario::parse(scenario, ScenarioFormat::Json)
+        .map_err(|error| JsValue::from_str(&error.to_string()))?;
+    let settings = scenario
+        .empty_environment_settings()
+        .ok_or_else(|| JsValue::from_str("Only empty environment scenarios can be run"))?;
+    let mut simulation = settings
+        .simulation_builder()
+        .with_log(log)
+        .with_sleep(sleep)
+        .build()
+        .map_err(|errors| JsValue::from_str(&ScenarioError::Invalid(errors).to_string()))?;
+    let controller = simulation.controller();
+    let mut outgoing = simulation
+        .take_outgoing()
+        .expect("Outgoing stream of a new simulation");
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let result = match simulation.run().await {
+            Ok(()) => JsValue::NULL,
+            Err(error) => JsValue::from_str(&error.to_string()),
+        };
+        let _ = on_finished.call1(&JsValue::NULL, &result);
+    });
+    // Ends with the run, when the simulation drops its sender
+    wasm_bindgen_futures::spawn_local(async move {
+        while let Some(message) = outgoing.next().await {
+            if let OutgoingQueueMessage::Agents(agents) = message {
+                let agents = serde_json::to_string(&agents).unwrap_or_default();
+                let _ = on_agents.call1(&JsValue::NULL, &JsValue::from_str(&agents));
+            }
+        }
+    });
+    Ok(ScenarioRun { controller })
+}
+
+fn parse_query(query: &str) -> Result<AgentQuery, JsValue> {
+    serde_json::from_str(query)
+        .map_err(|error| JsValue::from_str(&format!("Invalid query: {}", error)))
 }
@@ -1,13 +1,18 @@
 #![cfg(target_arch = "wasm32")]
 
+use futures::StreamExt;
 use smart_factory_environment::empty_environment::{
     EmptyEnvironmentSettings, InfiniteEmptyEnvironment,
 };
 use smart_factory_environment::environment::AgentEnvironment;
 use smart_factory_environment::message::OutgoingQueueMessage;
+use smart_factory_environment::pacing::Pacing;
 use smart_factory_wasm_port::sleep;
-use std::sync::mpsc::TryRecvError;
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::time::Duration;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
 use wasm_bindgen_test::*;
 
 wasm_bindgen_test_configure!(run_in_browser);
@@ -130,19 +135,81 @@ pub fn when_change_iter_then_call_log() {
 pub async fn it_runs() {
     let log_function = |message: &str| smart_factory_wasm_port::log(message);
     let mut environment = InfiniteEmptyEnvironment::new(log_function, sleep);
-    let result = environment.run(EmptyEnvironmentSettings::new(
-        0,
+    let mut outgoing = environment.take_outgoing().unwrap();
+    let run = environment.run(EmptyEnvironmentSettings::new(
+        1,
         SLEEP_DURATION_MS,
         ITER_COUNT_SLEEP,
-        0
-    ));
-    let result = result.await;
-    assert!(result.is_ok());
-    assert!(environment.receiver.is_some());
-    let receiver = environment.receiver.unwrap();
-    let _result = receiver.try_recv();
-    assert!(matches!(
-        Result::<OutgoingQueueMessage, TryRecvError>::Ok(OutgoingQueueMessage::Started),
-        _result
+        u64::MAX,
     ));
+    // The run does not end by itself, so the message is read while it runs
+    let finished = futures::future::select(run, outgoing.next()).await;
+    match finished {
+        futures::future::Either::Left(_) => panic!("The run ended"),
+        futures::future::Either::Right((message, _)) => {
+            assert!(matches!(message, Some(OutgoingQueueMessage::Started)))
+        }
+    }
+}
+
+#[wasm_bindgen_test]
+pub async fn it_runs_a_json_scenario() {
+    let scenario =
+        r#"{"environment": {"type": "empty", "agent_count": 2}, "stop": {"max_iter": 10}}"#;
+    assert!(smart_factory_wasm_port::run_scenario(scenario.to_string())
+        .await
+        .is_ok());
+    assert!(
+        smart_factory_wasm_port::run_scenario("{\"seed\": -1}".to_string())
+            .await
+            .is_err()
+    );
+}
+
+#[wasm_bindgen_test]
+pub async fn it_answers_queries_during_a_run() {
+    let answers = Rc::new(RefCell::new(vec![]));
+    let on_agents = {
+        let answers = answers.clone();
+        Closure::<dyn FnMut(String)>::new(move |agents: String| answers.borrow_mut().push(agents))
+    };
+    let on_finished = Closure::<dyn FnMut(JsValue)>::new(|_: JsValue| {});
+    let scenario = r#"{
+        "environment": {"type": "empty", "agent_count": 2},
+        "engine": {"pacing": {"mode": "yield", "every": 1000}}
+    }"#;
+    let run = smart_factory_wasm_port::start_scenario(
+        scenario,
+        on_agents
+            .as_ref()
+            .unchecked_ref::<js_sys::Function>()
+            .clone(),
+        on_finished
+            .as_ref()
+            .unchecked_ref::<js_sys::Function>()
+            .clone(),
+    )
+    .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    assert!(run.query(r#"{"type_name": "InfiniteLoopAgent"}"#).is_ok());
+    sleep(Duration::from_millis(100)).await;
+    run.halt();
+    sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(answers.borrow().len(), 1);
+    assert!(answers.borrow()[0].contains("\"counter\""));
+    assert!(run.query("\"all\"").is_err());
+}
+
+#[wasm_bindgen_test]
+pub async fn yield_pacing_lets_browser_timers_fire() {
+    let mut environment = InfiniteEmptyEnvironment::new(smart_factory_wasm_port::log, sleep);
+    let run = environment.run(
+        EmptyEnvironmentSettings::new(1, SLEEP_DURATION_MS, ITER_COUNT_SLEEP, u64::MAX)
+            .with_pacing(Pacing::Yield { every: 1000 }),
+    );
+    // The timer only fires if the run hands control back to the event loop
+    let timer = Box::pin(sleep(Duration::from_millis(100)));
+    let finished = futures::future::select(run, timer).await;
+    assert!(matches!(finished, futures::future::Either::Right(_)));
 }